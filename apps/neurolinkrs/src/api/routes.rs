@@ -1,22 +1,39 @@
 use axum::{
     body::Body,
-    extract::{Multipart, Path, State},
-    http::{header, HeaderValue, StatusCode},
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     response::{Html, IntoResponse, Json, Response},
     routing::{post, get},
     Router,
 };
+use base64::Engine;
+use futures_util::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use crate::transfer::{SharedFile, TransferManager, UploadBatch};
-use tokio::process::Command;
-use tracing::{info, error};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower_http::services::ServeFile;
+use crate::archive;
+use crate::hashing::{self, DedupStats};
+use crate::sanitize;
+use crate::share::{self, Claim, ShareError, ShareRecord, ShareTarget};
+use crate::storage;
+use crate::transfer::{
+    ChunkEncoding, ChunkManifest, ConflictPolicy, SharedFile, TransferError, TransferManager, TransferOptions, TransferStatus,
+    UploadBatch, UploadedFile,
+};
+use tracing::{info, warn, error};
 
 #[derive(Serialize)]
 pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
 }
 
 #[derive(Deserialize)]
@@ -25,12 +42,22 @@ pub struct InitTransferRequest {
     pub total_size: u64,
     pub chunk_size: usize,
     pub batch_id: Option<String>,
+    /// Path below the uploaded folder, e.g. `photos/2024/a.jpg`; stored as a tree.
+    pub relative_path: Option<String>,
+    /// `overwrite`, `rename`, `reject` or `version`; defaults to the daemon's policy.
+    pub conflict_policy: Option<ConflictPolicy>,
+    /// SHA-256 of the whole file; completion fails if the reassembled bytes differ.
+    pub expected_sha256: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct InitTransferResponse {
     pub transfer_id: String,
     pub total_chunks: usize,
+    /// The server already held this content; the file is stored and no chunks are needed.
+    pub completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_name: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -45,6 +72,8 @@ pub struct StatusResponse {
     pub transfer_id: String,
     pub status: String,
     pub progress: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
 pub fn routes(transfer_manager: Arc<TransferManager>) -> Router {
@@ -52,11 +81,18 @@ pub fn routes(transfer_manager: Arc<TransferManager>) -> Router {
         .route("/", get(root_page))
         .route("/files", get(list_files))
         .route("/uploads", get(list_uploads))
+        .route("/shared/*filename", get(serve_shared_file))
+        .route("/download/archive", post(download_archive))
         .route("/download/batch/:batch_id", get(download_batch))
+        .route("/download/batch/:batch_id/*filename", get(download_batch_file))
+        .route("/share", post(create_share))
         .route("/transfer/init", post(init_transfer))
-        .route("/transfer/chunk", post(receive_chunk))
+        // Chunk bodies are streamed to disk, so the default 2 MB body cap does not apply
+        .route("/transfer/chunk", post(receive_chunk).layer(DefaultBodyLimit::disable()))
         .route("/transfer/complete", post(complete_transfer))
         .route("/transfer/:id/status", get(get_status))
+        .route("/transfer/:id/chunks", get(get_chunks))
+        .route("/stats/dedup", get(dedup_stats))
         .route("/health", get(health_check))
         .with_state(transfer_manager)
 }
@@ -259,7 +295,23 @@ async fn root_page() -> Html<&'static str> {
             overflow-wrap: anywhere;
         }
         a.file-link:hover { text-decoration: underline; }
+        .pick {
+            display: flex;
+            align-items: center;
+            gap: 8px;
+            min-width: 0;
+        }
+        .pick input { margin: 0; flex: none; }
         .size { color: var(--muted); font-size: 12px; }
+        .share-expiry { display: flex; flex-wrap: wrap; gap: 6px 14px; align-items: center; }
+        .share-expiry select, .share-expiry input[type=password] {
+            margin-left: 4px;
+            border-radius: 8px;
+            border: 1px solid #c7c7c1;
+            padding: 3px 6px;
+            font-size: 13px;
+            background: #fff;
+        }
         .hidden-input { display: none; }
         @media (max-width: 760px) {
             body { padding: 22px 12px 34px; }
@@ -274,7 +326,7 @@ async fn root_page() -> Html<&'static str> {
             <p class="sub">Fast local transfers with clean batch uploads. Pick a folder, upload once, share single files or a full batch zip.</p>
             <div class="meta">
                 <span class="pill">Rust Runtime (neurolinkd)</span>
-                <span class="pill">Batch Download: ZIP</span>
+                <span class="pill">Batch Download: ZIP / tar</span>
                 <span class="pill">API: /transfer/*</span>
             </div>
         </section>
@@ -299,12 +351,37 @@ async fn root_page() -> Html<&'static str> {
         <section class="card">
             <h2 class="title">Upload Batches</h2>
             <p class="hint">Newest batches first. Every upload run creates one batch.</p>
+            <p class="hint share-expiry">
+                <label>Share links expire after
+                    <select id="shareExpiry">
+                        <option value="3600">1 hour</option>
+                        <option value="86400" selected>1 day</option>
+                        <option value="604800">7 days</option>
+                        <option value="2592000">30 days</option>
+                    </select>
+                </label>
+                <label>Downloads
+                    <select id="shareDownloads">
+                        <option value="" selected>unlimited</option>
+                        <option value="1">1 (one-time)</option>
+                        <option value="5">5</option>
+                        <option value="10">10</option>
+                    </select>
+                </label>
+                <label>Password
+                    <input id="sharePassword" type="password" autocomplete="new-password" placeholder="none" />
+                </label>
+                <label><input id="shareDelete" type="checkbox" disabled /> Delete files after the last download</label>
+            </p>
             <ul id="files" class="files"></ul>
         </section>
     </div>
 
     <script>
         const CHUNK_SIZE = 1024 * 1024;
+        const RESUME_KEY = 'neurolinkd.resume';
+        const ADMIN_TOKEN_KEY = 'neurolinkd.adminToken';
+        const WHOLE_HASH_LIMIT = 256 * 1024 * 1024;
         const folderInput = document.getElementById('folderInput');
         const fileInput = document.getElementById('fileInput');
         const pickFolderBtn = document.getElementById('pickFolderBtn');
@@ -316,6 +393,10 @@ async fn root_page() -> Html<&'static str> {
         const statusEl = document.getElementById('status');
         const selectionEl = document.getElementById('selection');
         const filesEl = document.getElementById('files');
+        const shareExpiry = document.getElementById('shareExpiry');
+        const shareDownloads = document.getElementById('shareDownloads');
+        const sharePassword = document.getElementById('sharePassword');
+        const shareDelete = document.getElementById('shareDelete');
         let selectedFiles = [];
 
         function setStatus(text, kind) {
@@ -338,13 +419,45 @@ async fn root_page() -> Html<&'static str> {
             selectionEl.textContent = `${selectedFiles.length} files selected · ${formatBytes(total)}`;
         }
 
+        // Folder picks carry the path below the chosen folder; plain picks only a name.
+        function uploadPath(file) {
+            return file.webkitRelativePath || file.name;
+        }
+
+        function encodePath(name) {
+            return name.split('/').map(encodeURIComponent).join('/');
+        }
+
+        // Batch links keep serving the bytes that batch uploaded, even after a later overwrite.
+        function batchFileUrl(batchId, name) {
+            return `/download/batch/${encodeURIComponent(batchId)}/${encodePath(name)}`;
+        }
+
         function setFilesFromList(list) {
             selectedFiles = Array.from(list || []);
             updateSelection();
         }
 
+        // Making share links, and listing uploads when links are required, needs this
+        // machine or the daemon's --admin-token; other machines are asked for the token once.
+        async function adminFetch(url, options = {}) {
+            const send = () => {
+                const token = localStorage.getItem(ADMIN_TOKEN_KEY);
+                const headers = Object.assign({}, options.headers, token ? { Authorization: `Bearer ${token}` } : {});
+                return fetch(url, Object.assign({}, options, { headers }));
+            };
+            const res = await send();
+            if (res.status !== 403) return res;
+            const json = await res.clone().json().catch(() => null);
+            if (!json || json.code !== 'admin_required') return res;
+            const token = window.prompt('Admin token for this server');
+            if (!token) return res;
+            localStorage.setItem(ADMIN_TOKEN_KEY, token);
+            return send();
+        }
+
         async function refreshFiles() {
-            const res = await fetch('/uploads');
+            const res = await adminFetch('/uploads');
             const json = await res.json();
 
             if (!res.ok || !json.success || !Array.isArray(json.data)) {
@@ -361,18 +474,27 @@ async fn root_page() -> Html<&'static str> {
                 const when = new Date(batch.uploaded_at).toLocaleString();
                 const items = batch.files.map((file) => `
                     <div class="file-row">
-                        <a class="file-link" href="/shared/${encodeURIComponent(file.name)}" target="_blank" rel="noreferrer">${file.name}</a>
+                        <label class="pick">
+                            <input type="checkbox" class="pick-box" data-name="${encodeURIComponent(file.stored_name)}" />
+                            <a class="file-link" href="${batchFileUrl(batch.batch_id, file.stored_name)}" target="_blank" rel="noreferrer">${file.stored_name}</a>
+                        </label>
                         <div class="file-actions">
                             <span class="size">${formatBytes(file.size)}</span>
-                            <a class="mini-btn" href="/shared/${encodeURIComponent(file.name)}" download="${file.name}">Download</a>
+                            <a class="mini-btn" href="${batchFileUrl(batch.batch_id, file.stored_name)}" download="${file.stored_name.split('/').pop()}">Download</a>
+                            <button class="mini-btn share-btn" type="button" data-file="${encodeURIComponent(file.stored_name)}">Copy share link</button>
                         </div>
                     </div>
                 `).join('');
                 return `
-                    <li>
+                    <li data-batch="${encodeURIComponent(batch.batch_id)}">
                         <div class="batch-head">
                             <span>${when} · ${batch.files.length} file(s)</span>
-                            <a class="link-btn" href="/download/batch/${encodeURIComponent(batch.batch_id)}">Download ZIP</a>
+                            <div class="file-actions">
+                                <button class="mini-btn pick-download" type="button" disabled>Download selected</button>
+                                <a class="link-btn" href="/download/batch/${encodeURIComponent(batch.batch_id)}">Download ZIP</a>
+                                <a class="mini-btn" href="/download/batch/${encodeURIComponent(batch.batch_id)}/SHA256SUMS" download="SHA256SUMS">Checksums</a>
+                                <button class="mini-btn share-btn" type="button">Copy share link</button>
+                            </div>
                         </div>
                         ${items}
                     </li>
@@ -380,33 +502,185 @@ async fn root_page() -> Html<&'static str> {
             }).join('');
         }
 
-        async function uploadSingleFile(file, batchId, doneBytes, totalBytes) {
-            const initRes = await fetch('/transfer/init', {
-                method: 'POST',
-                headers: { 'Content-Type': 'application/json' },
-                body: JSON.stringify({
-                    filename: file.name,
-                    total_size: file.size,
-                    chunk_size: CHUNK_SIZE,
-                    batch_id: batchId
-                })
-            });
-            const initJson = await initRes.json();
-            if (!initRes.ok || !initJson.success || !initJson.data) {
-                throw new Error(initJson.error || 'Init failed');
+        function pickedNames(batchEl) {
+            return Array.from(batchEl.querySelectorAll('.pick-box:checked'))
+                .map((box) => decodeURIComponent(box.dataset.name));
+        }
+
+        function updatePicks(batchEl) {
+            const count = pickedNames(batchEl).length;
+            const btn = batchEl.querySelector('.pick-download');
+            btn.disabled = count === 0;
+            btn.textContent = count ? `Download selected (${count})` : 'Download selected';
+        }
+
+        // The archive is posted for, so it arrives as a blob and is saved from memory.
+        async function downloadSelected(batchEl) {
+            const names = pickedNames(batchEl);
+            if (names.length === 0) return;
+            const btn = batchEl.querySelector('.pick-download');
+            btn.disabled = true;
+            setStatus(`Preparing archive of ${names.length} file(s)...`);
+            try {
+                const res = await fetch('/download/archive', {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ batch_id: decodeURIComponent(batchEl.dataset.batch), files: names })
+                });
+                if (!res.ok) {
+                    const json = await res.json().catch(() => null);
+                    throw new Error((json && json.error) || 'Archive download failed');
+                }
+                const url = URL.createObjectURL(await res.blob());
+                const link = document.createElement('a');
+                link.href = url;
+                link.download = 'selection.zip';
+                document.body.appendChild(link);
+                link.click();
+                link.remove();
+                setTimeout(() => URL.revokeObjectURL(url), 60000);
+                setStatus(`Downloaded ${names.length} file(s)`, 'ok');
+            } catch (err) {
+                setStatus(err.message || 'Archive download failed', 'err');
+            } finally {
+                updatePicks(batchEl);
+            }
+        }
+
+        // A file button shares the current copy of that file; a batch button shares the whole batch.
+        async function copyShareLink(btn) {
+            const body = { expires_in: Number(shareExpiry.value) };
+            if (shareDownloads.value) {
+                body.max_downloads = Number(shareDownloads.value);
+                body.delete_after = shareDelete.checked;
+            }
+            if (sharePassword.value) body.password = sharePassword.value;
+            if (btn.dataset.file) body.file = decodeURIComponent(btn.dataset.file);
+            else body.batch_id = decodeURIComponent(btn.closest('li').dataset.batch);
+            try {
+                const res = await adminFetch('/share', {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify(body)
+                });
+                const json = await res.json().catch(() => null);
+                if (!res.ok || !json || !json.success) {
+                    throw new Error((json && json.error) || 'Could not create share link');
+                }
+                const url = location.origin + json.data.url;
+                const until = new Date(json.data.expires_at).toLocaleString();
+                // The clipboard API needs a secure context, which plain-http LAN pages are not
+                if (navigator.clipboard && window.isSecureContext) {
+                    await navigator.clipboard.writeText(url);
+                    setStatus(`Share link copied, valid until ${until}`, 'ok');
+                } else {
+                    window.prompt(`Share link, valid until ${until}`, url);
+                }
+            } catch (err) {
+                setStatus(err.message || 'Could not create share link', 'err');
+            }
+        }
+
+        // Transfers that were started but not completed, keyed by file identity,
+        // so a retry after a dropped connection resumes instead of restarting.
+        function resumeKey(file) {
+            return [uploadPath(file), file.size, file.lastModified, CHUNK_SIZE].join('|');
+        }
+
+        function loadResumeMap() {
+            try {
+                return JSON.parse(localStorage.getItem(RESUME_KEY) || '{}');
+            } catch (_) {
+                return {};
             }
+        }
 
-            const transferId = initJson.data.transfer_id;
-            const totalChunks = initJson.data.total_chunks;
-            for (let idx = 0; idx < totalChunks; idx++) {
-                const chunkBlob = file.slice(idx * CHUNK_SIZE, Math.min(file.size, (idx + 1) * CHUNK_SIZE));
+        function saveResume(file, transferId) {
+            const map = loadResumeMap();
+            if (transferId) map[resumeKey(file)] = transferId;
+            else delete map[resumeKey(file)];
+            localStorage.setItem(RESUME_KEY, JSON.stringify(map));
+        }
+
+        async function fetchReceivedChunks(transferId) {
+            const res = await fetch(`/transfer/${encodeURIComponent(transferId)}/chunks`);
+            const json = await res.json().catch(() => null);
+            if (!res.ok || !json || !json.success || !json.data) return null;
+            return json.data;
+        }
+
+        // crypto.subtle only exists in secure contexts, so plain-http LAN pages skip hashing.
+        async function sha256Hex(blob) {
+            if (!window.crypto || !window.crypto.subtle) return null;
+            const digest = await crypto.subtle.digest('SHA-256', await blob.arrayBuffer());
+            return Array.from(new Uint8Array(digest)).map((b) => b.toString(16).padStart(2, '0')).join('');
+        }
+
+        // subtle.digest needs the whole file in memory, so very large files skip it.
+        async function fileSha256(file) {
+            if (file.size > WHOLE_HASH_LIMIT) return null;
+            return sha256Hex(file);
+        }
+
+        async function uploadChunk(transferId, idx, chunkBlob, chunkHash, name) {
+            for (let attempt = 1; ; attempt++) {
                 const form = new FormData();
                 form.append('transfer_id', transferId);
                 form.append('chunk_index', idx.toString());
-                form.append('chunk', chunkBlob, `${file.name}.part${idx}`);
+                if (chunkHash) form.append('chunk_sha256', chunkHash);
+                form.append('chunk', chunkBlob, `${name}.part${idx}`);
                 const chunkRes = await fetch('/transfer/chunk', { method: 'POST', body: form });
                 const chunkJson = await chunkRes.json();
-                if (!chunkRes.ok || !chunkJson.success) throw new Error(chunkJson.error || `Chunk ${idx + 1} failed`);
+                if (chunkRes.ok && chunkJson.success) return;
+                if (chunkJson.code === 'chunk_hash_mismatch' && attempt < 3) continue;
+                throw new Error(chunkJson.error || `Chunk ${idx + 1} failed`);
+            }
+        }
+
+        async function uploadSingleFile(file, batchId, doneBytes, totalBytes) {
+            let transferId = loadResumeMap()[resumeKey(file)];
+            let totalChunks = 0;
+            const received = new Set();
+
+            const manifest = transferId ? await fetchReceivedChunks(transferId) : null;
+            // A failed, finished or expired transfer cannot be resumed; forget it and start over
+            if (transferId && !manifest) saveResume(file, null);
+            if (manifest) {
+                totalChunks = manifest.total_chunks;
+                manifest.received.forEach((chunk) => received.add(chunk.index));
+            } else {
+                const fileHash = await fileSha256(file);
+                const initRes = await fetch('/transfer/init', {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({
+                        filename: file.name,
+                        relative_path: file.webkitRelativePath || null,
+                        total_size: file.size,
+                        chunk_size: CHUNK_SIZE,
+                        batch_id: batchId,
+                        expected_sha256: fileHash
+                    })
+                });
+                const initJson = await initRes.json();
+                if (!initRes.ok || !initJson.success || !initJson.data) {
+                    throw new Error(initJson.error || 'Init failed');
+                }
+                // Content the server already holds is stored immediately; nothing to send
+                if (initJson.data.completed) {
+                    bar.style.width = `${Math.floor(((doneBytes + file.size) / totalBytes) * 100)}%`;
+                    return;
+                }
+                transferId = initJson.data.transfer_id;
+                totalChunks = initJson.data.total_chunks;
+                saveResume(file, transferId);
+            }
+
+            for (let idx = 0; idx < totalChunks; idx++) {
+                if (received.has(idx)) continue;
+                const chunkBlob = file.slice(idx * CHUNK_SIZE, Math.min(file.size, (idx + 1) * CHUNK_SIZE));
+                const chunkHash = await sha256Hex(chunkBlob);
+                await uploadChunk(transferId, idx, chunkBlob, chunkHash, file.name);
                 const uploaded = doneBytes + Math.min(file.size, (idx + 1) * CHUNK_SIZE);
                 bar.style.width = `${Math.floor((uploaded / totalBytes) * 100)}%`;
             }
@@ -418,6 +692,7 @@ async fn root_page() -> Html<&'static str> {
             });
             const doneJson = await doneRes.json();
             if (!doneRes.ok || !doneJson.success) throw new Error(doneJson.error || 'Complete failed');
+            saveResume(file, null);
         }
 
         async function uploadBatch() {
@@ -434,7 +709,7 @@ async fn root_page() -> Html<&'static str> {
             try {
                 for (let i = 0; i < selectedFiles.length; i++) {
                     const file = selectedFiles[i];
-                    setStatus(`Uploading ${i + 1}/${selectedFiles.length}: ${file.name}`);
+                    setStatus(`Uploading ${i + 1}/${selectedFiles.length}: ${uploadPath(file)}`);
                     await uploadSingleFile(file, batchId, doneBytes, totalBytes);
                     doneBytes += file.size;
                 }
@@ -451,6 +726,19 @@ async fn root_page() -> Html<&'static str> {
         pickFilesBtn.addEventListener('click', () => fileInput.click());
         startUploadBtn.addEventListener('click', uploadBatch);
         refreshBtn.addEventListener('click', refreshFiles);
+        // Files can only be deleted after a last download if the downloads are limited
+        shareDownloads.addEventListener('change', () => {
+            shareDelete.disabled = !shareDownloads.value;
+            if (shareDelete.disabled) shareDelete.checked = false;
+        });
+
+        filesEl.addEventListener('change', (e) => {
+            if (e.target.classList.contains('pick-box')) updatePicks(e.target.closest('li'));
+        });
+        filesEl.addEventListener('click', (e) => {
+            if (e.target.classList.contains('pick-download')) downloadSelected(e.target.closest('li'));
+            if (e.target.classList.contains('share-btn')) copyShareLink(e.target);
+        });
 
         folderInput.addEventListener('change', () => setFilesFromList(folderInput.files));
         fileInput.addEventListener('change', () => setFilesFromList(fileInput.files));
@@ -479,12 +767,19 @@ async fn health_check() -> Json<ApiResponse<String>> {
         success: true,
         data: Some("healthy".to_string()),
         error: None,
+        code: None,
     })
 }
 
 async fn list_files(
     State(manager): State<Arc<TransferManager>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    // Names and hashes are what a link grants, so listing them needs as much trust as making one
+    if manager.requires_share_links() && !is_admin(&manager, peer, &headers) {
+        return transfer_error_response(&ShareError::AdminRequired.into());
+    }
     match manager.list_files().await {
         Ok(files) => (
             StatusCode::OK,
@@ -492,6 +787,7 @@ async fn list_files(
                 success: true,
                 data: Some(files),
                 error: None,
+                code: None,
             }),
         ),
         Err(e) => (
@@ -500,6 +796,7 @@ async fn list_files(
                 success: false,
                 data: None,
                 error: Some(e.to_string()),
+                code: None,
             }),
         ),
     }
@@ -507,7 +804,12 @@ async fn list_files(
 
 async fn list_uploads(
     State(manager): State<Arc<TransferManager>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    if manager.requires_share_links() && !is_admin(&manager, peer, &headers) {
+        return transfer_error_response(&ShareError::AdminRequired.into());
+    }
     let uploads = manager.list_upload_batches().await;
     (
         StatusCode::OK,
@@ -515,58 +817,768 @@ async fn list_uploads(
             success: true,
             data: Some(uploads),
             error: None,
+            code: None,
         }),
     )
 }
 
-async fn download_batch(
+/// Longest a share link may stay valid.
+const MAX_SHARE_SECS: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_SHARE_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize, Default)]
+pub struct ShareRequest {
+    /// A stored file to share, e.g. `photos/2024/a.jpg`.
+    pub file: Option<String>,
+    /// An upload batch to share, instead of `file`.
+    pub batch_id: Option<String>,
+    /// Seconds the link stays valid; a day by default, 30 days at most.
+    pub expires_in: Option<u64>,
+    /// Password recipients must give, as the HTTP Basic auth password.
+    pub password: Option<String>,
+    /// Downloads the link allows before it revokes itself.
+    pub max_downloads: Option<u64>,
+    /// Delete the shared files after the last allowed download.
+    #[serde(default)]
+    pub delete_after: bool,
+}
+
+impl ShareRequest {
+    /// Whether the share has to be recorded rather than only signed.
+    fn is_limited(&self) -> bool {
+        self.password.is_some() || self.max_downloads.is_some()
+    }
+
+    fn check_limits(&self) -> Result<(), String> {
+        if self.password.as_deref() == Some("") {
+            return Err("password must not be empty".to_string());
+        }
+        if self.max_downloads == Some(0) {
+            return Err("max_downloads must be at least 1".to_string());
+        }
+        if self.delete_after && self.max_downloads.is_none() {
+            return Err("delete_after needs max_downloads".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ShareLink {
+    /// Path and query of the link, relative to this server.
+    pub url: String,
+    pub expires_at: String,
+}
+
+/// The parameters of a share link: a signature, or the id of a recorded share.
+#[derive(Deserialize, Default)]
+pub struct ShareLinkQuery {
+    pub expires: Option<u64>,
+    pub sig: Option<String>,
+    pub share: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Whether a request may manage shares: it came from this machine, or it
+/// carries the `--admin-token` as `Authorization: Bearer <token>`.
+///
+/// Without connection info (no `into_make_service_with_connect_info`) the
+/// peer is unknown and only the token is accepted.
+fn is_admin(manager: &TransferManager, peer: Option<ConnectInfo<SocketAddr>>, headers: &HeaderMap) -> bool {
+    if peer.is_some_and(|ConnectInfo(addr)| addr.ip().to_canonical().is_loopback()) {
+        return true;
+    }
+    let Some(expected) = manager.admin_token() else {
+        return false;
+    };
+    bearer_token(headers).is_some_and(|given| !given.is_empty() && share::token_matches(expected, &given))
+}
+
+/// The token of an `Authorization: Bearer` header.
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+/// Mints a link to one stored file or one batch that expires after
+/// `expires_in` seconds. A share with a password or a download limit is
+/// recorded server-side; any other is only signed.
+///
+/// Only this machine or a holder of the admin token may make links: a link
+/// can outlive `--require-share-links` checks and can delete files.
+async fn create_share(
     State(manager): State<Arc<TransferManager>>,
-    Path(batch_id): Path<String>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(req): Json<ShareRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&manager, peer, &headers) {
+        return transfer_error_response(&ShareError::AdminRequired.into());
+    }
+    let expires_in = req.expires_in.unwrap_or(DEFAULT_SHARE_SECS);
+    if expires_in == 0 || expires_in > MAX_SHARE_SECS {
+        let reason = format!("expires_in must be between 1 and {} seconds", MAX_SHARE_SECS);
+        return transfer_error_response(&TransferError::InvalidRequest(reason).into());
+    }
+    if let Err(reason) = req.check_limits() {
+        return transfer_error_response(&TransferError::InvalidRequest(reason).into());
+    }
+    let target = match manager.share_target(req.file.as_deref(), req.batch_id.as_deref()).await {
+        Ok(target) => target,
+        Err(err) => return transfer_error_response(&err.into()),
+    };
+    let shares = match manager.shares().await {
+        Ok(shares) => shares,
+        Err(err) => {
+            error!("Failed to load share state: {}", err);
+            return transfer_error_response(&TransferError::Io(err).into());
+        }
+    };
+
+    let now = unix_now();
+    let expires = now + expires_in;
+    let url = if req.is_limited() {
+        let record = ShareRecord {
+            target: target.clone(),
+            expires,
+            password_hash: None,
+            max_downloads: req.max_downloads,
+            downloads: 0,
+            delete_files: req.delete_after,
+        };
+        match shares.store.create(record, req.password.as_deref(), now).await {
+            Ok(id) => share::record_link(&target, &id),
+            Err(err) => {
+                error!("Failed to record share: {}", err);
+                return transfer_error_response(&TransferError::Io(err).into());
+            }
+        }
+    } else {
+        shares.signer.link(&target, expires)
+    };
+
+    let expires_at = chrono::DateTime::<chrono::Utc>::from(UNIX_EPOCH + std::time::Duration::from_secs(expires));
+    info!("Shared {:?} until {}", target, expires_at.to_rfc3339());
+    (
+        StatusCode::OK,
+        Json(ApiResponse {
+            success: true,
+            data: Some(ShareLink {
+                url,
+                expires_at: expires_at.to_rfc3339(),
+            }),
+            error: None,
+            code: None,
+        }),
+    )
+}
+
+/// Checks that a download came through a valid link to `target`.
+///
+/// A recorded share (`?share=`) is always checked, with its password taken
+/// from HTTP Basic auth, and `count` takes one of its allowed downloads. A
+/// signed link is only checked in `--require-share-links` mode.
+async fn authorize_download(
+    manager: &TransferManager,
+    target: &ShareTarget,
+    link: &ShareLinkQuery,
+    headers: &HeaderMap,
+    count: bool,
+) -> Result<Option<Claim>, anyhow::Error> {
+    if link.share.is_none() && !manager.requires_share_links() {
+        return Ok(None);
+    }
+    let shares = manager.shares().await?;
+    let now = unix_now();
+    match &link.share {
+        Some(id) => {
+            let password = basic_auth_password(headers);
+            Ok(Some(shares.store.claim(id, target, password.as_deref(), count, now).await?))
+        }
+        None => {
+            shares.signer.verify(target, link.expires, link.sig.as_deref(), now)?;
+            Ok(None)
+        }
+    }
+}
+
+/// The password of an `Authorization: Basic` header; the user name is ignored.
+fn basic_auth_password(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    decoded.split_once(':').map(|(_, password)| password.to_string())
+}
+
+/// Plain-text refusal for a GET download that failed its share link check.
+/// A share that wants a password asks for it through Basic auth.
+fn share_refusal(err: anyhow::Error) -> Response {
+    match err.downcast_ref::<ShareError>() {
+        Some(err) if err.wants_password() => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"neurolink share\", charset=\"UTF-8\"")],
+            err.to_string(),
+        )
+            .into_response(),
+        Some(err) => (StatusCode::FORBIDDEN, err.to_string()).into_response(),
+        None => {
+            error!("Failed to check a share link: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to check share link").into_response()
+        }
+    }
+}
+
+/// Drops `Range` from a download that takes one of a share's allowed
+/// downloads, so each one it counts sends the whole file.
+fn send_whole(claim: Option<&Claim>, headers: &mut HeaderMap) {
+    if claim.is_some_and(|claim| claim.counted) {
+        headers.remove(header::RANGE);
+    }
+}
+
+/// Finishes a download let through by a recorded share: gives the download
+/// back if nothing was sent, and after the last allowed download of a share
+/// that deletes its files, deletes them once the body is done with.
+async fn settle_download(
+    manager: Arc<TransferManager>,
+    target: ShareTarget,
+    claim: Option<Claim>,
+    response: Response,
 ) -> Response {
-    let files = manager.files_for_batch(&batch_id).await;
-    if files.is_empty() {
-        return (StatusCode::NOT_FOUND, "Batch not found").into_response();
+    let Some(claim) = claim else {
+        return response;
+    };
+    if !response.status().is_success() {
+        if let Ok(shares) = manager.shares().await {
+            shares.store.release(&claim).await;
+        }
+        return response;
     }
+    let mut response = response;
+    if claim.counted {
+        response.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    }
+    if !claim.delete_files {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let cleanup = DeleteOnDrop { manager, target };
+    let body = body.into_data_stream().map(move |chunk| {
+        let _keep = &cleanup;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Deletes a share's files when the body sending them is dropped, whether it
+/// was sent in full or the client went away.
+struct DeleteOnDrop {
+    manager: Arc<TransferManager>,
+    target: ShareTarget,
+}
 
-    let storage_path = manager.storage_path();
-    let mut cmd = Command::new("zip");
-    cmd.arg("-q").arg("-").current_dir(storage_path);
-    for file in &files {
-        cmd.arg(&file.name);
+impl Drop for DeleteOnDrop {
+    fn drop(&mut self) {
+        let manager = self.manager.clone();
+        let target = self.target.clone();
+        tokio::spawn(async move {
+            if let Err(err) = manager.delete_shared(&target).await {
+                warn!("Failed to delete {:?} after its last download: {}", target, err);
+            }
+        });
     }
+}
 
-    let output = match cmd.output().await {
-        Ok(output) if output.status.success() => output.stdout,
-        Ok(output) => {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to build zip archive: {}", stderr),
-            )
-                .into_response();
+/// Serves a stored file; the name goes through the filename policy so
+/// traversal and daemon state under the storage root stay out of reach.
+async fn serve_shared_file(
+    State(manager): State<Arc<TransferManager>>,
+    Path(filename): Path<String>,
+    Query(link): Query<ShareLinkQuery>,
+    mut request: Request,
+) -> Response {
+    let name = sanitize::check_relative_path(&filename).unwrap_or_else(|_| filename.clone());
+    let target = ShareTarget::File(name);
+    let count = request.method() != Method::HEAD;
+    let claim = match authorize_download(&manager, &target, &link, request.headers(), count).await {
+        Ok(claim) => claim,
+        Err(err) => return share_refusal(err),
+    };
+    send_whole(claim.as_ref(), request.headers_mut());
+    let path = sanitize::locate(&manager.storage_path(), &filename).ok();
+    let hash = manager.shared_file_hash(&filename).await;
+    let response = serve_file(&manager, path, hash, request).await;
+    settle_download(manager, target, claim, response).await
+}
+
+/// Name of the checksum manifest served beside a batch and packed into its archives.
+const CHECKSUMS_NAME: &str = "SHA256SUMS";
+
+/// Serves a file exactly as the batch received it, even if the shared copy
+/// has since been replaced.
+///
+/// `SHA256SUMS` is the batch's checksum manifest, unless the batch uploaded a
+/// file by that name.
+async fn download_batch_file(
+    State(manager): State<Arc<TransferManager>>,
+    Path((batch_id, filename)): Path<(String, String)>,
+    Query(link): Query<ShareLinkQuery>,
+    mut request: Request,
+) -> Response {
+    // Fetching the checksums does not use up a download
+    let target = ShareTarget::Batch(batch_id.clone());
+    let count = request.method() != Method::HEAD && filename != CHECKSUMS_NAME;
+    let claim = match authorize_download(&manager, &target, &link, request.headers(), count).await {
+        Ok(claim) => claim,
+        Err(err) => return share_refusal(err),
+    };
+    send_whole(claim.as_ref(), request.headers_mut());
+    if filename == CHECKSUMS_NAME {
+        let files = manager.files_for_batch(&batch_id).await;
+        if let Some(manifest) = batch_checksums(&files) {
+            let mut response = Response::new(Body::from(manifest));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+            return response;
         }
+    }
+    let path = manager.batch_file(&batch_id, &filename).ok();
+    let hash = manager.batch_file_hash(&batch_id, &filename).await;
+    let response = serve_file(&manager, path, hash, request).await;
+    settle_download(manager, target, claim, response).await
+}
+
+/// The `sha256sum -c` manifest for a batch's files, if any of them has a
+/// recorded hash and none of them takes the manifest's name.
+fn batch_checksums(files: &[UploadedFile]) -> Option<String> {
+    if files.is_empty() || files.iter().any(|file| file.stored_name == CHECKSUMS_NAME) {
+        return None;
+    }
+    let hashed: Vec<(&str, &str)> = files
+        .iter()
+        .filter_map(|file| Some((file.final_hash.as_deref()?, file.stored_name.as_str())))
+        .collect();
+    if hashed.is_empty() {
+        return None;
+    }
+    Some(hashing::sha256sums(hashed))
+}
+
+/// Serves a stored file. With its SHA-256 on record (`hash`), the response
+/// carries it as a strong `ETag` and as `Digest`/`Repr-Digest`, and
+/// `If-None-Match` and `If-Range` are checked against it.
+async fn serve_file(
+    manager: &TransferManager,
+    path: Option<PathBuf>,
+    hash: Option<String>,
+    mut request: Request,
+) -> Response {
+    let (path, modified) = match path {
+        Some(path) => match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_file() => (path, meta.modified().ok()),
+            _ => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+        },
+        None => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+    };
+    let key = manager.storage_key();
+    let info = match storage::inspect_async(&path, key).await {
+        Ok(info) => info,
         Err(err) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to run zip: {}", err),
-            )
-                .into_response();
+            error!("Failed to inspect {}: {}", path.display(), err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
         }
     };
 
-    let mut response = Response::new(Body::from(output));
-    *response.status_mut() = StatusCode::OK;
+    // Files compressed at rest go out as stored to clients that can decode zstd
+    let send_zstd = info.compressed && accepts_zstd(request.headers());
+
+    let etag = hash.as_deref().map(|hash| entity_tag(hash, send_zstd));
+    if request.headers().contains_key(header::IF_NONE_MATCH) {
+        // If-None-Match takes precedence over If-Modified-Since
+        request.headers_mut().remove(header::IF_MODIFIED_SINCE);
+        if etag.as_deref().is_some_and(|etag| none_match(request.headers(), etag)) {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            add_integrity_headers(response.headers_mut(), hash.as_deref(), etag.as_deref(), send_zstd);
+            return response;
+        }
+    }
+    if !if_range_holds(request.headers(), etag.as_deref(), modified) {
+        request.headers_mut().remove(header::RANGE);
+    }
+
+    let mut response = if info.encrypted || (info.compressed && !send_zstd) {
+        serve_decoded(&path, key, &info, send_zstd, request).await
+    } else {
+        match ServeFile::new(&path).try_call(request).await {
+            Ok(response) => {
+                let mut response = response.map(Body::new);
+                if send_zstd {
+                    let headers = response.headers_mut();
+                    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("zstd"));
+                    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+                }
+                response
+            }
+            Err(err) => {
+                error!("Failed to serve {}: {}", path.display(), err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+            }
+        }
+    };
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        add_integrity_headers(response.headers_mut(), hash.as_deref(), etag.as_deref(), send_zstd);
+    }
+    response
+}
+
+/// Strong entity tag for content with SHA-256 `hash`. The zstd-encoded
+/// form is a different representation and gets a tag of its own.
+fn entity_tag(hash: &str, zstd: bool) -> String {
+    if zstd {
+        format!("\"{}-zstd\"", hash)
+    } else {
+        format!("\"{}\"", hash)
+    }
+}
+
+/// Sets `ETag`, and for the unencoded content also the digests of it: RFC 3230
+/// `Digest` for older clients and RFC 9530 `Repr-Digest`.
+fn add_integrity_headers(headers: &mut HeaderMap, hash: Option<&str>, etag: Option<&str>, zstd: bool) {
+    if let Some(v) = etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+        headers.insert(header::ETAG, v);
+    }
+    // The recorded hash covers the decoded bytes, not a zstd-encoded body
+    let Some(digest) = hash.filter(|_| !zstd).and_then(|hash| hex::decode(hash).ok()) else {
+        return;
+    };
+    let digest = base64::engine::general_purpose::STANDARD.encode(digest);
+    if let Ok(v) = HeaderValue::from_str(&format!("SHA-256={}", digest)) {
+        headers.insert(HeaderName::from_static("digest"), v);
+    }
+    if let Ok(v) = HeaderValue::from_str(&format!("sha-256=:{}:", digest)) {
+        headers.insert(HeaderName::from_static("repr-digest"), v);
+    }
+}
+
+/// Whether `If-None-Match` is `*` or lists `etag`, compared weakly.
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Whether a `Range` request may be answered with a part of the file: true
+/// without `If-Range`, or when `If-Range` names this exact version, by strong
+/// entity tag or by its modification date.
+fn if_range_holds(headers: &HeaderMap, etag: Option<&str>, modified: Option<SystemTime>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return etag == Some(value);
+    }
+    match (chrono::DateTime::parse_from_rfc2822(value), modified) {
+        (Ok(date), Some(modified)) => {
+            let modified = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            date.timestamp() == modified as i64
+        }
+        _ => false,
+    }
+}
+
+/// The part of a file a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// No usable range, or several of them: the whole file is sent.
+    Whole,
+    /// First and last byte, both within the file.
+    Part(u64, u64),
+    /// The range starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Reads a single `bytes=` range of a file of `len` bytes.
+fn byte_range(headers: &HeaderMap, len: u64) -> ByteRange {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Whole;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // The last `suffix` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Part(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Whole;
+    };
+    let end = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Whole,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(start, end.min(len - 1))
+}
+
+/// Streams a file that cannot be served byte for byte from disk: decrypted,
+/// and decompressed unless `send_zstd` is set.
+///
+/// A byte range of the decoded content is answered from the encryption
+/// segment holding its start, after decoding up to it in a compressed file.
+/// The zstd-encoded form is always sent whole.
+async fn serve_decoded(
+    path: &std::path::Path,
+    key: Option<&storage::StorageKey>,
+    info: &storage::StoredInfo,
+    send_zstd: bool,
+    request: Request,
+) -> Response {
+    let len = info.logical_size;
+    let range = if send_zstd { ByteRange::Whole } else { byte_range(request.headers(), len) };
+    let opened = match range {
+        _ if send_zstd => storage::open_encoded(path, key).await,
+        ByteRange::Whole => storage::open(path, key).await,
+        ByteRange::Part(start, _) => storage::open_from(path, key, start).await,
+        ByteRange::Unsatisfiable => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            if let Ok(v) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                response.headers_mut().insert(header::CONTENT_RANGE, v);
+            }
+            return response;
+        }
+    };
+    let (reader, info) = match opened {
+        Ok(opened) => opened,
+        Err(err) => {
+            error!("Failed to open {}: {}", path.display(), err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+        }
+    };
+
+    let (reader, sent): (storage::StoredReader, u64) = match range {
+        ByteRange::Part(start, end) => (Box::new(reader.take(end - start + 1)), end - start + 1),
+        _ => (reader, info.logical_size),
+    };
+    let body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from_stream(ReaderStream::new(reader))
+    };
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    if let Ok(v) = HeaderValue::from_str(mime.as_ref()) {
+        headers.insert(header::CONTENT_TYPE, v);
+    }
+    if send_zstd {
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("zstd"));
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    } else {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(sent));
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    if let ByteRange::Part(start, end) = range {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        if let Ok(v) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, info.logical_size)) {
+            response.headers_mut().insert(header::CONTENT_RANGE, v);
+        }
+    }
+    let headers = response.headers_mut();
+    if info.compressed {
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
+}
+
+/// Whether `Accept-Encoding` lists zstd without refusing it through `q=0`.
+fn accepts_zstd(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            coding.eq_ignore_ascii_case("zstd") && !refused
+        })
+}
+
+#[derive(Deserialize)]
+pub struct BatchDownloadQuery {
+    /// `zip` (default), `tar`, `tar.zst` or `tar.gz`.
+    pub format: Option<String>,
+}
+
+async fn download_batch(
+    State(manager): State<Arc<TransferManager>>,
+    Path(batch_id): Path<String>,
+    Query(query): Query<BatchDownloadQuery>,
+    Query(link): Query<ShareLinkQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let format = match query.format.as_deref().unwrap_or("").parse::<archive::Format>() {
+        Ok(format) => format,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+    let target = ShareTarget::Batch(batch_id.clone());
+    let claim = match authorize_download(&manager, &target, &link, &headers, method != Method::HEAD).await {
+        Ok(claim) => claim,
+        Err(err) => return share_refusal(err),
+    };
+    let files = manager.files_for_batch(&batch_id).await;
+    let batch_dir = match manager.batch_dir(&batch_id) {
+        Ok(dir) if !files.is_empty() => dir,
+        _ => {
+            let response = (StatusCode::NOT_FOUND, "Batch not found").into_response();
+            return settle_download(manager, target, claim, response).await;
+        }
+    };
+    let mut entries: Vec<archive::Entry> = files
+        .iter()
+        .map(|file| archive::Entry::stored(file.stored_name.clone(), batch_dir.join(&file.stored_name)))
+        .collect();
+    if let Some(manifest) = batch_checksums(&files) {
+        entries.push(archive::Entry::generated(CHECKSUMS_NAME, manifest));
+    }
+
+    let mut response = Response::new(archive::build(format, entries, manager.storage_key().cloned()));
     response
         .headers_mut()
-        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
 
-    let disposition = format!("attachment; filename=\"upload-{}.zip\"", batch_id);
+    let disposition = format!("attachment; filename=\"upload-{}.{}\"", batch_id, format.extension());
+    if let Ok(v) = HeaderValue::from_str(&disposition) {
+        response.headers_mut().insert(header::CONTENT_DISPOSITION, v);
+    }
+    settle_download(manager, target, claim, response).await
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveRequest {
+    /// Stored paths to include, e.g. `photos/2024/a.jpg`.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// A stored directory to include in full, instead of `files`.
+    pub directory: Option<String>,
+    /// Resolve names inside this batch rather than the storage root.
+    pub batch_id: Option<String>,
+    /// `zip` (default), `tar`, `tar.zst` or `tar.gz`.
+    pub format: Option<String>,
+}
+
+/// Streams a chosen set of stored files, or one directory, as an archive.
+///
+/// When downloads need share links, or the query names a recorded share,
+/// only files of a batch can be picked, with that batch's link parameters in
+/// the query string.
+async fn download_archive(
+    State(manager): State<Arc<TransferManager>>,
+    Query(link): Query<ShareLinkQuery>,
+    headers: HeaderMap,
+    Json(req): Json<ArchiveRequest>,
+) -> Response {
+    let format = match req.format.as_deref().unwrap_or("").parse::<archive::Format>() {
+        Ok(format) => format,
+        Err(reason) => return transfer_error_response::<()>(&TransferError::InvalidRequest(reason).into()).into_response(),
+    };
+    let target = req.batch_id.clone().map(ShareTarget::Batch);
+    let authorized = match &target {
+        Some(target) => authorize_download(&manager, target, &link, &headers, true).await,
+        None if manager.requires_share_links() || link.share.is_some() => Err(ShareError::Missing.into()),
+        None => Ok(None),
+    };
+    let claim = match authorized {
+        Ok(claim) => claim,
+        Err(err) => return transfer_error_response::<()>(&err).into_response(),
+    };
+
+    let response = match manager.select_files(req.batch_id.as_deref(), &req.files, req.directory.as_deref()).await {
+        Ok(selected) => archive_response(&manager, format, selected, req.directory.as_deref()),
+        Err(err) => transfer_error_response::<()>(&err.into()).into_response(),
+    };
+    match target {
+        Some(target) => settle_download(manager, target, claim, response).await,
+        None => response,
+    }
+}
+
+fn archive_response(
+    manager: &TransferManager,
+    format: archive::Format,
+    selected: Vec<(String, PathBuf)>,
+    directory: Option<&str>,
+) -> Response {
+    let entries = selected
+        .into_iter()
+        .map(|(name, path)| archive::Entry::stored(name, path))
+        .collect();
+
+    // The directory's own name when it makes a safe header value
+    let stem = directory
+        .and_then(|dir| dir.trim_end_matches(['/', '\\']).rsplit(['/', '\\']).next())
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_. ".contains(c)))
+        .unwrap_or("selection");
+
+    let mut response = Response::new(archive::build(format, entries, manager.storage_key().cloned()));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    let disposition = format!("attachment; filename=\"{}.{}\"", stem, format.extension());
     if let Ok(v) = HeaderValue::from_str(&disposition) {
         response.headers_mut().insert(header::CONTENT_DISPOSITION, v);
     }
     response
 }
 
+async fn dedup_stats(State(manager): State<Arc<TransferManager>>) -> Json<ApiResponse<DedupStats>> {
+    Json(ApiResponse {
+        success: true,
+        data: Some(manager.dedup_stats().await),
+        error: None,
+        code: None,
+    })
+}
+
 async fn init_transfer(
     State(manager): State<Arc<TransferManager>>,
     Json(req): Json<InitTransferRequest>,
@@ -581,49 +1593,96 @@ async fn init_transfer(
                 success: false,
                 data: None,
                 error: Some("Invalid chunk_size: must be greater than 0".to_string()),
+                code: None,
             }),
         );
     }
 
+    let options = TransferOptions {
+        batch_id: req.batch_id,
+        relative_path: req.relative_path,
+        conflict_policy: req.conflict_policy,
+        expected_hash: req.expected_sha256,
+    };
+
     match manager
-        .init_transfer(req.filename, req.total_size, req.chunk_size, req.batch_id)
+        .init_transfer(req.filename, req.total_size, req.chunk_size, options)
         .await
     {
-        Ok(transfer_id) => {
-            let total_chunks = ((req.total_size + req.chunk_size as u64 - 1) / req.chunk_size as u64) as usize;
+        Ok(metadata) => {
+            let completed = matches!(metadata.status, TransferStatus::Completed { .. });
             (
                 StatusCode::OK,
                 Json(ApiResponse {
                     success: true,
                     data: Some(InitTransferResponse {
-                        transfer_id,
-                        total_chunks,
+                        transfer_id: metadata.id,
+                        total_chunks: metadata.total_chunks,
+                        completed,
+                        stored_name: metadata.stored_name,
                     }),
                     error: None,
+                    code: None,
                 }),
             )
         }
         Err(e) => {
             error!("Failed to init transfer: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiResponse {
-                    success: false,
-                    data: None,
-                    error: Some(e.to_string()),
-                }),
-            )
+            transfer_error_response(&e)
         }
     }
 }
 
+/// Maps a transfer failure to an HTTP status and its client-facing error code.
+fn transfer_error_response<T>(e: &anyhow::Error) -> (StatusCode, Json<ApiResponse<T>>) {
+    let (status, code) = match e.downcast_ref::<TransferError>() {
+        Some(err) => {
+            let status = match err {
+                TransferError::TransferNotFound(_) | TransferError::FileNotFound(_) => StatusCode::NOT_FOUND,
+                TransferError::ChunkOutOfOrder { .. }
+                | TransferError::ChunkSizeMismatch { .. }
+                | TransferError::ChunkDecode { .. }
+                | TransferError::InvalidRequest(_)
+                | TransferError::InvalidFilename(_) => StatusCode::BAD_REQUEST,
+                TransferError::InvalidChunkHash { .. }
+                | TransferError::FileHashMismatch { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                TransferError::TransferFailed(_)
+                | TransferError::Busy(_)
+                | TransferError::FileExists(_) => StatusCode::CONFLICT,
+                TransferError::FileTooLarge { .. }
+                | TransferError::BatchTooLarge { .. }
+                | TransferError::QuotaExceeded { .. }
+                | TransferError::InsufficientStorage { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                TransferError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Some(err.code()))
+        }
+        None => match e.downcast_ref::<ShareError>() {
+            Some(err) if err.wants_password() => (StatusCode::UNAUTHORIZED, Some(err.code())),
+            Some(err) => (StatusCode::FORBIDDEN, Some(err.code())),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        },
+    };
+    (
+        status,
+        Json(ApiResponse {
+            success: false,
+            data: None,
+            error: Some(e.to_string()),
+            code,
+        }),
+    )
+}
+
 async fn receive_chunk(
     State(manager): State<Arc<TransferManager>>,
     mut multipart: Multipart,
-) -> Result<Json<ApiResponse<ChunkResponse>>, StatusCode> {
+) -> Result<(StatusCode, Json<ApiResponse<ChunkResponse>>), StatusCode> {
     let mut transfer_id = None;
     let mut chunk_index = None;
-    let mut chunk_data = None;
+    let mut chunk_sha256 = None;
+    let mut chunk_encoding = ChunkEncoding::Identity;
+    let mut result = None;
 
     while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
         let name = field.name().unwrap_or("").to_string();
@@ -636,45 +1695,69 @@ async fn receive_chunk(
                 let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
                 chunk_index = text.parse().ok();
             }
+            "chunk_sha256" => {
+                let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                chunk_sha256 = Some(text.trim().to_string()).filter(|t| !t.is_empty());
+            }
+            "chunk_encoding" => {
+                let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                match text.parse() {
+                    Ok(encoding) => chunk_encoding = encoding,
+                    Err(reason) => {
+                        return Ok(transfer_error_response(&TransferError::InvalidRequest(reason).into()));
+                    }
+                }
+            }
             "chunk" => {
-                chunk_data = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec());
+                // The body is streamed straight to disk, so the fields it depends on must come first
+                let (Some(transfer_id), Some(chunk_index)) = (transfer_id.as_deref(), chunk_index) else {
+                    return Err(StatusCode::BAD_REQUEST);
+                };
+                let reader = StreamReader::new(field.map_err(std::io::Error::other));
+                result = Some(match chunk_encoding.decode(reader) {
+                    Ok(reader) => {
+                        manager
+                            .receive_chunk(transfer_id, chunk_index, reader, chunk_sha256.as_deref())
+                            .await
+                    }
+                    Err(e) => Err(e.into()),
+                });
             }
             _ => {}
         }
     }
 
     let transfer_id = transfer_id.ok_or(StatusCode::BAD_REQUEST)?;
-    let chunk_index = chunk_index.ok_or(StatusCode::BAD_REQUEST)?;
-    let chunk_data = chunk_data.ok_or(StatusCode::BAD_REQUEST)?;
+    let result = result.ok_or(StatusCode::BAD_REQUEST)?;
 
-    match manager.receive_chunk(&transfer_id, chunk_index, chunk_data).await {
+    match result {
         Ok(hash) => {
             if let Some(metadata) = manager.get_transfer_status(&transfer_id).await {
                 let received = match &metadata.status {
-                    crate::transfer::TransferStatus::InProgress { received_chunks } => *received_chunks,
+                    TransferStatus::InProgress { received_chunks } => *received_chunks,
                     _ => metadata.total_chunks,
                 };
 
-                Ok(Json(ApiResponse {
-                    success: true,
-                    data: Some(ChunkResponse {
-                        chunk_hash: hash,
-                        received_count: received,
-                        total_chunks: metadata.total_chunks,
+                Ok((
+                    StatusCode::OK,
+                    Json(ApiResponse {
+                        success: true,
+                        data: Some(ChunkResponse {
+                            chunk_hash: hash,
+                            received_count: received,
+                            total_chunks: metadata.total_chunks,
+                        }),
+                        error: None,
+                        code: None,
                     }),
-                    error: None,
-                }))
+                ))
             } else {
                 Err(StatusCode::NOT_FOUND)
             }
         }
         Err(e) => {
             error!("Failed to receive chunk: {}", e);
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(e.to_string()),
-            }))
+            Ok(transfer_error_response(&e))
         }
     }
 }
@@ -682,26 +1765,34 @@ async fn receive_chunk(
 async fn complete_transfer(
     State(manager): State<Arc<TransferManager>>,
     Json(req): Json<serde_json::Value>,
-) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+) -> Result<(StatusCode, Json<ApiResponse<serde_json::Value>>), StatusCode> {
     let transfer_id = req["transfer_id"].as_str().ok_or(StatusCode::BAD_REQUEST)?;
 
     match manager.complete_transfer(transfer_id).await {
-        Ok(metadata) => Ok(Json(ApiResponse {
-            success: true,
-            data: Some(serde_json::json!({
-                "transfer_id": metadata.id,
-                "filename": metadata.filename,
-                "status": "completed"
-            })),
-            error: None,
-        })),
+        Ok(metadata) => {
+            let final_hash = match &metadata.status {
+                TransferStatus::Completed { final_hash } => Some(final_hash.clone()),
+                _ => None,
+            };
+            Ok((
+                StatusCode::OK,
+                Json(ApiResponse {
+                    success: true,
+                    data: Some(serde_json::json!({
+                        "transfer_id": metadata.id,
+                        "filename": metadata.filename,
+                        "stored_name": metadata.stored_name,
+                        "sha256": final_hash,
+                        "status": "completed"
+                    })),
+                    error: None,
+                    code: None,
+                }),
+            ))
+        }
         Err(e) => {
             error!("Failed to complete transfer: {}", e);
-            Ok(Json(ApiResponse {
-                success: false,
-                data: None,
-                error: Some(e.to_string()),
-            }))
+            Ok(transfer_error_response(&e))
         }
     }
 }
@@ -713,13 +1804,17 @@ async fn get_status(
     match manager.get_transfer_status(&transfer_id).await {
         Some(metadata) => {
             let (status_str, progress) = match &metadata.status {
-                crate::transfer::TransferStatus::Pending => ("pending".to_string(), "0%".to_string()),
-                crate::transfer::TransferStatus::InProgress { received_chunks } => {
+                TransferStatus::Pending => ("pending".to_string(), "0%".to_string()),
+                TransferStatus::InProgress { received_chunks } => {
                     let pct = (received_chunks * 100) / metadata.total_chunks;
                     ("in_progress".to_string(), format!("{}%", pct))
                 }
-                crate::transfer::TransferStatus::Completed { .. } => ("completed".to_string(), "100%".to_string()),
-                crate::transfer::TransferStatus::Failed { reason } => ("failed".to_string(), reason.clone()),
+                TransferStatus::Completed { .. } => ("completed".to_string(), "100%".to_string()),
+                TransferStatus::Failed { reason } => ("failed".to_string(), reason.clone()),
+            };
+            let reason = match &metadata.status {
+                TransferStatus::Failed { reason } => Some(reason.clone()),
+                _ => None,
             };
 
             Ok(Json(ApiResponse {
@@ -728,22 +1823,52 @@ async fn get_status(
                     transfer_id: metadata.id,
                     status: status_str,
                     progress,
+                    reason,
                 }),
                 error: None,
+                code: None,
             }))
         }
         None => Ok(Json(ApiResponse {
             success: false,
             data: None,
             error: Some("Transfer not found".to_string()),
+            code: None,
         })),
     }
 }
 
+async fn get_chunks(
+    State(manager): State<Arc<TransferManager>>,
+    Path(transfer_id): Path<String>,
+) -> impl IntoResponse {
+    match manager.chunk_manifest(&transfer_id).await {
+        Ok(manifest) => (
+            StatusCode::OK,
+            Json(ApiResponse {
+                success: true,
+                data: Some(manifest),
+                error: None,
+                code: None,
+            }),
+        ),
+        Err(e) => transfer_error_response::<ChunkManifest>(&e),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use axum::Json;
+    use sha2::{Digest, Sha256};
+
+    fn no_link() -> Query<ShareLinkQuery> {
+        Query(ShareLinkQuery::default())
+    }
+
+    fn local() -> Option<ConnectInfo<SocketAddr>> {
+        Some(ConnectInfo(([127, 0, 0, 1], 50000).into()))
+    }
 
     #[tokio::test]
     async fn init_transfer_zero_chunk_size_returns_bad_request() {
@@ -753,9 +1878,716 @@ mod tests {
             total_size: 1024,
             chunk_size: 0,
             batch_id: None,
+            relative_path: None,
+            conflict_policy: None,
+            expected_sha256: None,
         };
 
         let response = init_transfer(State(manager), Json(req)).await.into_response();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[test]
+    fn chunk_hash_mismatch_maps_to_unprocessable_with_code() {
+        let err: anyhow::Error = TransferError::InvalidChunkHash {
+            index: 3,
+            expected: "aa".to_string(),
+            actual: "bb".to_string(),
+        }
+        .into();
+
+        let (status, Json(body)) = transfer_error_response::<()>(&err);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.code, Some("chunk_hash_mismatch"));
+    }
+
+    #[test]
+    fn chunk_decode_failure_maps_to_bad_request_with_code() {
+        let err: anyhow::Error = TransferError::ChunkDecode { index: 0, reason: "truncated zstd frame".to_string() }.into();
+
+        let (status, Json(body)) = transfer_error_response::<()>(&err);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, Some("chunk_decode_failed"));
+    }
+
+    #[test]
+    fn quota_exceeded_maps_to_payload_too_large_with_code() {
+        let err: anyhow::Error = TransferError::QuotaExceeded { used: 10, requested: 5, quota: 12 }.into();
+
+        let (status, Json(body)) = transfer_error_response::<()>(&err);
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body.code, Some("quota_exceeded"));
+    }
+
+    #[tokio::test]
+    async fn shared_file_route_refuses_traversal_and_state_dir() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(storage.path().join(".neurolink")).unwrap();
+        std::fs::write(storage.path().join(".neurolink").join("secret"), b"state").unwrap();
+        std::fs::write(storage.path().join("shared.txt"), b"hello").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+
+        for name in ["../etc/passwd", ".neurolink/secret", "/etc/passwd"] {
+            let request = Request::builder().body(Body::empty()).unwrap();
+            let response = serve_shared_file(State(manager.clone()), Path(name.to_string()), no_link(), request).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", name);
+        }
+
+        let request = Request::builder().body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager), Path("shared.txt".to_string()), no_link(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn shared_file_route_serves_nested_files_but_not_folders() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(storage.path().join("site/a")).unwrap();
+        std::fs::write(storage.path().join("site/a/readme.md"), b"hello").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+
+        let request = Request::builder().body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager.clone()), Path("site/a/readme.md".to_string()), no_link(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder().body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager), Path("site/a".to_string()), no_link(), request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn files_named_unlike_an_upload_would_be_stay_reachable() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let config = crate::transfer::TransferConfig { require_share_links: true, ..Default::default() };
+        let manager = Arc::new(TransferManager::new(storage.path()).with_config(config));
+        for name in ["shot 10:00.png", "what?.txt", "CON"] {
+            std::fs::write(storage.path().join(name), name).unwrap();
+            let listed = manager.list_files().await.unwrap();
+            assert!(listed.iter().any(|file| file.name == name));
+
+            let (_, link) = share_link(&manager, Some(name), None).await;
+            let response = serve_shared_file(State(manager.clone()), Path(name.to_string()), link, Request::new(Body::empty())).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", name);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], name.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_file_carries_its_hash_and_honours_preconditions() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let data = b"0123456789".repeat(100);
+        let id = manager.init_transfer("big.bin".to_string(), 1000, 1000, TransferOptions::default()).await.unwrap().id;
+        manager.receive_chunk(&id, 0, data.as_slice(), None).await.unwrap();
+        manager.complete_transfer(&id).await.unwrap();
+
+        let digest = Sha256::digest(&data);
+        let etag = format!("\"{}\"", hex::encode(digest));
+        let b64 = base64::engine::general_purpose::STANDARD.encode(digest);
+        let get = |headers: &[(header::HeaderName, &str)]| {
+            let mut builder = Request::builder();
+            for (name, value) in headers {
+                builder = builder.header(name, *value);
+            }
+            serve_shared_file(State(manager.clone()), Path("big.bin".to_string()), no_link(), builder.body(Body::empty()).unwrap())
+        };
+
+        let response = get(&[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(response.headers()["digest"], format!("SHA-256={}", b64).as_str());
+        assert_eq!(response.headers()["repr-digest"], format!("sha-256=:{}:", b64).as_str());
+
+        let response = get(&[(header::IF_NONE_MATCH, &format!("\"other\", W/{}", etag))]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        // A resumed download continues only if the content is the one it started on
+        let response = get(&[(header::RANGE, "bytes=990-"), (header::IF_RANGE, &etag)]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], &data[990..]);
+
+        let response = get(&[(header::RANGE, "bytes=990-"), (header::IF_RANGE, "\"stale\"")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 1000);
+
+        // Content changed outside an upload has no hash on record to vouch for it
+        let path = storage.path().join("big.bin");
+        std::fs::write(&path, b"x".repeat(1000)).unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let response = get(&[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::ETAG).is_none());
+    }
+
+    #[tokio::test]
+    async fn second_upload_of_the_same_content_carries_its_hash() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        for name in ["a.bin", "b.bin"] {
+            let id = manager.init_transfer(name.to_string(), 4, 4, TransferOptions::default()).await.unwrap().id;
+            manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+            manager.complete_transfer(&id).await.unwrap();
+        }
+
+        let digest = Sha256::digest(b"abcd");
+        let b64 = base64::engine::general_purpose::STANDARD.encode(digest);
+        let request = Request::builder().body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager.clone()), Path("b.bin".to_string()), no_link(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], format!("\"{}\"", hex::encode(digest)).as_str());
+        assert_eq!(response.headers()["digest"], format!("SHA-256={}", b64).as_str());
+        assert_eq!(response.headers()["repr-digest"], format!("sha-256=:{}:", b64).as_str());
+    }
+
+    #[tokio::test]
+    async fn compressed_file_is_decoded_unless_client_accepts_zstd() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let path = storage.path().join("notes.txt");
+        let data = "all work and no play\n".repeat(2000);
+        std::fs::write(&path, &data).unwrap();
+        assert!(storage::finish_data_file(&path, None, true).await.unwrap());
+        let manager = Arc::new(TransferManager::new(storage.path()));
+
+        let request = Request::builder().body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager.clone()), Path("notes.txt".to_string()), no_link(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], data.len().to_string().as_str());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, data.as_bytes());
+
+        let request = Request::builder()
+            .header(header::ACCEPT_ENCODING, "gzip, zstd")
+            .body(Body::empty())
+            .unwrap();
+        let response = serve_shared_file(State(manager), Path("notes.txt".to_string()), no_link(), request).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, std::fs::read(&path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn ranges_of_encrypted_and_compressed_files_are_decoded_from_their_start() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let key = storage::StorageKey::from_bytes(&[7u8; 32]).unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(storage.path().join("clip.bin"), &data).unwrap();
+        storage::encrypt_in_place(&storage.path().join("clip.bin"), &key, 100_000).await.unwrap();
+        let text = "all work and no play\n".repeat(2000);
+        std::fs::write(storage.path().join("notes.txt"), &text).unwrap();
+        assert!(storage::finish_data_file(&storage.path().join("notes.txt"), None, true).await.unwrap());
+        let config = crate::transfer::TransferConfig { encryption_key: Some(key), ..Default::default() };
+        let manager = Arc::new(TransferManager::new(storage.path()).with_config(config));
+        let fetch = |name: &str, range: &str| {
+            let request = Request::builder().header(header::RANGE, range).body(Body::empty()).unwrap();
+            serve_shared_file(State(manager.clone()), Path(name.to_string()), no_link(), request)
+        };
+
+        let cases = [
+            ("clip.bin", "bytes=170000-170009", 170_000..170_010, data.as_slice()),
+            ("clip.bin", "bytes=-5", 299_995..300_000, data.as_slice()),
+            ("notes.txt", "bytes=21-", 21..text.len(), text.as_bytes()),
+        ];
+        for (name, range, part, content) in cases {
+            let response = fetch(name, range).await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{} {}", name, range);
+            assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+            let expected = format!("bytes {}-{}/{}", part.start, part.end - 1, content.len());
+            assert_eq!(response.headers()[header::CONTENT_RANGE], expected.as_str());
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], part.len().to_string().as_str());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], &content[part]);
+        }
+
+        let response = fetch("clip.bin", "bytes=300000-").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */300000");
+        // Several ranges at once get the whole file
+        let response = fetch("clip.bin", "bytes=0-1,5-6").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], data.as_slice());
+    }
+
+    #[tokio::test]
+    async fn batch_zip_is_streamed_with_every_file() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        for (name, byte) in [("holiday photos/día 1.jpg", 1u8), ("notes.txt", 2)] {
+            let options = TransferOptions {
+                batch_id: Some("batch_a".to_string()),
+                relative_path: Some(name.to_string()),
+                ..Default::default()
+            };
+            let id = manager.init_transfer(name.to_string(), 512, 512, options).await.unwrap().id;
+            manager.receive_chunk(&id, 0, &[byte; 512][..], None).await.unwrap();
+            manager.complete_transfer(&id).await.unwrap();
+        }
+
+        let query = Query(BatchDownloadQuery { format: None });
+        let response = download_batch(State(manager), Path("batch_a".to_string()), query, no_link(), Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        let mut zip = ::zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, ["SHA256SUMS", "holiday photos/día 1.jpg", "notes.txt"]);
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut zip.by_name("notes.txt").unwrap(), &mut data).unwrap();
+        assert_eq!(data, vec![2u8; 512]);
+
+        let mut sums = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name("SHA256SUMS").unwrap(), &mut sums).unwrap();
+        assert!(sums.ends_with(&format!("{}  notes.txt\n", hex::encode(Sha256::digest([2u8; 512])))));
+    }
+
+    #[tokio::test]
+    async fn batch_checksums_are_served_in_sha256sum_format() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        for (name, data) in [("a.txt", &b"abcd"[..]), ("docs/b.txt", &b"efgh"[..])] {
+            let options = TransferOptions {
+                batch_id: Some("batch_a".to_string()),
+                relative_path: Some(name.to_string()),
+                ..Default::default()
+            };
+            let id = manager.init_transfer(name.to_string(), 4, 4, options).await.unwrap().id;
+            manager.receive_chunk(&id, 0, data, None).await.unwrap();
+            manager.complete_transfer(&id).await.unwrap();
+        }
+
+        let path = Path(("batch_a".to_string(), "SHA256SUMS".to_string()));
+        let response = download_batch_file(State(manager.clone()), path, no_link(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            format!(
+                "{}  a.txt\n{}  docs/b.txt\n",
+                hex::encode(Sha256::digest(b"abcd")),
+                hex::encode(Sha256::digest(b"efgh"))
+            )
+        );
+
+        let path = Path(("batch_missing".to_string(), "SHA256SUMS".to_string()));
+        let response = download_batch_file(State(manager), path, no_link(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn duplicate_uploads_keep_their_hashes_in_listings_and_manifests() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        for name in ["a.txt", "b.txt"] {
+            let options = TransferOptions { batch_id: Some("batch_a".to_string()), ..Default::default() };
+            let id = manager.init_transfer(name.to_string(), 4, 4, options).await.unwrap().id;
+            manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+            manager.complete_transfer(&id).await.unwrap();
+        }
+        let hash = hex::encode(Sha256::digest(b"abcd"));
+
+        let response = list_files(State(manager.clone()), local(), HeaderMap::new()).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let files = listed["data"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| file["final_hash"] == hash.as_str()), "{}", listed);
+
+        let path = Path(("batch_a".to_string(), "SHA256SUMS".to_string()));
+        let response = download_batch_file(State(manager), path, no_link(), Request::new(Body::empty())).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8(body.to_vec()).unwrap(), format!("{0}  a.txt\n{0}  b.txt\n", hash));
+    }
+
+    #[tokio::test]
+    async fn batch_download_format_sets_type_and_name() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let options = TransferOptions { batch_id: Some("batch_a".to_string()), ..Default::default() };
+        let id = manager.init_transfer("a.txt".to_string(), 4, 4, options).await.unwrap().id;
+        manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+        manager.complete_transfer(&id).await.unwrap();
+
+        let query = Query(BatchDownloadQuery { format: Some("tar.gz".to_string()) });
+        let response = download_batch(State(manager.clone()), Path("batch_a".to_string()), query, no_link(), Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/gzip");
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"upload-batch_a.tar.gz\"");
+
+        let query = Query(BatchDownloadQuery { format: Some("rar".to_string()) });
+        let response = download_batch(State(manager), Path("batch_a".to_string()), query, no_link(), Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn archive_of_selected_files_holds_only_those() {
+        let storage = tempfile::TempDir::new().unwrap();
+        for name in ["album/1.jpg", "album/2.jpg", "album/3.jpg"] {
+            std::fs::create_dir_all(storage.path().join("album")).unwrap();
+            std::fs::write(storage.path().join(name), name).unwrap();
+        }
+        let manager = Arc::new(TransferManager::new(storage.path()));
+
+        let req = ArchiveRequest {
+            files: vec!["album/3.jpg".to_string(), "album/1.jpg".to_string()],
+            directory: None,
+            batch_id: None,
+            format: None,
+        };
+        let response = download_archive(State(manager.clone()), no_link(), HeaderMap::new(), Json(req)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"selection.zip\"");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let zip = ::zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+        assert_eq!(zip.file_names().collect::<Vec<_>>().len(), 2);
+
+        let req = ArchiveRequest { files: vec![], directory: Some("album".to_string()), batch_id: None, format: Some("tar".to_string()) };
+        let response = download_archive(State(manager.clone()), no_link(), HeaderMap::new(), Json(req)).await;
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"album.tar\"");
+
+        let req = ArchiveRequest { files: vec!["album/4.jpg".to_string()], directory: None, batch_id: None, format: None };
+        let response = download_archive(State(manager), no_link(), HeaderMap::new(), Json(req)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn share_links_and_restricted_listings_need_this_machine_or_the_admin_token() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::write(storage.path().join("a.txt"), b"abcd").unwrap();
+        let config = crate::transfer::TransferConfig {
+            require_share_links: true,
+            admin_token: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        let manager = Arc::new(TransferManager::new(storage.path()).with_config(config));
+        let remote = || Some(ConnectInfo(SocketAddr::from(([192, 168, 1, 20], 50000))));
+        let bearer = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+            headers
+        };
+        let share = |peer, headers| {
+            let req = ShareRequest { file: Some("a.txt".to_string()), ..Default::default() };
+            create_share(State(manager.clone()), peer, headers, Json(req))
+        };
+
+        for (peer, headers) in [(remote(), HeaderMap::new()), (None, HeaderMap::new()), (remote(), bearer("guess")), (remote(), bearer(""))] {
+            let response = share(peer, headers).await.into_response();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["code"], "admin_required");
+        }
+        assert!(!storage.path().join(".neurolink").join("share.key").exists());
+        assert_eq!(share(remote(), bearer("s3cret")).await.into_response().status(), StatusCode::OK);
+        assert_eq!(share(local(), HeaderMap::new()).await.into_response().status(), StatusCode::OK);
+
+        let files = |peer, headers| list_files(State(manager.clone()), peer, headers);
+        assert_eq!(files(remote(), HeaderMap::new()).await.into_response().status(), StatusCode::FORBIDDEN);
+        assert_eq!(files(remote(), bearer("s3cret")).await.into_response().status(), StatusCode::OK);
+        assert_eq!(files(local(), HeaderMap::new()).await.into_response().status(), StatusCode::OK);
+        let uploads = |peer, headers| list_uploads(State(manager.clone()), peer, headers);
+        assert_eq!(uploads(remote(), HeaderMap::new()).await.into_response().status(), StatusCode::FORBIDDEN);
+        assert_eq!(uploads(local(), HeaderMap::new()).await.into_response().status(), StatusCode::OK);
+
+        // Listings stay open when links are not required; making links never is
+        let open = Arc::new(TransferManager::new(storage.path()));
+        let response = list_files(State(open.clone()), remote(), HeaderMap::new()).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let req = ShareRequest { file: Some("a.txt".to_string()), ..Default::default() };
+        let response = create_share(State(open), remote(), bearer("s3cret"), Json(req)).await.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    /// The link a share request mints, split into its path and link parameters.
+    async fn share_link(manager: &Arc<TransferManager>, file: Option<&str>, batch_id: Option<&str>) -> (String, Query<ShareLinkQuery>) {
+        let req = ShareRequest { file: file.map(str::to_string), batch_id: batch_id.map(str::to_string), ..Default::default() };
+        mint_share(manager, req).await
+    }
+
+    async fn mint_share(manager: &Arc<TransferManager>, req: ShareRequest) -> (String, Query<ShareLinkQuery>) {
+        let response = create_share(State(manager.clone()), local(), HeaderMap::new(), Json(req)).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let url = json["data"]["url"].as_str().unwrap();
+        let (path, query) = url.split_once('?').unwrap();
+        let link = Query::<ShareLinkQuery>::try_from_uri(&format!("/?{}", query).parse().unwrap()).unwrap();
+        (path.to_string(), link)
+    }
+
+    #[tokio::test]
+    async fn restricted_downloads_need_a_valid_share_link() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let config = crate::transfer::TransferConfig { require_share_links: true, ..Default::default() };
+        let manager = Arc::new(TransferManager::new(storage.path()).with_config(config));
+        for name in ["a.txt", "b.txt"] {
+            let options = TransferOptions { batch_id: Some("batch_a".to_string()), ..Default::default() };
+            let id = manager.init_transfer(name.to_string(), 4, 4, options).await.unwrap().id;
+            manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+            manager.complete_transfer(&id).await.unwrap();
+        }
+        let get = || Request::new(Body::empty());
+
+        let response = serve_shared_file(State(manager.clone()), Path("a.txt".to_string()), no_link(), get()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let (path, link) = share_link(&manager, Some("a.txt"), None).await;
+        assert_eq!(path, "/shared/a.txt");
+        let response = serve_shared_file(State(manager.clone()), Path("a.txt".to_string()), link, get()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A file link opens that file only
+        let (_, link) = share_link(&manager, Some("a.txt"), None).await;
+        let response = serve_shared_file(State(manager.clone()), Path("b.txt".to_string()), link, get()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // A batch link opens the batch archive and each of its files
+        let (path, link) = share_link(&manager, None, Some("batch_a")).await;
+        assert_eq!(path, "/download/batch/batch_a");
+        let query = Query(BatchDownloadQuery { format: None });
+        let response = download_batch(State(manager.clone()), Path("batch_a".to_string()), query, link, Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let path = Path(("batch_a".to_string(), "b.txt".to_string()));
+        let (_, link) = share_link(&manager, None, Some("batch_a")).await;
+        let response = download_batch_file(State(manager.clone()), path, link, get()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let expired = ShareLinkQuery {
+            expires: Some(1),
+            sig: Some(manager.shares().await.unwrap().signer.sign(&ShareTarget::Batch("batch_a".to_string()), 1)),
+            share: None,
+        };
+        let query = Query(BatchDownloadQuery { format: None });
+        let response = download_batch(State(manager.clone()), Path("batch_a".to_string()), query, Query(expired), Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Picking files needs the link of the batch they come from
+        let req = ArchiveRequest { files: vec!["a.txt".to_string()], directory: None, batch_id: None, format: None };
+        let response = download_archive(State(manager.clone()), no_link(), HeaderMap::new(), Json(req)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["code"], "share_link_required");
+    }
+
+    #[tokio::test]
+    async fn share_requests_are_checked() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::write(storage.path().join("a.txt"), b"abcd").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+
+        let cases = [
+            (Some("a.txt"), Some("batch_a"), None, StatusCode::BAD_REQUEST),
+            (None, None, None, StatusCode::BAD_REQUEST),
+            (Some("a.txt"), None, Some(0), StatusCode::BAD_REQUEST),
+            (Some("a.txt"), None, Some(MAX_SHARE_SECS + 1), StatusCode::BAD_REQUEST),
+            (Some("missing.txt"), None, None, StatusCode::NOT_FOUND),
+            (None, Some("batch_missing"), None, StatusCode::NOT_FOUND),
+            (Some("../a.txt"), None, None, StatusCode::BAD_REQUEST),
+            (Some("a.txt"), None, Some(60), StatusCode::OK),
+        ];
+        for (file, batch_id, expires_in, expected) in cases {
+            let req = ShareRequest { file: file.map(str::to_string), batch_id: batch_id.map(str::to_string), expires_in, ..Default::default() };
+            let response = create_share(State(manager.clone()), local(), HeaderMap::new(), Json(req)).await.into_response();
+            assert_eq!(response.status(), expected, "{:?} {:?} {:?}", file, batch_id, expires_in);
+        }
+
+        // Links are not checked unless downloads require them
+        let response = serve_shared_file(State(manager), Path("a.txt".to_string()), no_link(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn with_password(password: &str) -> HeaderMap {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("anyone:{}", password));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", credentials)).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn password_share_burns_after_its_download() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(storage.path().join("docs")).unwrap();
+        std::fs::write(storage.path().join("docs/secret.txt"), b"abcd").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let req = ShareRequest {
+            file: Some("docs/secret.txt".to_string()),
+            password: Some("hunter2".to_string()),
+            max_downloads: Some(1),
+            delete_after: true,
+            ..Default::default()
+        };
+        let (path, link) = mint_share(&manager, req).await;
+        assert_eq!(path, "/shared/docs/secret.txt");
+        assert!(link.share.is_some() && link.sig.is_none());
+
+        let fetch = |method: Method, headers: HeaderMap, link: &Query<ShareLinkQuery>| {
+            let mut request = Request::builder().method(method).body(Body::empty()).unwrap();
+            *request.headers_mut() = headers;
+            let link = Query(ShareLinkQuery { share: link.share.clone(), ..Default::default() });
+            serve_shared_file(State(manager.clone()), Path("docs/secret.txt".to_string()), link, request)
+        };
+
+        let response = fetch(Method::GET, HeaderMap::new(), &link).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers()[header::WWW_AUTHENTICATE].to_str().unwrap().starts_with("Basic "));
+        let response = fetch(Method::GET, with_password("hunter3"), &link).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // HEAD does not use up the download
+        let response = fetch(Method::HEAD, with_password("hunter2"), &link).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(storage.path().join("docs/secret.txt").exists());
+
+        let response = fetch(Method::GET, with_password("hunter2"), &link).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"abcd");
+
+        let response = fetch(Method::GET, with_password("hunter2"), &link).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Deleted once the body is gone, along with the folder it emptied
+        for _ in 0..100 {
+            if !storage.path().join("docs").exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!storage.path().join("docs").exists());
+    }
+
+    #[tokio::test]
+    async fn file_share_deleted_after_its_last_download_takes_its_upload_along() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let options = TransferOptions { batch_id: Some("batch_a".to_string()), ..Default::default() };
+        let id = manager.init_transfer("a.txt".to_string(), 4, 4, options).await.unwrap().id;
+        manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+        manager.complete_transfer(&id).await.unwrap();
+        let hash = hex::encode(Sha256::digest(b"abcd"));
+        let blob = storage.path().join(".neurolink").join(hashing::BLOBS_DIR).join(&hash[..2]).join(&hash);
+        assert!(blob.exists());
+
+        let req = ShareRequest { file: Some("a.txt".to_string()), max_downloads: Some(2), delete_after: true, ..Default::default() };
+        let (_, link) = mint_share(&manager, req).await;
+        for _ in 0..2 {
+            let share = Query(ShareLinkQuery { share: link.share.clone(), ..Default::default() });
+            let response = serve_shared_file(State(manager.clone()), Path("a.txt".to_string()), share, Request::new(Body::empty())).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        }
+
+        for _ in 0..100 {
+            if !storage.path().join("a.txt").exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!storage.path().join("a.txt").exists());
+        let file = Path(("batch_a".to_string(), "a.txt".to_string()));
+        let response = download_batch_file(State(manager.clone()), file, no_link(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!blob.exists());
+        assert!(manager.list_upload_batches().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn limited_batch_share_counts_only_downloads_it_serves() {
+        let storage = tempfile::TempDir::new().unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let options = TransferOptions { batch_id: Some("batch_a".to_string()), ..Default::default() };
+        let id = manager.init_transfer("a.txt".to_string(), 4, 4, options).await.unwrap().id;
+        manager.receive_chunk(&id, 0, &b"abcd"[..], None).await.unwrap();
+        manager.complete_transfer(&id).await.unwrap();
+
+        let req = ShareRequest { batch_id: Some("batch_a".to_string()), max_downloads: Some(2), ..Default::default() };
+        let (_, link) = mint_share(&manager, req).await;
+        let share = || Query(ShareLinkQuery { share: link.share.clone(), ..Default::default() });
+        let file = |name: &str| Path(("batch_a".to_string(), name.to_string()));
+
+        // Checksums and misses are free
+        let response = download_batch_file(State(manager.clone()), file(CHECKSUMS_NAME), share(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = download_batch_file(State(manager.clone()), file("missing.txt"), share(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = download_batch_file(State(manager.clone()), file("a.txt"), share(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let query = Query(BatchDownloadQuery { format: Some("tar".to_string()) });
+        let response = download_batch(State(manager.clone()), Path("batch_a".to_string()), query, share(), Method::GET, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = download_batch_file(State(manager.clone()), file("a.txt"), share(), Request::new(Body::empty())).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // The share holds for its own batch only, and was not set to delete anything
+        let other = download_batch_file(State(manager.clone()), Path(("batch_b".to_string(), "a.txt".to_string())), share(), Request::new(Body::empty())).await;
+        assert_eq!(other.status(), StatusCode::FORBIDDEN);
+        assert!(manager.batch_file("batch_a", "a.txt").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn limited_share_sends_each_counted_download_whole() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::write(storage.path().join("a.txt"), b"abcdefgh").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let req = ShareRequest { file: Some("a.txt".to_string()), max_downloads: Some(2), ..Default::default() };
+        let (_, link) = mint_share(&manager, req).await;
+        let fetch = || {
+            let request = Request::builder().header(header::RANGE, "bytes=0-1").body(Body::empty()).unwrap();
+            let share = Query(ShareLinkQuery { share: link.share.clone(), ..Default::default() });
+            serve_shared_file(State(manager.clone()), Path("a.txt".to_string()), share, request)
+        };
+
+        for _ in 0..2 {
+            let response = fetch().await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"abcdefgh");
+        }
+        assert_eq!(fetch().await.status(), StatusCode::FORBIDDEN);
+
+        // Without a limit, ranges are served as before
+        let (_, link) = share_link(&manager, Some("a.txt"), None).await;
+        let request = Request::builder().header(header::RANGE, "bytes=0-1").body(Body::empty()).unwrap();
+        let response = serve_shared_file(State(manager.clone()), Path("a.txt".to_string()), link, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn share_limits_are_checked() {
+        let storage = tempfile::TempDir::new().unwrap();
+        std::fs::write(storage.path().join("a.txt"), b"abcd").unwrap();
+        let manager = Arc::new(TransferManager::new(storage.path()));
+        let file = Some("a.txt".to_string());
+        let cases = [
+            ShareRequest { file: file.clone(), password: Some(String::new()), ..Default::default() },
+            ShareRequest { file: file.clone(), max_downloads: Some(0), ..Default::default() },
+            ShareRequest { file: file.clone(), delete_after: true, ..Default::default() },
+        ];
+        for req in cases {
+            let response = create_share(State(manager.clone()), local(), HeaderMap::new(), Json(req)).await.into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(!storage.path().join(".neurolink").join("shares.json").exists());
+    }
+
+    #[test]
+    fn accepts_zstd_honours_zero_quality() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip, ZSTD;q=0.5"));
+        assert!(accepts_zstd(&headers));
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("zstd;q=0, gzip"));
+        assert!(!accepts_zstd(&headers));
+        assert!(!accepts_zstd(&HeaderMap::new()));
+    }
 }
@@ -0,0 +1,200 @@
+// Archives streamed while they are built.
+//
+// A batch can be far larger than memory, so the archive is written by a task
+// into a small pipe that the response body drains: a slow client holds the
+// writer back, and a read failure halfway through aborts the response rather
+// than ending it like a complete archive.
+
+pub mod tar;
+pub mod zip;
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use axum::body::Body;
+use futures_util::StreamExt;
+use tokio::io::{AsyncWrite, DuplexStream};
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::storage::{self, StorageKey, StoredReader};
+use self::tar::TarWriter;
+use self::zip::ZipWriter;
+
+/// Bytes buffered between the archive writer and the response body.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// A file and the path it takes inside an archive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path inside the archive, `/`-separated.
+    pub name: String,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// A file in storage, decoded if it is compressed or encrypted at rest.
+    Stored(PathBuf),
+    /// Content generated for the archive, such as a checksum manifest.
+    Generated(Vec<u8>),
+}
+
+impl Entry {
+    pub fn stored(name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self { name: name.into(), source: Source::Stored(path.into()) }
+    }
+
+    pub fn generated(name: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Self { name: name.into(), source: Source::Generated(data.into()) }
+    }
+
+    /// The entry's content, its length and modification time.
+    async fn open(self, key: Option<&StorageKey>) -> io::Result<(StoredReader, u64, SystemTime)> {
+        match self.source {
+            Source::Stored(path) => {
+                let (reader, info) = storage::open(&path, key).await?;
+                let modified = tokio::fs::metadata(&path).await?.modified()?;
+                Ok((reader, info.logical_size, modified))
+            }
+            Source::Generated(data) => {
+                let size = data.len() as u64;
+                Ok((Box::new(io::Cursor::new(data)), size, SystemTime::now()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Zip,
+    Tar,
+    TarZst,
+    TarGz,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "zip" => Ok(Self::Zip),
+            "tar" => Ok(Self::Tar),
+            "tar.zst" | "tzst" => Ok(Self::TarZst),
+            "tar.gz" | "tgz" => Ok(Self::TarGz),
+            other => Err(format!("unsupported archive format '{}'", other)),
+        }
+    }
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::Tar => "tar",
+            Self::TarZst => "tar.zst",
+            Self::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::Tar => "application/x-tar",
+            Self::TarZst => "application/zstd",
+            Self::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// Streams `entries` as an archive in `format`, decoding files stored
+/// compressed or encrypted at rest.
+pub fn build(format: Format, entries: Vec<Entry>, key: Option<StorageKey>) -> Body {
+    stream(move |out| async move {
+        match format {
+            Format::Zip => write_zip(out, entries, key).await,
+            Format::Tar => write_tar(out, entries, key).await,
+            Format::TarZst => write_tar(ZstdEncoder::new(out), entries, key).await,
+            Format::TarGz => write_tar(GzipEncoder::new(out), entries, key).await,
+        }
+    })
+}
+
+async fn write_zip(out: impl AsyncWrite + Unpin, entries: Vec<Entry>, key: Option<StorageKey>) -> io::Result<()> {
+    let mut zip = ZipWriter::new(out);
+    for entry in entries {
+        let name = entry.name.clone();
+        let (reader, size, modified) = entry.open(key.as_ref()).await?;
+        zip.add(&name, modified, size, reader).await?;
+    }
+    zip.finish().await
+}
+
+/// Writes a tar archive, adding each entry's parent directories (with the
+/// modification times of their stored counterparts) ahead of it.
+async fn write_tar(out: impl AsyncWrite + Unpin, entries: Vec<Entry>, key: Option<StorageKey>) -> io::Result<()> {
+    let mut tar = TarWriter::new(out);
+    let mut dirs = HashSet::new();
+    for entry in entries {
+        let name = entry.name.clone();
+        let stored = match &entry.source {
+            Source::Stored(path) => Some(path.clone()),
+            Source::Generated(_) => None,
+        };
+        let (reader, size, modified) = entry.open(key.as_ref()).await?;
+
+        let parts: Vec<&str> = name.split('/').collect();
+        for depth in 1..parts.len() {
+            let dir = parts[..depth].join("/");
+            if dirs.contains(&dir) {
+                continue;
+            }
+            let mut dir_modified = modified;
+            if let Some(stored) = stored.as_ref().and_then(|p| p.ancestors().nth(parts.len() - depth)) {
+                if let Ok(time) = tokio::fs::metadata(stored).await.and_then(|m| m.modified()) {
+                    dir_modified = time;
+                }
+            }
+            tar.add_dir(&dir, dir_modified).await?;
+            dirs.insert(dir);
+        }
+        tar.add(&name, modified, size, reader).await?;
+    }
+    tar.finish().await
+}
+
+/// Runs `write` against a pipe and returns the read end as a response body.
+///
+/// If `write` fails, the body ends with that error so the connection is cut
+/// instead of finishing a truncated archive.
+fn stream<F, Fut>(write: F) -> Body
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+    let task = tokio::spawn(write(writer));
+    let outcome = futures_util::stream::once(async move {
+        let err = match task.await {
+            Ok(Ok(())) => return None,
+            Ok(Err(err)) => err,
+            Err(err) => io::Error::other(err),
+        };
+        // A client that went away is not worth a warning
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            warn!("Archive stream aborted: {}", err);
+        }
+        Some(Err(err))
+    })
+    .filter_map(|item| async move { item });
+
+    Body::from_stream(ReaderStream::new(reader).chain(outcome))
+}
+
+#[cfg(test)]
+mod tests;
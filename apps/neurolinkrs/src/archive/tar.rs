@@ -0,0 +1,197 @@
+// Streaming tar writer.
+//
+// Headers are POSIX ustar. A name that does not fit the ustar fields or is not
+// ASCII, or a size beyond the 8 GiB the octal field holds, goes into a pax
+// extended header written just before the entry it describes.
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+const BLOCK: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+/// Largest size the 11 octal digits of the size field can hold.
+const MAX_OCTAL_SIZE: u64 = 0o77_777_777_777;
+const FILE_MODE: u64 = 0o644;
+const DIR_MODE: u64 = 0o755;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    File,
+    Directory,
+    PaxHeader,
+}
+
+impl Kind {
+    fn flag(self) -> u8 {
+        match self {
+            Self::File => b'0',
+            Self::Directory => b'5',
+            Self::PaxHeader => b'x',
+        }
+    }
+}
+
+pub struct TarWriter<W: AsyncWrite + Unpin> {
+    out: BufWriter<W>,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out: BufWriter::with_capacity(BUFFER_SIZE, out) }
+    }
+
+    /// Appends a directory entry; `name` is given without a trailing slash.
+    pub async fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()> {
+        self.header(&format!("{}/", name), Kind::Directory, 0, modified).await
+    }
+
+    /// Appends a file holding the `size` bytes read from `data`.
+    ///
+    /// Fails if `data` holds a different number of bytes, since the header
+    /// already committed to the size.
+    pub async fn add(
+        &mut self,
+        name: &str,
+        modified: SystemTime,
+        size: u64,
+        mut data: impl AsyncRead + Unpin,
+    ) -> io::Result<()> {
+        self.header(name, Kind::File, size, modified).await?;
+
+        let mut written = 0u64;
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = data.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            written += n as u64;
+            if written > size {
+                break;
+            }
+            self.out.write_all(&buffer[..n]).await?;
+        }
+        if written != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes but read {}", size, written),
+            ));
+        }
+        self.pad(size).await
+    }
+
+    /// Writes the end-of-archive marker and flushes the archive.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(&[0u8; BLOCK * 2]).await?;
+        self.out.flush().await?;
+        self.out.shutdown().await
+    }
+
+    async fn header(&mut self, name: &str, kind: Kind, size: u64, modified: SystemTime) -> io::Result<()> {
+        let name = name.replace('\\', "/");
+        let split = split_name(&name);
+        let mtime = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        let mut records = String::new();
+        if split.is_none() {
+            pax_record(&mut records, "path", &name);
+        }
+        if size > MAX_OCTAL_SIZE {
+            pax_record(&mut records, "size", &size.to_string());
+        }
+        if !records.is_empty() {
+            let block = ustar_block("././@PaxHeader", "", Kind::PaxHeader, records.len() as u64, mtime);
+            self.out.write_all(&block).await?;
+            self.out.write_all(records.as_bytes()).await?;
+            self.pad(records.len() as u64).await?;
+        }
+
+        // The pax record wins; the ustar fields only need to be plausible
+        let (prefix, short) = split.unwrap_or(("", ascii_tail(&name)));
+        let block = ustar_block(short, prefix, kind, size.min(MAX_OCTAL_SIZE), mtime);
+        self.out.write_all(&block).await
+    }
+
+    async fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rem = (len % BLOCK as u64) as usize;
+        if rem != 0 {
+            self.out.write_all(&[0u8; BLOCK][rem..]).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits an ASCII name into ustar prefix and name fields, if it fits.
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if !name.is_ascii() {
+        return None;
+    }
+    if name.len() <= NAME_LEN {
+        return Some(("", name));
+    }
+    // Split at a slash, leaving as much as possible in the prefix
+    let dir_name = name.strip_suffix('/').unwrap_or(name);
+    dir_name
+        .rmatch_indices('/')
+        .map(|(i, _)| i)
+        .find(|&i| i <= PREFIX_LEN && name.len() - i - 1 <= NAME_LEN)
+        .map(|i| (&name[..i], &name[i + 1..]))
+}
+
+/// The last up to 100 ASCII bytes of a name, for readers that ignore pax.
+fn ascii_tail(name: &str) -> &str {
+    let start = name.len().saturating_sub(NAME_LEN);
+    let start = (start..name.len()).find(|&i| name.is_char_boundary(i)).unwrap_or(name.len());
+    &name[start..]
+}
+
+/// Appends one `"<len> <key>=<value>\n"` record, where `len` counts itself.
+fn pax_record(records: &mut String, key: &str, value: &str) {
+    let body = key.len() + value.len() + 3;
+    let mut len = body + 1;
+    while body + len.to_string().len() != len {
+        len = body + len.to_string().len();
+    }
+    records.push_str(&format!("{} {}={}\n", len, key, value));
+}
+
+fn ustar_block(name: &str, prefix: &str, kind: Kind, size: u64, mtime: u64) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    put_bytes(&mut block[0..100], name.as_bytes());
+    let mode = match kind {
+        Kind::Directory => DIR_MODE,
+        _ => FILE_MODE,
+    };
+    put_octal(&mut block[100..108], mode);
+    put_octal(&mut block[108..116], 0); // uid
+    put_octal(&mut block[116..124], 0); // gid
+    put_octal(&mut block[124..136], size);
+    put_octal(&mut block[136..148], mtime.min(MAX_OCTAL_SIZE));
+    block[156] = kind.flag();
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    put_bytes(&mut block[345..500], prefix.as_bytes());
+
+    // Checksum is taken with its own field read as spaces
+    block[148..156].fill(b' ');
+    let checksum: u64 = block.iter().map(|&b| b as u64).sum();
+    put_octal(&mut block[148..155], checksum);
+    block[155] = b' ';
+    block
+}
+
+fn put_bytes(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Zero-padded octal filling all but the last byte of `field`, which stays NUL.
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    field[..digits].copy_from_slice(&text.as_bytes()[text.len() - digits..]);
+    field[digits] = 0;
+}
@@ -0,0 +1,175 @@
+use super::*;
+use std::io::{Cursor, Read};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+async fn collect(body: Body) -> Result<Vec<u8>, axum::Error> {
+    axum::body::to_bytes(body, usize::MAX).await.map(|b| b.to_vec())
+}
+
+fn read_zip(bytes: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+    let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+    (0..archive.len())
+        .map(|i| {
+            let mut file = archive.by_index(i).unwrap();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            (file.name().to_string(), data)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_zip_holds_each_entry_under_its_name() {
+    let dir = TempDir::new().unwrap();
+    let names = ["plain.txt", "with spaces.txt", "фото/日本語 ñ.txt", "empty"];
+    let mut entries = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let path = dir.path().join(format!("file{}", i));
+        let data = if *name == "empty" { Vec::new() } else { name.repeat(1000).into_bytes() };
+        std::fs::write(&path, data).unwrap();
+        entries.push(Entry::stored(*name, path));
+    }
+
+    let files = read_zip(collect(build(Format::Zip, entries, None)).await.unwrap());
+    let listed: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(listed, names);
+    assert_eq!(files[2].1, names[2].repeat(1000).into_bytes());
+    assert!(files[3].1.is_empty());
+}
+
+#[tokio::test]
+async fn test_zip_decodes_files_stored_compressed_and_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let key = StorageKey::from_bytes(&[9u8; 32]).unwrap();
+    let path = dir.path().join("app.log");
+    let data = b"GET / 200\n".repeat(50_000);
+    std::fs::write(&path, &data).unwrap();
+    storage::encrypt_in_place(&path, &key, storage::crypto::SEGMENT_SIZE).await.unwrap();
+    assert!(storage::finish_data_file(&path, Some(&key), true).await.unwrap());
+
+    let entries = vec![Entry::stored("app.log", path)];
+    let files = read_zip(collect(build(Format::Zip, entries, Some(key))).await.unwrap());
+    assert_eq!(files, vec![("app.log".to_string(), data)]);
+}
+
+#[tokio::test]
+async fn test_zip64_fields_are_readable() {
+    let mut out = Vec::new();
+    let mut zip = ZipWriter::new(&mut out).with_zip64_limit(100);
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for i in 0..150u8 {
+        let data = vec![i; 150];
+        zip.add(&format!("{}.bin", i), modified, 150, data.as_slice()).await.unwrap();
+    }
+    zip.finish().await.unwrap();
+
+    let files = read_zip(out);
+    assert_eq!(files.len(), 150);
+    assert_eq!(files[149], ("149.bin".to_string(), vec![149u8; 150]));
+}
+
+#[tokio::test]
+async fn test_unreadable_entry_aborts_the_stream() {
+    let dir = TempDir::new().unwrap();
+    let present = dir.path().join("present.txt");
+    std::fs::write(&present, b"here").unwrap();
+    let entries = vec![
+        Entry::stored("present.txt", present),
+        Entry::stored("missing.txt", dir.path().join("missing.txt")),
+    ];
+    assert!(collect(build(Format::Zip, entries, None)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_entry_size_mismatch_is_an_error() {
+    let mut zip = ZipWriter::new(Vec::new());
+    let err = zip.add("short.bin", SystemTime::now(), 10, &b"12345"[..]).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+async fn read_tar(format: Format, body: Body) -> Vec<(String, ::tar::EntryType, u64, Vec<u8>)> {
+    let bytes = collect(body).await.unwrap();
+    let plain = match format {
+        Format::Tar => bytes,
+        Format::TarZst => zstd::stream::decode_all(bytes.as_slice()).unwrap(),
+        Format::TarGz => {
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(bytes.as_slice()).read_to_end(&mut out).unwrap();
+            out
+        }
+        Format::Zip => unreachable!(),
+    };
+    let mut archive = ::tar::Archive::new(plain.as_slice());
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let kind = entry.header().entry_type();
+            let mtime = entry.header().mtime().unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            (name, kind, mtime, data)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_tar_formats_keep_layout_and_mtimes() {
+    let dir = TempDir::new().unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let names = ["top.txt", "photos/2024/día 1.jpg", "photos/2024/day 2.jpg", "photos/cover.png"];
+    let mut entries = Vec::new();
+    for name in names {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, name.repeat(300)).unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        entries.push(Entry::stored(name, path));
+    }
+    entries.push(Entry::generated("SHA256SUMS", "checksums\n"));
+
+    for format in [Format::Tar, Format::TarZst, Format::TarGz] {
+        let files = read_tar(format, build(format, entries.clone(), None)).await;
+        let listed: Vec<(&str, ::tar::EntryType)> = files.iter().map(|(n, k, _, _)| (n.as_str(), *k)).collect();
+        assert_eq!(listed, [
+            ("top.txt", ::tar::EntryType::Regular),
+            ("photos/", ::tar::EntryType::Directory),
+            ("photos/2024/", ::tar::EntryType::Directory),
+            ("photos/2024/día 1.jpg", ::tar::EntryType::Regular),
+            ("photos/2024/day 2.jpg", ::tar::EntryType::Regular),
+            ("photos/cover.png", ::tar::EntryType::Regular),
+            ("SHA256SUMS", ::tar::EntryType::Regular),
+        ], "{:?}", format);
+        assert_eq!(files[3].2, 1_600_000_000);
+        assert_eq!(files[3].3, names[1].repeat(300).into_bytes());
+        assert_eq!(files[6].3, b"checksums\n");
+    }
+}
+
+#[tokio::test]
+async fn test_tar_names_beyond_ustar_fields_use_pax() {
+    let dir = TempDir::new().unwrap();
+    let split = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+    let single = "n".repeat(180);
+    let mut entries = Vec::new();
+    for (i, name) in [&split, &single].into_iter().enumerate() {
+        let path = dir.path().join(i.to_string());
+        std::fs::write(&path, b"x").unwrap();
+        entries.push(Entry::stored(name.clone(), path));
+    }
+
+    let files = read_tar(Format::Tar, build(Format::Tar, entries, None)).await;
+    let listed: Vec<&str> = files.iter().map(|(n, _, _, _)| n.as_str()).collect();
+    assert_eq!(listed, [format!("{}/", "d".repeat(120)), split.clone(), single.clone()]);
+}
+
+#[test]
+fn test_format_parses_known_names_only() {
+    assert_eq!("".parse::<Format>().unwrap(), Format::Zip);
+    assert_eq!("TAR.GZ".parse::<Format>().unwrap(), Format::TarGz);
+    assert_eq!("tzst".parse::<Format>().unwrap(), Format::TarZst);
+    assert!("rar".parse::<Format>().is_err());
+}
@@ -0,0 +1,262 @@
+// Streaming ZIP writer.
+//
+// Entries are stored uncompressed and written in one pass: the CRC is only
+// known once an entry's data has gone out, so each local header leaves it
+// blank and a data descriptor follows the data (general purpose bit 3).
+// Names are flagged as UTF-8 (bit 11). Sizes and offsets that do not fit in
+// 32 bits, and archives of more than 65535 entries, use the ZIP64 extensions.
+
+use std::io;
+use std::time::SystemTime;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const ZIP64_END_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const END_SIG: u32 = 0x0605_4b50;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Upper byte 3 marks Unix, so the external attributes carry a file mode.
+const MADE_BY_UNIX: u16 = 3 << 8;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+/// Regular file, rw-r--r--.
+const FILE_MODE: u32 = 0o100644;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// What the central directory needs to know about a written entry.
+struct Written {
+    name: Vec<u8>,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+    time: u16,
+    date: u16,
+}
+
+pub struct ZipWriter<W: AsyncWrite + Unpin> {
+    out: BufWriter<W>,
+    offset: u64,
+    entries: Vec<Written>,
+    /// Largest value written into a 32-bit field; see `with_zip64_limit`.
+    limit: u64,
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: BufWriter::with_capacity(BUFFER_SIZE, out),
+            offset: 0,
+            entries: Vec::new(),
+            limit: u32::MAX as u64 - 1,
+        }
+    }
+
+    /// Switches to ZIP64 fields for any size, offset or count above `limit`,
+    /// so tests can reach those paths without writing 4 GiB.
+    #[cfg(test)]
+    pub fn with_zip64_limit(mut self, limit: u64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Appends an entry holding the `size` bytes read from `data`.
+    ///
+    /// Fails if `data` holds a different number of bytes, since the entry's
+    /// header already committed to one layout.
+    pub async fn add(
+        &mut self,
+        name: &str,
+        modified: SystemTime,
+        size: u64,
+        mut data: impl AsyncRead + Unpin,
+    ) -> io::Result<()> {
+        let name = name.replace('\\', "/").into_bytes();
+        if name.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "entry name is too long"));
+        }
+        let zip64 = size > self.limit;
+        let (time, date) = dos_datetime(modified);
+        let offset = self.offset;
+
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        put_u32(&mut header, LOCAL_HEADER_SIG);
+        put_u16(&mut header, if zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT });
+        put_u16(&mut header, FLAG_DATA_DESCRIPTOR | FLAG_UTF8);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // CRC and sizes follow in the data descriptor
+        put_u32(&mut header, 0);
+        let blank_size = if zip64 { u32::MAX } else { 0 };
+        put_u32(&mut header, blank_size);
+        put_u32(&mut header, blank_size);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, if zip64 { 20 } else { 0 });
+        header.extend_from_slice(&name);
+        if zip64 {
+            put_u16(&mut header, ZIP64_EXTRA_ID);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+        self.write(&header).await?;
+
+        let mut crc = crc32fast::Hasher::new();
+        let mut written = 0u64;
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = data.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buffer[..n]);
+            self.write(&buffer[..n]).await?;
+            written += n as u64;
+        }
+        if written != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes but read {}", size, written),
+            ));
+        }
+        let crc = crc.finalize();
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIG);
+        put_u32(&mut descriptor, crc);
+        if zip64 {
+            put_u64(&mut descriptor, size);
+            put_u64(&mut descriptor, size);
+        } else {
+            put_u32(&mut descriptor, size as u32);
+            put_u32(&mut descriptor, size as u32);
+        }
+        self.write(&descriptor).await?;
+
+        self.entries.push(Written { name, crc, size, offset, zip64, time, date });
+        Ok(())
+    }
+
+    /// Writes the central directory and flushes the archive.
+    pub async fn finish(mut self) -> io::Result<()> {
+        let start = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            let big_size = entry.size > self.limit;
+            let big_offset = entry.offset > self.limit;
+            let mut extra = Vec::new();
+            if big_size {
+                put_u64(&mut extra, entry.size);
+                put_u64(&mut extra, entry.size);
+            }
+            if big_offset {
+                put_u64(&mut extra, entry.offset);
+            }
+            let needs_zip64 = entry.zip64 || big_offset;
+
+            put_u32(&mut directory, CENTRAL_HEADER_SIG);
+            put_u16(&mut directory, MADE_BY_UNIX | VERSION_ZIP64);
+            put_u16(&mut directory, if needs_zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT });
+            put_u16(&mut directory, FLAG_DATA_DESCRIPTOR | FLAG_UTF8);
+            put_u16(&mut directory, METHOD_STORED);
+            put_u16(&mut directory, entry.time);
+            put_u16(&mut directory, entry.date);
+            put_u32(&mut directory, entry.crc);
+            let size = if big_size { u32::MAX } else { entry.size as u32 };
+            put_u32(&mut directory, size);
+            put_u32(&mut directory, size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, if extra.is_empty() { 0 } else { extra.len() as u16 + 4 });
+            put_u16(&mut directory, 0); // comment
+            put_u16(&mut directory, 0); // disk
+            put_u16(&mut directory, 0); // internal attributes
+            put_u32(&mut directory, FILE_MODE << 16);
+            put_u32(&mut directory, if big_offset { u32::MAX } else { entry.offset as u32 });
+            directory.extend_from_slice(&entry.name);
+            if !extra.is_empty() {
+                put_u16(&mut directory, ZIP64_EXTRA_ID);
+                put_u16(&mut directory, extra.len() as u16);
+                directory.extend_from_slice(&extra);
+            }
+        }
+        self.write(&directory).await?;
+
+        let count = self.entries.len() as u64;
+        let size = directory.len() as u64;
+        let mut end = Vec::with_capacity(98);
+        let zip64 = count > self.limit.min(u16::MAX as u64 - 1) || size > self.limit || start > self.limit;
+        if zip64 {
+            let record = self.offset;
+            put_u32(&mut end, ZIP64_END_SIG);
+            put_u64(&mut end, 44); // size of the rest of this record
+            put_u16(&mut end, MADE_BY_UNIX | VERSION_ZIP64);
+            put_u16(&mut end, VERSION_ZIP64);
+            put_u32(&mut end, 0); // this disk
+            put_u32(&mut end, 0); // disk holding the directory
+            put_u64(&mut end, count);
+            put_u64(&mut end, count);
+            put_u64(&mut end, size);
+            put_u64(&mut end, start);
+
+            put_u32(&mut end, ZIP64_LOCATOR_SIG);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, record);
+            put_u32(&mut end, 1); // total disks
+        }
+        put_u32(&mut end, END_SIG);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        let short_count = if zip64 { u16::MAX } else { count as u16 };
+        put_u16(&mut end, short_count);
+        put_u16(&mut end, short_count);
+        put_u32(&mut end, if zip64 { u32::MAX } else { size as u32 });
+        put_u32(&mut end, if zip64 { u32::MAX } else { start as u32 });
+        put_u16(&mut end, 0); // comment
+        self.write(&end).await?;
+
+        self.out.flush().await?;
+        self.out.shutdown().await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes).await?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// MS-DOS time and date in local time, as unzip tools expect; clamped to the
+/// format's 1980-2107 range.
+fn dos_datetime(modified: SystemTime) -> (u16, u16) {
+    let local = DateTime::<Local>::from(modified);
+    if local.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    if local.year() > 2107 {
+        return ((23 << 11) | (59 << 5) | 29, (127 << 9) | (12 << 5) | 31);
+    }
+    let time = (local.hour() << 11) | (local.minute() << 5) | (local.second() / 2);
+    let date = ((local.year() as u32 - 1980) << 9) | (local.month() << 5) | local.day();
+    (time as u16, date as u16)
+}
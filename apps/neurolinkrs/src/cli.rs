@@ -1,13 +1,25 @@
 use clap::{Parser, Subcommand};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 use indicatif::{ProgressBar, ProgressStyle};
 use console::style;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use reqwest::Client;
+use sha2::{Digest, Sha256};
 use anyhow::{Result, Context};
 
+/// Attempts per chunk when the server reports the bytes arrived corrupted.
+const CHUNK_HASH_RETRIES: usize = 3;
+
+/// Bytes read from each of several spots in a file to judge whether it compresses.
+const COMPRESSION_SAMPLE_SIZE: usize = 64 * 1024;
+const COMPRESSION_SAMPLES: u64 = 4;
+/// zstd level for chunks: fast enough not to become the bottleneck on Wi-Fi.
+const COMPRESSION_LEVEL: i32 = 3;
+
 #[derive(Parser)]
 #[command(name = "neuroshare")]
 #[command(about = "Send files to NeuroLink servers")]
@@ -86,27 +98,76 @@ async fn send_files(paths: Vec<PathBuf>, host: String, port: u16, chunk_size_kb:
         println!("{} {}", style("Sending:").bold(), style(filename).yellow());
         println!("  {} {}", style("Size:").dim(), format_size(file_size));
 
-        // Initialize transfer
-        let init_response: serde_json::Value = client
-            .post(format!("{}/transfer/init", base_url))
-            .json(&serde_json::json!({
-                "filename": filename,
-                "total_size": file_size,
-                "chunk_size": chunk_size
-            }))
-            .send()
-            .await?
-            .json()
-            .await?;
+        // Resume a previous attempt at this file if the server still has it
+        let key = resume_key(&base_url, &path, &metadata, chunk_size);
+        let mut resume_state = load_resume_state();
+        let mut received = HashSet::new();
+        let mut resumed = None;
 
-        if !init_response["success"].as_bool().unwrap_or(false) {
-            println!("  {} {}", style("Error:").red().bold(), 
-                init_response["error"].as_str().unwrap_or("Unknown error"));
-            continue;
+        if let Some(saved_id) = resume_state.get(&key) {
+            let manifest: serde_json::Value = client
+                .get(format!("{}/transfer/{}/chunks", base_url, saved_id))
+                .send()
+                .await?
+                .json()
+                .await
+                .unwrap_or_default();
+
+            if manifest["success"].as_bool().unwrap_or(false) {
+                let data = &manifest["data"];
+                if let Some(chunks) = data["received"].as_array() {
+                    received.extend(chunks.iter().filter_map(|c| c["index"].as_u64()));
+                }
+                let total_chunks = data["total_chunks"].as_u64().unwrap_or(0) as usize;
+                println!("  {} {}/{} chunks already on server",
+                    style("Resuming:").dim(), received.len(), total_chunks);
+                resumed = Some((saved_id.clone(), total_chunks));
+            } else {
+                // Failed, finished or expired on the server: forget it and start over
+                resume_state.remove(&key);
+                save_resume_state(&resume_state);
+            }
         }
 
-        let transfer_id = init_response["data"]["transfer_id"].as_str().unwrap();
-        let total_chunks = init_response["data"]["total_chunks"].as_u64().unwrap() as usize;
+        let (transfer_id, total_chunks) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let file_hash = file_sha256(&path).await?;
+
+                // Initialize transfer
+                let init_response: serde_json::Value = client
+                    .post(format!("{}/transfer/init", base_url))
+                    .json(&serde_json::json!({
+                        "filename": filename,
+                        "total_size": file_size,
+                        "chunk_size": chunk_size,
+                        "expected_sha256": file_hash
+                    }))
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+
+                if !init_response["success"].as_bool().unwrap_or(false) {
+                    println!("  {} {}", style("Error:").red().bold(), 
+                        init_response["error"].as_str().unwrap_or("Unknown error"));
+                    continue;
+                }
+
+                // The server already had this content and stored the file without any chunks
+                if init_response["data"]["completed"].as_bool().unwrap_or(false) {
+                    let stored = init_response["data"]["stored_name"].as_str().unwrap_or(filename);
+                    println!("  {} already on server, stored as {}\n", style("Instant:").green().bold(), stored);
+                    continue;
+                }
+
+                let transfer_id = init_response["data"]["transfer_id"].as_str().unwrap().to_string();
+                let total_chunks = init_response["data"]["total_chunks"].as_u64().unwrap() as usize;
+                resume_state.insert(key.clone(), transfer_id.clone());
+                save_resume_state(&resume_state);
+                (transfer_id, total_chunks)
+            }
+        };
 
         // Create progress bar
         let pb = ProgressBar::new(file_size);
@@ -115,44 +176,79 @@ async fn send_files(paths: Vec<PathBuf>, host: String, port: u16, chunk_size_kb:
             .unwrap()
             .progress_chars("#>-"));
 
-        // Read and send chunks
+        // Already-compressed media would only cost CPU, so sample before deciding
         let mut file = File::open(&path).await?;
+        let compress = worth_compressing(&mut file, file_size).await?;
+        if compress {
+            pb.println(format!("  {} zstd", style("Compression:").dim()));
+        }
+
+        // Read and send the chunks the server does not have yet
         let mut buffer = vec![0u8; chunk_size];
-        let mut chunk_index = 0;
         let mut uploaded = 0u64;
-
-        loop {
-            let bytes_read = file.read(&mut buffer).await?;
-            if bytes_read == 0 {
-                break;
-            }
-
-            let chunk_data = &buffer[..bytes_read];
-
-            // Create multipart form
-            let form = reqwest::multipart::Form::new()
-                .text("transfer_id", transfer_id.to_string())
-                .text("chunk_index", chunk_index.to_string())
-                .part("chunk", reqwest::multipart::Part::bytes(chunk_data.to_vec()));
-
-            let response: serde_json::Value = client
-                .post(format!("{}/transfer/chunk", base_url))
-                .multipart(form)
-                .send()
-                .await?
-                .json()
-                .await?;
-
-            if !response["success"].as_bool().unwrap_or(false) {
-                pb.println(format!("  {} Chunk {} failed", style("Error:").red(), chunk_index));
-                continue;
+        let mut failed_chunks = 0;
+
+        for chunk_index in 0..total_chunks {
+            let offset = chunk_index as u64 * chunk_size as u64;
+            let chunk_len = (file_size - offset).min(chunk_size as u64) as usize;
+
+            if !received.contains(&(chunk_index as u64)) {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.read_exact(&mut buffer[..chunk_len]).await?;
+                let chunk_data = &buffer[..chunk_len];
+                let chunk_hash = hex::encode(Sha256::digest(chunk_data));
+                // The hash always covers the original bytes; fall back to raw when a chunk doesn't shrink
+                let packed = if compress {
+                    zstd::bulk::compress(chunk_data, COMPRESSION_LEVEL)
+                        .ok()
+                        .filter(|packed| packed.len() < chunk_len - chunk_len / 10)
+                } else {
+                    None
+                };
+
+                let mut response = serde_json::Value::Null;
+                for _ in 0..CHUNK_HASH_RETRIES {
+                    // Create multipart form
+                    let form = reqwest::multipart::Form::new()
+                        .text("transfer_id", transfer_id.clone())
+                        .text("chunk_index", chunk_index.to_string())
+                        .text("chunk_sha256", chunk_hash.clone());
+                    let form = match &packed {
+                        Some(packed) => form
+                            .text("chunk_encoding", "zstd")
+                            .part("chunk", reqwest::multipart::Part::bytes(packed.clone())),
+                        None => form.part("chunk", reqwest::multipart::Part::bytes(chunk_data.to_vec())),
+                    };
+
+                    response = client
+                        .post(format!("{}/transfer/chunk", base_url))
+                        .multipart(form)
+                        .send()
+                        .await?
+                        .json()
+                        .await?;
+
+                    if response["code"].as_str() != Some("chunk_hash_mismatch") {
+                        break;
+                    }
+                }
+
+                if !response["success"].as_bool().unwrap_or(false) {
+                    pb.println(format!("  {} Chunk {} failed", style("Error:").red(), chunk_index));
+                    failed_chunks += 1;
+                    continue;
+                }
             }
 
-            uploaded += bytes_read as u64;
-            chunk_index += 1;
-            
+            uploaded += chunk_len as u64;
             pb.set_position(uploaded);
-            pb.set_message(format!("Chunk {}/{}", chunk_index, total_chunks));
+            pb.set_message(format!("Chunk {}/{}", chunk_index + 1, total_chunks));
+        }
+
+        if failed_chunks > 0 {
+            pb.abandon_with_message(format!("{} chunk(s) failed", failed_chunks));
+            println!("  {} re-run the same command to resume\n", style("Incomplete:").yellow().bold());
+            continue;
         }
 
         pb.finish_with_message("Upload complete, finalizing...");
@@ -169,6 +265,8 @@ async fn send_files(paths: Vec<PathBuf>, host: String, port: u16, chunk_size_kb:
             .await?;
 
         if complete_response["success"].as_bool().unwrap_or(false) {
+            resume_state.remove(&key);
+            save_resume_state(&resume_state);
             println!("  {}\n", style("Success!").green().bold());
         } else {
             println!("  {} {}\n", style("Failed:").red().bold(),
@@ -191,6 +289,72 @@ async fn list_devices(timeout: u64) -> Result<()> {
     Ok(())
 }
 
+async fn file_sha256(path: &std::path::Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Compresses a few samples spread across the file and reports whether zstd
+/// saved enough to be worth doing for every chunk.
+async fn worth_compressing(file: &mut File, file_size: u64) -> Result<bool> {
+    let mut sample = vec![0u8; COMPRESSION_SAMPLE_SIZE];
+    let mut raw = 0usize;
+    let mut packed = 0usize;
+
+    for i in 0..COMPRESSION_SAMPLES {
+        let offset = file_size / COMPRESSION_SAMPLES * i;
+        let len = (file_size - offset).min(COMPRESSION_SAMPLE_SIZE as u64) as usize;
+        if len == 0 {
+            break;
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.read_exact(&mut sample[..len]).await?;
+        raw += len;
+        packed += zstd::bulk::compress(&sample[..len], COMPRESSION_LEVEL)?.len();
+    }
+
+    // Require at least a 20% saving overall
+    Ok(raw > 0 && packed * 5 < raw * 4)
+}
+
+/// Identifies one attempt at sending a file so an interrupted send can resume.
+fn resume_key(base_url: &str, path: &std::path::Path, metadata: &std::fs::Metadata, chunk_size: usize) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("{}|{}|{}|{}|{}", base_url, path.display(), metadata.len(), modified, chunk_size)
+}
+
+fn resume_state_path() -> PathBuf {
+    std::env::temp_dir().join("neuroshare-resume.json")
+}
+
+fn load_resume_state() -> HashMap<String, String> {
+    std::fs::read(resume_state_path())
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_resume_state(state: &HashMap<String, String>) {
+    if let Ok(data) = serde_json::to_vec_pretty(state) {
+        let _ = std::fs::write(resume_state_path(), data);
+    }
+}
+
 fn format_size(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     let mut size = bytes as f64;
@@ -0,0 +1,417 @@
+// Content-addressed blob store.
+//
+// Each distinct content is kept once at `blobs/<first two hex digits>/<sha256>`.
+// The blob is read-only, and the batch copies of that content, which nothing
+// edits either, are hard links to it: every batch holding the content shares
+// its one copy on disk. Shared files stay users' to edit, so they are clones of
+// the blob instead: copy-on-write reflinks where the filesystem supports them,
+// which cost no extra space, and plain copies elsewhere. Editing a shared file
+// in place never reaches the blob or a batch copy.
+// A blob is reused only after it has been read back against its hash; that
+// check is remembered until the blob's size or modification time changes.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use anyhow::Result;
+
+use crate::storage::{self, StorageKey};
+
+pub const BLOBS_DIR: &str = "blobs";
+const INDEX_FILE: &str = "index.json";
+
+/// How recently a blob may have been modified and still have its check
+/// remembered. File times are only as fine as the kernel's clock tick, so a
+/// write just after a check could leave the time unchanged.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// A blob's size and modification time, which change whenever it is rewritten.
+type Stamp = (u64, SystemTime);
+
+/// One distinct content and how many stored files were made from it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct BlobRecord {
+    size: u64,
+    references: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupStats {
+    /// Distinct contents held in the store.
+    pub blobs: usize,
+    /// Uploads that reference a blob.
+    pub references: u64,
+    /// Bytes the batch copies linked to a blob would take as files of their own.
+    pub logical_bytes: u64,
+    /// Bytes the blobs actually take.
+    pub stored_bytes: u64,
+    /// Bytes not taken because batch copies share their blob. Reflinked clones
+    /// share space too, but the filesystem does not report it, so they are not counted.
+    pub saved_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct DedupStore {
+    dir: PathBuf,
+    index: Mutex<HashMap<String, BlobRecord>>,
+    /// Blobs found intact, by the size and modification time they had then,
+    /// with their content's size.
+    verified: Mutex<HashMap<String, (Stamp, u64)>>,
+    /// Needed to size blobs that are encrypted at rest.
+    key: Option<StorageKey>,
+}
+
+impl DedupStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            index: Mutex::new(HashMap::new()),
+            verified: Mutex::new(HashMap::new()),
+            key: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: Option<StorageKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Reads the persisted index; a missing or unreadable index starts empty.
+    pub async fn load(&self) -> Result<()> {
+        let content = match fs::read(self.dir.join(INDEX_FILE)).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_slice(&content) {
+            Ok(index) => *self.index.lock().await = index,
+            Err(e) => warn!("Ignoring unreadable dedup index: {}", e),
+        }
+        Ok(())
+    }
+
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Size of the content stored for `hash`, if that content is held and its
+    /// blob, read back, still hashes to `hash`.
+    ///
+    /// The blob is only read again once its size or modification time has
+    /// changed since it last checked out. This is the content's own size even
+    /// when the blob is compressed or encrypted.
+    pub async fn verify(&self, hash: &str) -> Option<u64> {
+        let path = self.blob_path(hash);
+        let meta = fs::metadata(&path).await.ok()?;
+        let stamp: Stamp = (meta.len(), meta.modified().ok()?);
+        if let Some((checked, size)) = self.verified.lock().await.get(hash) {
+            if *checked == stamp {
+                return Some(*size);
+            }
+        }
+
+        let (reader, info) = storage::open(&path, self.key.as_ref()).await.ok()?;
+        let actual = super::compute_reader_hash(reader).await.ok()?;
+        if actual != hash {
+            warn!("Blob {} no longer matches its hash; it will be replaced", hash);
+            self.verified.lock().await.remove(hash);
+            return None;
+        }
+        let settled = SystemTime::now().duration_since(stamp.1).is_ok_and(|age| age > RACY_WINDOW);
+        if settled {
+            self.verified.lock().await.insert(hash.to_string(), (stamp, info.logical_size));
+        }
+        Some(info.logical_size)
+    }
+
+    /// Makes `source` (whose content hashes to `hash`) a clone of the blob for
+    /// that content, copying it into the store if the content is new or the
+    /// stored blob is damaged.
+    ///
+    /// Returns the blob path. Nothing is counted until [`record`](Self::record).
+    pub async fn ingest(&self, source: &Path, hash: &str) -> Result<PathBuf> {
+        let blob = self.blob_path(hash);
+        let size = storage::inspect_async(source, self.key.as_ref()).await?.logical_size;
+
+        // Held so two uploads of new identical content agree on one blob
+        let _index = self.index.lock().await;
+        match self.verify(hash).await {
+            Some(stored) if stored == size => clone_or_copy(&blob, source).await?,
+            _ => {
+                clone_or_copy(source, &blob).await?;
+                seal(&blob).await?;
+            }
+        }
+        Ok(blob)
+    }
+
+    /// Clones `source` into the store unless its content is held already,
+    /// leaving `source` itself untouched. Returns whether a blob was added.
+    ///
+    /// Only a reflink is made: a full copy of a file the store does not
+    /// otherwise need would double the space its content takes.
+    pub async fn adopt(&self, source: &Path, hash: &str) -> Result<bool> {
+        let size = storage::inspect_async(source, self.key.as_ref()).await?.logical_size;
+
+        let _index = self.index.lock().await;
+        if self.verify(hash).await == Some(size) {
+            return Ok(false);
+        }
+        let blob = self.blob_path(hash);
+        if !place(source, &blob, Method::Clone).await? {
+            return Ok(false);
+        }
+        seal(&blob).await?;
+        Ok(true)
+    }
+
+    /// Puts the blob for `hash` at `dest` as a hard link, for copies that are
+    /// never edited, such as a batch's. Falls back to a clone where the
+    /// filesystem cannot link.
+    pub async fn link(&self, hash: &str, dest: &Path) -> Result<()> {
+        link_or_clone(&self.blob_path(hash), dest).await
+    }
+
+    /// Counts one more stored file built from the blob for `hash`.
+    pub async fn record(&self, hash: &str, size: u64) {
+        let mut index = self.index.lock().await;
+        let record = index
+            .entry(hash.to_string())
+            .or_insert(BlobRecord { size, references: 0 });
+        record.references += 1;
+
+        if let Err(e) = self.persist(&index).await {
+            warn!("Failed to save dedup index: {}", e);
+        }
+    }
+
+    /// Counts one stored file less for `hash`, and removes the blob once no
+    /// stored file was made from it any more.
+    pub async fn release(&self, hash: &str) {
+        let mut index = self.index.lock().await;
+        let Some(record) = index.get_mut(hash) else {
+            return;
+        };
+        record.references = record.references.saturating_sub(1);
+
+        if record.references == 0 {
+            self.verified.lock().await.remove(hash);
+            match fs::remove_file(self.blob_path(hash)).await {
+                Ok(()) => {
+                    index.remove(hash);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    index.remove(hash);
+                }
+                Err(e) => warn!("Failed to remove unused blob {}: {}", hash, e),
+            }
+        }
+        if let Err(e) = self.persist(&index).await {
+            warn!("Failed to save dedup index: {}", e);
+        }
+    }
+
+    /// Sets each held blob's count to the `(hash, size)` uploads made from it,
+    /// so counts a crash left out of step with those uploads are put right.
+    /// Blobs no upload is made from any more are removed.
+    pub async fn recount<'a>(&self, uploads: impl IntoIterator<Item = (&'a str, u64)>) {
+        let mut index = self.index.lock().await;
+        let mut recounted: HashMap<String, BlobRecord> = HashMap::new();
+        for (hash, size) in uploads {
+            recounted.entry(hash.to_string()).or_insert(BlobRecord { size, references: 0 }).references += 1;
+        }
+        for (hash, record) in recounted.iter_mut() {
+            // Content whose blob is gone is stored afresh by its next upload
+            if !fs::try_exists(self.blob_path(hash)).await.unwrap_or(true) {
+                record.references = 0;
+            }
+        }
+        recounted.retain(|_, record| record.references > 0);
+
+        for (hash, record) in index.iter() {
+            if recounted.contains_key(hash) {
+                continue;
+            }
+            self.verified.lock().await.remove(hash);
+            match fs::remove_file(self.blob_path(hash)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Failed to remove unused blob {}: {}", hash, e);
+                    recounted.insert(hash.clone(), BlobRecord { references: 0, ..record.clone() });
+                }
+            }
+        }
+        if *index != recounted {
+            *index = recounted;
+            if let Err(e) = self.persist(&index).await {
+                warn!("Failed to save dedup index: {}", e);
+            }
+        }
+    }
+
+    /// Counts uploads from the index and space from the blobs on disk.
+    pub async fn stats(&self) -> DedupStats {
+        let index = self.index.lock().await;
+        let mut stats = DedupStats { blobs: index.len(), ..Default::default() };
+        for (hash, record) in index.iter() {
+            stats.references += record.references;
+            let Ok(meta) = fs::metadata(self.blob_path(hash)).await else {
+                continue;
+            };
+            // Every other name of the blob is a batch copy that would otherwise be a file of its own
+            let copies = meta.len() * (hard_links(&meta) - 1);
+            stats.logical_bytes += copies;
+            stats.stored_bytes += meta.len();
+            stats.saved_bytes += copies.saturating_sub(meta.len());
+        }
+        stats
+    }
+
+    async fn persist(&self, index: &HashMap<String, BlobRecord>) -> Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&tmp, serde_json::to_vec(index)?).await?;
+        fs::rename(&tmp, self.dir.join(INDEX_FILE)).await?;
+        Ok(())
+    }
+}
+
+/// Puts a copy of `source` at `dest`, replacing whatever is there.
+///
+/// The copy is a reflink sharing `source`'s blocks where the filesystem can
+/// clone files, and a full copy otherwise. It is made beside `dest` and
+/// renamed over it, so readers never see a missing or partial file. The copy
+/// is writable even when `source` is a read-only blob.
+pub async fn clone_or_copy(source: &Path, dest: &Path) -> Result<()> {
+    place(source, dest, Method::CloneOrCopy).await?;
+    Ok(())
+}
+
+/// Puts a hard link to `source` at `dest`, replacing whatever is there, or
+/// a clone or copy where the filesystem cannot link.
+pub async fn link_or_clone(source: &Path, dest: &Path) -> Result<()> {
+    place(source, dest, Method::Link).await?;
+    Ok(())
+}
+
+/// Whether files in `dir` can be cloned with reflinks, which share their
+/// blocks until either side is written.
+pub async fn reflinks_supported(dir: &Path) -> bool {
+    let probe = dir.join(".reflink-probe");
+    if fs::write(&probe, b"probe").await.is_err() {
+        return false;
+    }
+    let supported = place(&probe, &dir.join(".reflink-probe-clone"), Method::Clone).await.unwrap_or(false);
+    let _ = fs::remove_file(&probe).await;
+    let _ = fs::remove_file(dir.join(".reflink-probe-clone")).await;
+    supported
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    /// Reflink only; nothing is placed where that is unsupported.
+    Clone,
+    /// Reflink, or a full copy.
+    CloneOrCopy,
+    /// Hard link, or else as [`Method::CloneOrCopy`].
+    Link,
+}
+
+/// Places `source` at `dest` by `method`, through a temporary name beside
+/// `dest`. Returns false if nothing was placed.
+async fn place(source: &Path, dest: &Path, method: Method) -> Result<bool> {
+    if same_file::is_same_file(source, dest).unwrap_or(false) {
+        return Ok(true);
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let file_name = dest.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let tmp = dest.with_file_name(format!(".{}.clone-tmp", file_name));
+    let _ = fs::remove_file(&tmp).await;
+
+    let linked = method == Method::Link && match fs::hard_link(source, &tmp).await {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Hard link to {} unavailable ({}); cloning instead", dest.display(), e);
+            false
+        }
+    };
+    if !linked {
+        let (from, to) = (source.to_path_buf(), tmp.clone());
+        if let Err(e) = tokio::task::spawn_blocking(move || reflink(&from, &to)).await? {
+            let _ = fs::remove_file(&tmp).await;
+            if method == Method::Clone {
+                debug!("Reflink to {} unavailable ({})", dest.display(), e);
+                return Ok(false);
+            }
+            debug!("Reflink to {} unavailable ({}); copying instead", dest.display(), e);
+            fs::copy(source, &tmp).await?;
+        }
+        let mut permissions = fs::metadata(&tmp).await?.permissions();
+        if permissions.readonly() {
+            make_writable(&mut permissions);
+            fs::set_permissions(&tmp, permissions).await?;
+        }
+    }
+    if let Err(e) = fs::rename(&tmp, dest).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+/// Makes a blob read-only, so it cannot be written through any of its names.
+async fn seal(path: &Path) -> std::io::Result<()> {
+    let mut permissions = fs::metadata(path).await?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions).await
+}
+
+#[cfg(unix)]
+pub(crate) fn make_writable(permissions: &mut std::fs::Permissions) {
+    use std::os::unix::fs::PermissionsExt;
+
+    permissions.set_mode(permissions.mode() | 0o200);
+}
+
+#[cfg(not(unix))]
+pub(crate) fn make_writable(permissions: &mut std::fs::Permissions) {
+    permissions.set_readonly(false);
+}
+
+/// Names the file has; 1 where the platform does not report it.
+#[cfg(unix)]
+fn hard_links(meta: &std::fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(meta).max(1)
+}
+
+#[cfg(not(unix))]
+fn hard_links(_meta: &std::fs::Metadata) -> u64 {
+    1
+}
+
+/// Creates `dest` as a copy-on-write clone of `source`.
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src = std::fs::File::open(source)?;
+    let dst = std::fs::File::create(dest)?;
+    // SAFETY: both descriptors stay open for the duration of the call.
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+/// File cloning is only implemented for Linux.
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -1,18 +1,23 @@
 // File hashing and deduplication module
-// TODO: Implement SHA-256 streaming hash and deduplication index
+//
+// `compute_reader_hash` streams content through SHA-256; `DedupStore` keeps one
+// blob per distinct content under `.neurolink/blobs/` and hands out links and clones of it.
 
 use sha2::{Sha256, Digest};
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub async fn compute_file_hash(path: &Path) -> anyhow::Result<String> {
-    let mut file = File::open(path).await?;
+mod dedup;
+
+pub use dedup::{clone_or_copy, link_or_clone, reflinks_supported, DedupStats, DedupStore, BLOBS_DIR};
+#[cfg(test)]
+pub(crate) use dedup::make_writable;
+
+pub async fn compute_reader_hash(mut reader: impl AsyncRead + Unpin) -> anyhow::Result<String> {
     let mut hasher = Sha256::new();
-    let mut buffer = vec![0u8; 8192]; // 8KB chunks
+    let mut buffer = vec![0u8; 64 * 1024];
 
     loop {
-        let bytes_read = file.read(&mut buffer).await?;
+        let bytes_read = reader.read(&mut buffer).await?;
         if bytes_read == 0 {
             break;
         }
@@ -21,3 +26,22 @@ pub async fn compute_file_hash(path: &Path) -> anyhow::Result<String> {
 
     Ok(hex::encode(hasher.finalize()))
 }
+
+/// Renders `(hash, name)` pairs as a `SHA256SUMS` file that `sha256sum -c`
+/// accepts. As in coreutils, a name holding a backslash or newline is escaped
+/// and its line marked with a leading backslash.
+pub fn sha256sums<'a>(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut out = String::new();
+    for (hash, name) in files {
+        if name.contains(['\\', '\n']) {
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            out.push_str(&format!("\\{}  {}\n", hash, escaped));
+        } else {
+            out.push_str(&format!("{}  {}\n", hash, name));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,159 @@
+use super::*;
+use tempfile::TempDir;
+
+const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+/// Writes over a read-only blob, as a failing disk or a careless user might.
+fn tamper(path: &std::path::Path, data: &[u8]) {
+    let mut permissions = std::fs::metadata(path).unwrap().permissions();
+    make_writable(&mut permissions);
+    std::fs::set_permissions(path, permissions).unwrap();
+    std::fs::write(path, data).unwrap();
+}
+
+#[tokio::test]
+async fn test_compute_reader_hash() {
+    assert_eq!(compute_reader_hash(&b"hello"[..]).await.unwrap(), HELLO_SHA256);
+}
+
+#[test]
+fn test_sha256sums_lines_match_coreutils() {
+    let sums = sha256sums([(HELLO_SHA256, "photos/a b.jpg"), (HELLO_SHA256, "odd\\name")]);
+    assert_eq!(
+        sums,
+        format!("{0}  photos/a b.jpg\n\\{0}  odd\\\\name\n", HELLO_SHA256)
+    );
+}
+
+#[tokio::test]
+async fn test_identical_content_shares_one_blob() {
+    let dir = TempDir::new().unwrap();
+    let store = DedupStore::new(dir.path().join("blobs"));
+    let first = dir.path().join("Shinchan-freinds.PNG");
+    let second = dir.path().join("Shinchan-freinds.png");
+    std::fs::write(&first, b"hello").unwrap();
+    std::fs::write(&second, b"hello").unwrap();
+
+    let blob = store.ingest(&first, HELLO_SHA256).await.unwrap();
+    store.record(HELLO_SHA256, 5).await;
+    assert_eq!(store.ingest(&second, HELLO_SHA256).await.unwrap(), blob);
+    store.record(HELLO_SHA256, 5).await;
+
+    assert_eq!(std::fs::read(&blob).unwrap(), b"hello");
+    assert_eq!(std::fs::read(&second).unwrap(), b"hello");
+    // Clones, not links: editing a stored file in place leaves the blob alone
+    assert!(!same_file::is_same_file(&second, &blob).unwrap());
+    assert!(std::fs::metadata(&blob).unwrap().permissions().readonly());
+    std::fs::write(&second, b"HELLO").unwrap();
+    assert_eq!(store.verify(HELLO_SHA256).await, Some(5));
+
+    // Batch copies are links to the read-only blob and take no space of their own
+    let stats = store.stats().await;
+    assert_eq!((stats.logical_bytes, stats.saved_bytes), (0, 0));
+    for batch in ["batch_a", "batch_b"] {
+        let copy = dir.path().join(batch).join("Shinchan-freinds.png");
+        store.link(HELLO_SHA256, &copy).await.unwrap();
+        assert!(same_file::is_same_file(&copy, &blob).unwrap());
+        assert!(std::fs::metadata(&copy).unwrap().permissions().readonly());
+    }
+
+    let stats = store.stats().await;
+    assert_eq!(stats, DedupStats {
+        blobs: 1,
+        references: 2,
+        logical_bytes: 10,
+        stored_bytes: 5,
+        saved_bytes: 5,
+    });
+}
+
+#[tokio::test]
+async fn test_index_survives_reload() {
+    let dir = TempDir::new().unwrap();
+    let store = DedupStore::new(dir.path().join("blobs"));
+    let file = dir.path().join("a.txt");
+    std::fs::write(&file, b"hello").unwrap();
+    store.ingest(&file, HELLO_SHA256).await.unwrap();
+    store.record(HELLO_SHA256, 5).await;
+
+    let reloaded = DedupStore::new(dir.path().join("blobs"));
+    reloaded.load().await.unwrap();
+    assert_eq!(reloaded.stats().await.references, 1);
+    assert_eq!(reloaded.verify(HELLO_SHA256).await, Some(5));
+}
+
+#[tokio::test]
+async fn test_clone_or_copy_replaces_destination() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("nested").join("dest");
+    std::fs::write(&source, b"new").unwrap();
+    std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+    std::fs::write(&dest, b"old").unwrap();
+
+    clone_or_copy(&source, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+
+    // Copying again leaves no temp files behind
+    clone_or_copy(&source, &dest).await.unwrap();
+    let entries: Vec<_> = std::fs::read_dir(dest.parent().unwrap()).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_damaged_blob_is_not_reused_and_gets_replaced() {
+    let dir = TempDir::new().unwrap();
+    let store = DedupStore::new(dir.path().join("blobs"));
+    let file = dir.path().join("a.txt");
+    std::fs::write(&file, b"hello").unwrap();
+    let blob = store.ingest(&file, HELLO_SHA256).await.unwrap();
+    store.record(HELLO_SHA256, 5).await;
+
+    // Same size, other bytes
+    tamper(&blob, b"jello");
+    assert_eq!(store.verify(HELLO_SHA256).await, None);
+
+    let again = dir.path().join("b.txt");
+    std::fs::write(&again, b"hello").unwrap();
+    store.ingest(&again, HELLO_SHA256).await.unwrap();
+    assert_eq!(std::fs::read(&again).unwrap(), b"hello");
+    assert_eq!(store.verify(HELLO_SHA256).await, Some(5));
+}
+
+#[tokio::test]
+async fn test_release_removes_blob_after_last_reference() {
+    let dir = TempDir::new().unwrap();
+    let store = DedupStore::new(dir.path().join("blobs"));
+    let file = dir.path().join("a.txt");
+    std::fs::write(&file, b"hello").unwrap();
+    let blob = store.ingest(&file, HELLO_SHA256).await.unwrap();
+    store.record(HELLO_SHA256, 5).await;
+    store.record(HELLO_SHA256, 5).await;
+
+    store.release(HELLO_SHA256).await;
+    assert!(blob.exists());
+    store.release(HELLO_SHA256).await;
+    assert!(!blob.exists());
+    assert_eq!(std::fs::read(&file).unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn test_verified_blob_is_not_read_again_until_it_changes() {
+    let dir = TempDir::new().unwrap();
+    let store = DedupStore::new(dir.path().join("blobs"));
+    let file = dir.path().join("a.txt");
+    std::fs::write(&file, b"hello").unwrap();
+    let blob = store.ingest(&file, HELLO_SHA256).await.unwrap();
+    let settled = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    std::fs::File::open(&blob).unwrap().set_modified(settled).unwrap();
+    assert_eq!(store.verify(HELLO_SHA256).await, Some(5));
+
+    // Same size and time: trusted without reading the bytes again
+    tamper(&blob, b"jello");
+    std::fs::File::open(&blob).unwrap().set_modified(settled).unwrap();
+    assert_eq!(store.verify(HELLO_SHA256).await, Some(5));
+
+    // A new time sends it back through the hash
+    std::fs::File::open(&blob).unwrap().set_modified(settled + std::time::Duration::from_secs(1)).unwrap();
+    assert_eq!(store.verify(HELLO_SHA256).await, None);
+}
@@ -1,22 +1,28 @@
 use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use axum::Router;
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 use tokio::signal;
-use tower_http::services::ServeDir;
 use tower_http::cors::CorsLayer;
 use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 mod transfer;
 mod api;
+mod archive;
 mod hashing;
+mod sanitize;
+mod share;
+mod storage;
 
-use transfer::TransferManager;
+use storage::StorageKey;
+use transfer::{ConflictPolicy, TransferConfig, TransferLimits, TransferManager};
 
 #[derive(Parser, Debug)]
 #[command(name = "neurolinkrs", version = "2.0.0", about = "Rust file sharing server with built-in web UI")]
+#[command(group(ArgGroup::new("key").args(["encryption_key_file", "encryption_key"])))]
 struct Args {
     /// Port to run the server on
     #[arg(short, long, env = "NEUROLINKRS_PORT", default_value_t = 3001)]
@@ -25,6 +31,83 @@ struct Args {
     /// Directory to store and serve shared files
     #[arg(short, long, env = "NEUROLINKRS_STORAGE", default_value = "./shared")]
     storage: String,
+
+    /// Largest single file accepted (e.g. 4G, 500M); unlimited when unset
+    #[arg(long, env = "NEUROLINKRS_MAX_FILE_SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Largest total size of one upload batch; unlimited when unset
+    #[arg(long, env = "NEUROLINKRS_MAX_BATCH_SIZE", value_parser = parse_size)]
+    max_batch_size: Option<u64>,
+
+    /// Total bytes the storage directory may hold, daemon state included; each upload reserves what it will write (up to twice its size plus a chunk); unlimited when unset
+    #[arg(long, env = "NEUROLINKRS_STORAGE_QUOTA", value_parser = parse_size)]
+    storage_quota: Option<u64>,
+
+    /// What to do when an upload's filename already exists: overwrite, rename, reject or version
+    #[arg(long, env = "NEUROLINKRS_ON_CONFLICT", default_value_t = ConflictPolicy::Overwrite)]
+    on_conflict: ConflictPolicy,
+
+    /// Seconds a transfer may go without receiving a chunk before it expires
+    #[arg(long, env = "NEUROLINKRS_IDLE_TIMEOUT", default_value_t = 3600)]
+    idle_timeout: u64,
+
+    /// Hash files already in the storage directory at startup and clone their content into the dedup store, so uploads of it complete instantly; needs a filesystem with reflinks
+    #[arg(long, env = "NEUROLINKRS_DEDUP_EXISTING")]
+    dedup_existing: bool,
+
+    /// Store compressible uploads zstd-compressed on disk; downloads decompress them transparently
+    #[arg(long, env = "NEUROLINKRS_COMPRESS_AT_REST")]
+    compress_at_rest: bool,
+
+    /// Encrypt stored files with the key in this file (64 hex characters or 32 raw bytes)
+    #[arg(long, env = "NEUROLINKRS_ENCRYPTION_KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Encrypt stored files with this key, given as 64 hex characters; prefer the environment variable
+    #[arg(long, env = "NEUROLINKRS_ENCRYPTION_KEY", hide_env_values = true)]
+    encryption_key: Option<String>,
+
+    /// Encrypt the plaintext files already in the storage directory at startup
+    #[arg(long, env = "NEUROLINKRS_ENCRYPT_EXISTING", requires = "key")]
+    encrypt_existing: bool,
+
+    /// Serve /shared and /download/batch only through signed share links made with POST /share
+    #[arg(long, env = "NEUROLINKRS_REQUIRE_SHARE_LINKS")]
+    require_share_links: bool,
+
+    /// Token that lets other machines make share links, sent as `Authorization: Bearer <token>`; prefer the environment variable
+    #[arg(long, env = "NEUROLINKRS_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+}
+
+/// Parses a byte count with an optional binary K/M/G/T suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let upper = value.to_ascii_uppercase();
+    let digits = upper.trim_end_matches(['B', 'I']);
+    let (number, shift) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 10),
+        Some('M') => (&digits[..digits.len() - 1], 20),
+        Some('G') => (&digits[..digits.len() - 1], 30),
+        Some('T') => (&digits[..digits.len() - 1], 40),
+        _ => (digits, 0),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    number
+        .checked_mul(1u64 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+fn load_encryption_key(file: Option<&Path>, hex: Option<&str>) -> anyhow::Result<Option<StorageKey>> {
+    match (file, hex) {
+        (Some(path), _) => StorageKey::load(path).map(Some),
+        (None, Some(hex)) => StorageKey::from_hex(hex).map(Some),
+        (None, None) => Ok(None),
+    }
 }
 
 fn detect_lan_ip() -> Option<IpAddr> {
@@ -114,13 +197,57 @@ async fn main() {
     info!("Storage path: {}", storage_path);
     info!("Listening on port: {}", port);
 
-    // Initialize transfer manager
-    let transfer_manager = Arc::new(TransferManager::new(&storage_path));
+    let encryption_key = load_encryption_key(args.encryption_key_file.as_deref(), args.encryption_key.as_deref())
+        .expect("Failed to load encryption key");
+    if encryption_key.is_some() {
+        info!("Encryption at rest enabled");
+    }
+    if args.require_share_links {
+        info!("Downloads require signed share links");
+    }
+
+    // Initialize transfer manager and pick up transfers interrupted by a restart
+    let config = TransferConfig {
+        limits: TransferLimits {
+            max_file_size: args.max_file_size,
+            max_batch_size: args.max_batch_size,
+            storage_quota: args.storage_quota,
+        },
+        conflict_policy: args.on_conflict,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        compress_at_rest: args.compress_at_rest,
+        encryption_key,
+        require_share_links: args.require_share_links,
+        admin_token: args.admin_token.filter(|token| !token.is_empty()),
+        ..Default::default()
+    };
+    let transfer_manager = Arc::new(TransferManager::new(&storage_path).with_config(config));
+    if args.encrypt_existing {
+        let count = transfer_manager
+            .encrypt_existing()
+            .await
+            .expect("Failed to encrypt existing storage");
+        info!("Encrypted {} existing file(s)", count);
+    }
+    match transfer_manager.recover().await {
+        Ok(0) => {}
+        Ok(count) => info!("Recovered {} in-flight transfer(s)", count),
+        Err(e) => warn!("Failed to recover in-flight transfers: {}", e),
+    }
+    transfer_manager.spawn_reaper();
+    if args.dedup_existing {
+        let manager = transfer_manager.clone();
+        tokio::spawn(async move {
+            match manager.dedup_existing().await {
+                Ok(added) => info!("Added {} existing file content(s) to the dedup store", added),
+                Err(e) => warn!("Failed to deduplicate existing files: {}", e),
+            }
+        });
+    }
 
     // Build router
     let app = Router::new()
         .merge(api::routes::routes(transfer_manager))
-        .nest_service("/shared", ServeDir::new(storage_path.clone()))
         .layer(CorsLayer::permissive());
 
     let addr: SocketAddr = ([0, 0, 0, 0], port).into();
@@ -137,7 +264,8 @@ async fn main() {
     // Start server with graceful shutdown
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     
-    axum::serve(listener, app)
+    // Peer addresses let share management trust connections from this machine
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
@@ -0,0 +1,185 @@
+// Filename policy for everything written to or read from the storage root.
+//
+// Names arrive from clients, so anything that could climb out of the storage
+// root or alias daemon state is rejected outright. Names that are merely
+// awkward on some filesystem (Windows-reserved characters and device names,
+// trailing dots, overlong names) are normalized into a safe equivalent when
+// written, so uploads from any client still land somewhere predictable. Names
+// read are never rewritten: a file that already has such a name stays
+// reachable by it.
+//
+// Folder uploads use relative paths, which are checked one component at a
+// time and always come back joined with `/`.
+
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::transfer::STATE_DIR;
+
+/// Longest name, in bytes, most filesystems accept for a single component.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Deepest folder nesting accepted in a relative path.
+pub const MAX_DEPTH: usize = 32;
+
+/// Characters Windows refuses in filenames; replaced so shares stay portable.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows treats specially regardless of extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FilenameError {
+    #[error("Filename is empty")]
+    Empty,
+    #[error("Filename must not be an absolute path")]
+    Absolute,
+    #[error("Filename must not contain path separators or '..'")]
+    Traversal,
+    #[error("Filename contains control characters")]
+    ControlCharacter,
+    #[error("Filename is reserved for daemon state")]
+    Reserved,
+    #[error("Path is nested deeper than {MAX_DEPTH} levels")]
+    TooDeep,
+}
+
+/// Validates a client-supplied filename and returns the name to store it under.
+pub fn sanitize_filename(name: &str) -> Result<String, FilenameError> {
+    if name.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(FilenameError::ControlCharacter);
+    }
+    if name.starts_with(['/', '\\']) || has_drive_prefix(name) {
+        return Err(FilenameError::Absolute);
+    }
+    if name.contains(['/', '\\']) || name == ".." {
+        return Err(FilenameError::Traversal);
+    }
+
+    // Windows silently drops trailing dots and spaces, which would alias names
+    let trimmed = name.trim().trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+
+    let mut clean: String = trimmed
+        .chars()
+        .map(|c| if RESERVED_CHARS.contains(&c) { '_' } else { c })
+        .collect();
+
+    if clean.eq_ignore_ascii_case(STATE_DIR) {
+        return Err(FilenameError::Reserved);
+    }
+
+    let stem = clean.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        clean.insert(0, '_');
+    }
+
+    Ok(truncate_name(clean))
+}
+
+/// Validates a client-supplied relative path such as `photos/2024/a.jpg`.
+///
+/// Either separator is accepted; empty and `.` components are dropped, while
+/// `..` anywhere is rejected rather than resolved.
+pub fn sanitize_relative_path(path: &str) -> Result<String, FilenameError> {
+    if path.starts_with(['/', '\\']) || has_drive_prefix(path) {
+        return Err(FilenameError::Absolute);
+    }
+
+    let components: Vec<&str> = path
+        .split(['/', '\\'])
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    if components.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if components.len() > MAX_DEPTH {
+        return Err(FilenameError::TooDeep);
+    }
+
+    let clean = components
+        .into_iter()
+        .map(sanitize_filename)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(clean.join("/"))
+}
+
+/// Resolves a client-supplied name or relative path to the location under
+/// `root` that a file by that name is written to.
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, FilenameError> {
+    Ok(root.join(sanitize_relative_path(name)?))
+}
+
+/// Validates the relative path of a file to read, such as one `/files`
+/// listed, and returns it joined with `/`.
+///
+/// Traversal, absolute paths, control characters and daemon state are
+/// rejected as on write, but nothing is normalized. Only `/` separates
+/// components, plus `\` where the platform treats it as a separator.
+pub fn check_relative_path(path: &str) -> Result<String, FilenameError> {
+    if path.chars().any(char::is_control) {
+        return Err(FilenameError::ControlCharacter);
+    }
+    if path.starts_with(['/', '\\']) || has_drive_prefix(path) {
+        return Err(FilenameError::Absolute);
+    }
+
+    let components: Vec<&str> = path
+        .split(|c| c == '/' || std::path::is_separator(c))
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    if components.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if components.len() > MAX_DEPTH {
+        return Err(FilenameError::TooDeep);
+    }
+    for component in &components {
+        if *component == ".." {
+            return Err(FilenameError::Traversal);
+        }
+        if component.eq_ignore_ascii_case(STATE_DIR) {
+            return Err(FilenameError::Reserved);
+        }
+    }
+    Ok(components.join("/"))
+}
+
+/// Resolves the name of a file to read to its location under `root`, as it is.
+pub fn locate(root: &Path, name: &str) -> Result<PathBuf, FilenameError> {
+    Ok(root.join(check_relative_path(name)?))
+}
+
+fn has_drive_prefix(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Shortens `name` to [`MAX_NAME_LEN`] bytes, keeping a short extension intact.
+fn truncate_name(name: String) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name;
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+    let mut end = MAX_NAME_LEN - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+#[cfg(test)]
+mod tests;
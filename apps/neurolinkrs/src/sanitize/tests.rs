@@ -0,0 +1,124 @@
+use super::*;
+
+#[test]
+fn test_plain_names_pass_through() {
+    assert_eq!(sanitize_filename("report.pdf").unwrap(), "report.pdf");
+    assert_eq!(sanitize_filename(".bashrc").unwrap(), ".bashrc");
+    assert_eq!(sanitize_filename("résumé 2024.txt").unwrap(), "résumé 2024.txt");
+}
+
+#[test]
+fn test_traversal_is_rejected() {
+    for name in ["..", "../../.bashrc", "a/../b", "..\\..\\boot.ini", "sub/file.txt"] {
+        assert_eq!(sanitize_filename(name), Err(FilenameError::Traversal), "{}", name);
+    }
+}
+
+#[test]
+fn test_absolute_paths_are_rejected() {
+    for name in ["/etc/passwd", "\\Windows\\win.ini", "C:\\Windows\\win.ini", "c:evil.txt"] {
+        assert_eq!(sanitize_filename(name), Err(FilenameError::Absolute), "{}", name);
+    }
+}
+
+#[test]
+fn test_control_characters_are_rejected() {
+    for name in ["evil\0.txt", "line\nbreak.txt", "bell\x07", "del\x7f"] {
+        assert_eq!(sanitize_filename(name), Err(FilenameError::ControlCharacter), "{:?}", name);
+    }
+}
+
+#[test]
+fn test_empty_and_dot_only_names_are_rejected() {
+    for name in ["", ".", "...", "   ", " . "] {
+        assert_eq!(sanitize_filename(name), Err(FilenameError::Empty), "{:?}", name);
+    }
+}
+
+#[test]
+fn test_state_dir_is_reserved() {
+    assert_eq!(sanitize_filename(STATE_DIR), Err(FilenameError::Reserved));
+    assert_eq!(sanitize_filename(".NeuroLink"), Err(FilenameError::Reserved));
+}
+
+#[test]
+fn test_windows_device_names_are_prefixed() {
+    assert_eq!(sanitize_filename("CON").unwrap(), "_CON");
+    assert_eq!(sanitize_filename("nul.txt").unwrap(), "_nul.txt");
+    assert_eq!(sanitize_filename("com1.tar.gz").unwrap(), "_com1.tar.gz");
+    assert_eq!(sanitize_filename("console.log").unwrap(), "console.log");
+}
+
+#[test]
+fn test_reserved_characters_and_trailing_dots_are_normalized() {
+    assert_eq!(sanitize_filename("what?.txt").unwrap(), "what_.txt");
+    assert_eq!(sanitize_filename("a<b>|c*.txt").unwrap(), "a_b__c_.txt");
+    assert_eq!(sanitize_filename("notes.txt. . ").unwrap(), "notes.txt");
+    assert_eq!(sanitize_filename("  padded.txt").unwrap(), "padded.txt");
+}
+
+#[test]
+fn test_overlong_names_are_truncated_keeping_extension() {
+    let name = format!("{}.tar.gz", "a".repeat(300));
+    let clean = sanitize_filename(&name).unwrap();
+    assert_eq!(clean.len(), MAX_NAME_LEN);
+    assert!(clean.ends_with(".gz"));
+
+    // Truncation never splits a multi-byte character
+    let name = "é".repeat(200);
+    let clean = sanitize_filename(&name).unwrap();
+    assert!(clean.len() <= MAX_NAME_LEN);
+    assert!(clean.chars().all(|c| c == 'é'));
+}
+
+#[test]
+fn test_resolve_stays_under_root() {
+    let root = Path::new("/srv/share");
+    assert_eq!(resolve(root, "a.txt").unwrap(), root.join("a.txt"));
+    assert!(resolve(root, "../a.txt").is_err());
+    assert!(resolve(root, "/etc/passwd").is_err());
+}
+
+#[test]
+fn test_relative_paths_keep_their_folders() {
+    assert_eq!(sanitize_relative_path("photos/2024/a.jpg").unwrap(), "photos/2024/a.jpg");
+    assert_eq!(sanitize_relative_path("docs\\notes\\b.md").unwrap(), "docs/notes/b.md");
+    assert_eq!(sanitize_relative_path("a//./b.txt").unwrap(), "a/b.txt");
+    assert_eq!(sanitize_relative_path("aux/what?.txt").unwrap(), "_aux/what_.txt");
+}
+
+#[test]
+fn test_relative_path_traversal_and_absolute_are_rejected() {
+    assert_eq!(sanitize_relative_path("a/../../b"), Err(FilenameError::Traversal));
+    assert_eq!(sanitize_relative_path("../b"), Err(FilenameError::Traversal));
+    assert_eq!(sanitize_relative_path("/srv/b"), Err(FilenameError::Absolute));
+    assert_eq!(sanitize_relative_path("C:/b"), Err(FilenameError::Absolute));
+    assert_eq!(sanitize_relative_path(".neurolink/transfers/x"), Err(FilenameError::Reserved));
+    assert_eq!(sanitize_relative_path("a/b\0/c"), Err(FilenameError::ControlCharacter));
+    assert_eq!(sanitize_relative_path("./"), Err(FilenameError::Empty));
+}
+
+#[test]
+fn test_relative_path_depth_is_limited() {
+    let deep = vec!["d"; MAX_DEPTH + 1].join("/");
+    assert_eq!(sanitize_relative_path(&deep), Err(FilenameError::TooDeep));
+    let ok = vec!["d"; MAX_DEPTH].join("/");
+    assert!(sanitize_relative_path(&ok).is_ok());
+}
+
+#[test]
+fn test_read_paths_are_checked_but_not_rewritten() {
+    let root = Path::new("/srv/share");
+    for name in ["shot 10:00.png", "what?.txt", "CON", "notes.txt. ", "photos/a*b.jpg"] {
+        assert_eq!(check_relative_path(name).unwrap(), name, "{}", name);
+    }
+    assert_eq!(locate(root, "./photos//a.jpg").unwrap(), root.join("photos/a.jpg"));
+
+    assert_eq!(check_relative_path("../a.txt"), Err(FilenameError::Traversal));
+    assert_eq!(check_relative_path("photos/../../a.txt"), Err(FilenameError::Traversal));
+    assert_eq!(check_relative_path("/etc/passwd"), Err(FilenameError::Absolute));
+    assert_eq!(check_relative_path("C:\\Windows\\win.ini"), Err(FilenameError::Absolute));
+    assert_eq!(check_relative_path("a\0b"), Err(FilenameError::ControlCharacter));
+    assert_eq!(check_relative_path(".neurolink/secret"), Err(FilenameError::Reserved));
+    assert_eq!(check_relative_path("./"), Err(FilenameError::Empty));
+}
@@ -0,0 +1,219 @@
+// Signed, expiring share links.
+//
+// A link names what it grants (one stored file or one upload batch) and when
+// it expires, and carries an HMAC-SHA256 over both keyed by a secret kept in
+// `.neurolink/share.key`. Nothing is stored per link, so a link cannot be
+// revoked early; replacing the secret file invalidates every link at once.
+// Shares with a password or a download limit are the exception: those are
+// recorded server-side (see `store`) and their links carry only a record id.
+
+mod store;
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub use store::{Claim, ShareRecord, ShareStore};
+
+pub const SECRET_FILE: &str = "share.key";
+const SECRET_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a share link grants access to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum ShareTarget {
+    /// A file under the storage root, by its `/`-separated stored name.
+    File(String),
+    /// An upload batch: its archive, checksums and each of its files.
+    Batch(String),
+}
+
+impl ShareTarget {
+    /// The download path the link points at.
+    fn path(&self) -> String {
+        match self {
+            Self::File(name) => format!("/shared/{}", encode_path(name)),
+            Self::Batch(batch_id) => format!("/download/batch/{}", encode_path(batch_id)),
+        }
+    }
+
+    /// The signed message; stored names never hold control characters.
+    fn message(&self, expires: u64) -> String {
+        match self {
+            Self::File(name) => format!("file\n{}\n{}", name, expires),
+            Self::Batch(batch_id) => format!("batch\n{}\n{}", batch_id, expires),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ShareError {
+    #[error("A share link is required for this download")]
+    Missing,
+    #[error("Share link has expired")]
+    Expired,
+    #[error("Share link is not valid")]
+    Invalid,
+    #[error("Share link has been used up or revoked")]
+    Revoked,
+    #[error("This share needs a password")]
+    PasswordRequired,
+    #[error("Wrong password for this share")]
+    WrongPassword,
+    #[error("Only this machine or a caller with the admin token may do this")]
+    AdminRequired,
+}
+
+impl ShareError {
+    /// Stable machine-readable code reported to clients alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ShareError::Missing => "share_link_required",
+            ShareError::Expired => "share_link_expired",
+            ShareError::Invalid => "share_link_invalid",
+            ShareError::Revoked => "share_link_revoked",
+            ShareError::PasswordRequired => "share_password_required",
+            ShareError::WrongPassword => "share_password_wrong",
+            ShareError::AdminRequired => "admin_required",
+        }
+    }
+
+    /// Whether asking again with a password could succeed.
+    pub fn wants_password(&self) -> bool {
+        matches!(self, ShareError::PasswordRequired | ShareError::WrongPassword)
+    }
+}
+
+/// Link signing, and the records of shares limited by a password or a
+/// number of downloads.
+#[derive(Debug)]
+pub struct Shares {
+    pub signer: ShareSigner,
+    pub store: ShareStore,
+}
+
+impl Shares {
+    pub async fn load(state_dir: &Path, now: u64) -> io::Result<Self> {
+        Ok(Self {
+            signer: ShareSigner::load_or_create(state_dir).await?,
+            store: ShareStore::load(state_dir, now).await?,
+        })
+    }
+}
+
+/// Whether `given` is the admin token. Digests are compared in constant time,
+/// so the token cannot be guessed byte by byte.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (Sha256::digest(expected), Sha256::digest(given));
+    expected.iter().zip(given.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The link to a recorded share of `target`.
+pub fn record_link(target: &ShareTarget, id: &str) -> String {
+    format!("{}?share={}", target.path(), id)
+}
+
+pub struct ShareSigner {
+    secret: [u8; SECRET_LEN],
+}
+
+impl fmt::Debug for ShareSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ShareSigner(..)")
+    }
+}
+
+impl ShareSigner {
+    pub fn new(secret: [u8; SECRET_LEN]) -> Self {
+        Self { secret }
+    }
+
+    /// Loads the secret from `state_dir`, creating a random one readable only
+    /// by this user on first use.
+    pub async fn load_or_create(state_dir: &Path) -> io::Result<Self> {
+        let path = state_dir.join(SECRET_FILE);
+        fs::create_dir_all(state_dir).await?;
+
+        let mut secret = [0u8; SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        match options.open(&path).await {
+            Ok(mut file) => {
+                file.write_all(&secret).await?;
+                file.sync_all().await?;
+                return Ok(Self::new(secret));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut stored = Vec::with_capacity(SECRET_LEN);
+        fs::File::open(&path).await?.read_to_end(&mut stored).await?;
+        let secret = stored.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} must hold exactly {} bytes", path.display(), SECRET_LEN),
+            )
+        })?;
+        Ok(Self::new(secret))
+    }
+
+    /// Hex signature for `target` until `expires` (seconds since the epoch).
+    pub fn sign(&self, target: &ShareTarget, expires: u64) -> String {
+        hex::encode(self.mac(target, expires).finalize().into_bytes())
+    }
+
+    /// The link to `target`, valid until `expires`.
+    pub fn link(&self, target: &ShareTarget, expires: u64) -> String {
+        format!("{}?expires={}&sig={}", target.path(), expires, self.sign(target, expires))
+    }
+
+    /// Checks a link's `expires` and `sig` parameters against `target` at time `now`.
+    pub fn verify(&self, target: &ShareTarget, expires: Option<u64>, sig: Option<&str>, now: u64) -> Result<(), ShareError> {
+        let (Some(expires), Some(sig)) = (expires, sig) else {
+            return Err(ShareError::Missing);
+        };
+        let sig = hex::decode(sig).map_err(|_| ShareError::Invalid)?;
+        // Constant-time comparison, so a signature cannot be guessed byte by byte
+        self.mac(target, expires).verify_slice(&sig).map_err(|_| ShareError::Invalid)?;
+        if now >= expires {
+            return Err(ShareError::Expired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, target: &ShareTarget, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(target.message(expires).as_bytes());
+        mac
+    }
+}
+
+/// Percent-encodes each `/`-separated segment of a stored name for a URL path.
+fn encode_path(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,183 @@
+// Server-side records of limited shares.
+//
+// A share with a password or a download allowance cannot be checked from the
+// link alone, so it is recorded in `.neurolink/shares.json` and its link
+// carries only the record's random id. The allowance is claimed under one lock
+// from the check through the increment, so parallel requests cannot both take
+// the last download.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::{ShareError, ShareTarget};
+
+pub const SHARES_FILE: &str = "shares.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareRecord {
+    pub target: ShareTarget,
+    /// Seconds since the epoch.
+    pub expires: u64,
+    /// Argon2 PHC string of the share's password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u64>,
+    #[serde(default)]
+    pub downloads: u64,
+    /// Delete the shared files once the last allowed download is sent.
+    #[serde(default)]
+    pub delete_files: bool,
+}
+
+/// A download let through by a limited share.
+#[derive(Debug, Clone)]
+pub struct Claim {
+    pub id: String,
+    /// Whether the download took one of the share's allowed downloads.
+    pub counted: bool,
+    /// Whether this was the last allowed download of a share that deletes its files.
+    pub delete_files: bool,
+}
+
+#[derive(Debug)]
+pub struct ShareStore {
+    path: PathBuf,
+    records: Mutex<HashMap<String, ShareRecord>>,
+}
+
+impl ShareStore {
+    /// Reads the records kept in `state_dir`, dropping those that have expired.
+    pub async fn load(state_dir: &Path, now: u64) -> io::Result<Self> {
+        let path = state_dir.join(SHARES_FILE);
+        let mut records: HashMap<String, ShareRecord> = match fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable share records: {}", e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        records.retain(|_, record| record.expires > now);
+        Ok(Self { path, records: Mutex::new(records) })
+    }
+
+    /// Records a share, hashing its password, and returns the id its link carries.
+    pub async fn create(&self, mut record: ShareRecord, password: Option<&str>, now: u64) -> io::Result<String> {
+        if let Some(password) = password {
+            let password = password.to_string();
+            let hash = tokio::task::spawn_blocking(move || {
+                let salt = SaltString::generate(&mut OsRng);
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+            })
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|e| io::Error::other(e.to_string()))?;
+            record.password_hash = Some(hash);
+        }
+
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let id = hex::encode(id);
+
+        let mut records = self.records.lock().await;
+        records.retain(|_, record| record.expires > now);
+        records.insert(id.clone(), record);
+        if let Err(e) = self.persist(&records).await {
+            records.remove(&id);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Lets a download of `target` through share `id`, checking its expiry
+    /// and password, and taking one of its allowed downloads if `count` is set.
+    pub async fn claim(
+        &self,
+        id: &str,
+        target: &ShareTarget,
+        password: Option<&str>,
+        count: bool,
+        now: u64,
+    ) -> Result<Claim, ShareError> {
+        let record = self.records.lock().await.get(id).cloned().ok_or(ShareError::Revoked)?;
+        if record.target != *target {
+            return Err(ShareError::Invalid);
+        }
+        if now >= record.expires {
+            return Err(ShareError::Expired);
+        }
+        if let Some(hash) = record.password_hash {
+            let password = password.ok_or(ShareError::PasswordRequired)?.to_string();
+            // Deliberately slow, so it runs off the async workers and outside the lock
+            let matches = tokio::task::spawn_blocking(move || {
+                PasswordHash::new(&hash)
+                    .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            })
+            .await
+            .unwrap_or(false);
+            if !matches {
+                return Err(ShareError::WrongPassword);
+            }
+        }
+
+        let mut claim = Claim { id: id.to_string(), counted: false, delete_files: false };
+        if !count {
+            return Ok(claim);
+        }
+        let mut records = self.records.lock().await;
+        let record = records.get_mut(id).ok_or(ShareError::Revoked)?;
+        let Some(max) = record.max_downloads else {
+            return Ok(claim);
+        };
+        if record.downloads >= max {
+            return Err(ShareError::Revoked);
+        }
+        record.downloads += 1;
+        claim.counted = true;
+        claim.delete_files = record.delete_files && record.downloads == max;
+        if let Err(e) = self.persist(&records).await {
+            warn!("Failed to save share records: {}", e);
+        }
+        Ok(claim)
+    }
+
+    /// Gives back the download a claim took, when nothing was sent for it.
+    pub async fn release(&self, claim: &Claim) {
+        if !claim.counted {
+            return;
+        }
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.get_mut(&claim.id) {
+            record.downloads = record.downloads.saturating_sub(1);
+            if let Err(e) = self.persist(&records).await {
+                warn!("Failed to save share records: {}", e);
+            }
+        }
+    }
+
+    /// Writes the records readable only by this user, replacing the file whole.
+    async fn persist(&self, records: &HashMap<String, ShareRecord>) -> io::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&tmp).await?;
+        file.write_all(&serde_json::to_vec(records)?).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, &self.path).await
+    }
+}
@@ -0,0 +1,147 @@
+use super::*;
+use tempfile::TempDir;
+
+fn signer() -> ShareSigner {
+    ShareSigner::new([7u8; SECRET_LEN])
+}
+
+#[test]
+fn test_signed_link_verifies_until_it_expires() {
+    let signer = signer();
+    let target = ShareTarget::File("photos/día 1.jpg".to_string());
+    let sig = signer.sign(&target, 1_000);
+
+    assert_eq!(signer.verify(&target, Some(1_000), Some(&sig), 999), Ok(()));
+    assert_eq!(signer.verify(&target, Some(1_000), Some(&sig), 1_000), Err(ShareError::Expired));
+    assert_eq!(
+        signer.link(&target, 1_000),
+        format!("/shared/photos/d%C3%ADa%201.jpg?expires=1000&sig={}", sig)
+    );
+}
+
+#[test]
+fn test_link_grants_only_its_own_target_and_expiry() {
+    let signer = signer();
+    let target = ShareTarget::Batch("batch_a".to_string());
+    let sig = signer.sign(&target, 1_000);
+
+    // Moving the expiry, the target or its kind breaks the signature
+    assert_eq!(signer.verify(&target, Some(2_000), Some(&sig), 0), Err(ShareError::Invalid));
+    let other = ShareTarget::Batch("batch_b".to_string());
+    assert_eq!(signer.verify(&other, Some(1_000), Some(&sig), 0), Err(ShareError::Invalid));
+    let file = ShareTarget::File("batch_a".to_string());
+    assert_eq!(signer.verify(&file, Some(1_000), Some(&sig), 0), Err(ShareError::Invalid));
+
+    assert_eq!(signer.verify(&target, Some(1_000), Some("zz"), 0), Err(ShareError::Invalid));
+    assert_eq!(signer.verify(&target, None, Some(&sig), 0), Err(ShareError::Missing));
+    assert_eq!(signer.verify(&target, Some(1_000), None, 0), Err(ShareError::Missing));
+
+    let stranger = ShareSigner::new([8u8; SECRET_LEN]);
+    assert_eq!(stranger.verify(&target, Some(1_000), Some(&sig), 0), Err(ShareError::Invalid));
+}
+
+#[tokio::test]
+async fn test_secret_is_created_once_and_reused() {
+    let dir = TempDir::new().unwrap();
+    let target = ShareTarget::File("a.txt".to_string());
+    let first = ShareSigner::load_or_create(dir.path()).await.unwrap();
+    let second = ShareSigner::load_or_create(dir.path()).await.unwrap();
+    assert_eq!(first.sign(&target, 1), second.sign(&target, 1));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.path().join(SECRET_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    std::fs::write(dir.path().join(SECRET_FILE), b"short").unwrap();
+    assert!(ShareSigner::load_or_create(dir.path()).await.is_err());
+}
+
+fn record(target: &ShareTarget, max_downloads: Option<u64>) -> ShareRecord {
+    ShareRecord {
+        target: target.clone(),
+        expires: 1_000,
+        password_hash: None,
+        max_downloads,
+        downloads: 0,
+        delete_files: true,
+    }
+}
+
+#[tokio::test]
+async fn test_parallel_claims_never_exceed_the_limit() {
+    let dir = TempDir::new().unwrap();
+    let store = std::sync::Arc::new(ShareStore::load(dir.path(), 0).await.unwrap());
+    let target = ShareTarget::File("a.txt".to_string());
+    let id = store.create(record(&target, Some(3)), None, 0).await.unwrap();
+
+    let claims = (0..16).map(|_| {
+        let (store, target, id) = (store.clone(), target.clone(), id.clone());
+        tokio::spawn(async move { store.claim(&id, &target, None, true, 1).await })
+    });
+    let mut granted = Vec::new();
+    for claim in claims {
+        match claim.await.unwrap() {
+            Ok(claim) => granted.push(claim),
+            Err(err) => assert_eq!(err, ShareError::Revoked),
+        }
+    }
+    assert_eq!(granted.len(), 3);
+    // Only the download that used up the share deletes its files
+    assert_eq!(granted.iter().filter(|claim| claim.delete_files).count(), 1);
+
+    // A download given back can be taken again
+    store.release(&granted[0]).await;
+    assert!(store.claim(&id, &target, None, true, 1).await.is_ok());
+    assert_eq!(store.claim(&id, &target, None, true, 1).await.unwrap_err(), ShareError::Revoked);
+    // Uncounted requests still need a live share
+    assert!(store.claim(&id, &target, None, false, 1).await.is_ok());
+    assert_eq!(store.claim(&id, &target, None, false, 1_000).await.unwrap_err(), ShareError::Expired);
+}
+
+#[tokio::test]
+async fn test_password_is_hashed_and_checked() {
+    let dir = TempDir::new().unwrap();
+    let store = ShareStore::load(dir.path(), 0).await.unwrap();
+    let target = ShareTarget::Batch("batch_a".to_string());
+    let id = store.create(record(&target, None), Some("hunter2"), 0).await.unwrap();
+
+    let stored = std::fs::read_to_string(dir.path().join(store::SHARES_FILE)).unwrap();
+    assert!(stored.contains("$argon2") && !stored.contains("hunter2"));
+
+    assert_eq!(store.claim(&id, &target, None, true, 1).await.unwrap_err(), ShareError::PasswordRequired);
+    assert_eq!(store.claim(&id, &target, Some("hunter3"), true, 1).await.unwrap_err(), ShareError::WrongPassword);
+    assert!(store.claim(&id, &target, Some("hunter2"), true, 1).await.is_ok());
+
+    let other = ShareTarget::Batch("batch_b".to_string());
+    assert_eq!(store.claim(&id, &other, Some("hunter2"), true, 1).await.unwrap_err(), ShareError::Invalid);
+    assert_eq!(store.claim("unknown", &target, Some("hunter2"), true, 1).await.unwrap_err(), ShareError::Revoked);
+}
+
+#[tokio::test]
+async fn test_records_survive_a_restart_until_they_expire() {
+    let dir = TempDir::new().unwrap();
+    let target = ShareTarget::File("a.txt".to_string());
+    let id = {
+        let store = ShareStore::load(dir.path(), 0).await.unwrap();
+        let id = store.create(record(&target, Some(2)), None, 0).await.unwrap();
+        store.claim(&id, &target, None, true, 1).await.unwrap();
+        id
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.path().join(store::SHARES_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let store = ShareStore::load(dir.path(), 1).await.unwrap();
+    assert!(store.claim(&id, &target, None, true, 1).await.is_ok());
+    assert_eq!(store.claim(&id, &target, None, true, 1).await.unwrap_err(), ShareError::Revoked);
+
+    let store = ShareStore::load(dir.path(), 1_000).await.unwrap();
+    assert_eq!(store.claim(&id, &target, None, false, 1).await.unwrap_err(), ShareError::Revoked);
+}
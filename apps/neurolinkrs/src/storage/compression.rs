@@ -0,0 +1,131 @@
+// zstd compression at rest.
+//
+// A file compressed at rest starts with a zstd skippable frame that tags it as
+// ours and records its original size, followed by ordinary zstd data. Any zstd
+// decoder can still read it, the tag survives renames and hard links, and an
+// uploaded file that merely happens to be a `.zst` archive is left alone.
+
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use zstd::stream::raw::{Decoder, Operation};
+
+/// One of the sixteen magic numbers zstd reserves for skippable frames.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const TAG: &[u8; 4] = b"NLZ1";
+/// Skippable frame: magic, payload length, then our tag and the original size.
+pub const HEADER_LEN: usize = 4 + 4 + TAG.len() + 8;
+
+const LEVEL: i32 = 3;
+/// Leading bytes compressed to judge a file before committing to all of it.
+pub const SAMPLE_SIZE: usize = 256 * 1024;
+const DECODE_BUFFER_SIZE: usize = 64 * 1024;
+
+fn header(logical_size: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&((TAG.len() + 8) as u32).to_le_bytes());
+    header[8..12].copy_from_slice(TAG);
+    header[12..].copy_from_slice(&logical_size.to_le_bytes());
+    header
+}
+
+pub fn parse_header(bytes: &[u8]) -> Option<u64> {
+    let expected = header(0);
+    if bytes.len() < HEADER_LEN || bytes[..12] != expected[..12] {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes[12..HEADER_LEN].try_into().ok()?))
+}
+
+/// Whether a sample of the content shrinks enough to compress the whole file.
+pub fn worth_compressing(sample: &[u8]) -> io::Result<bool> {
+    Ok(saves_enough(sample.len() as u64, zstd::bulk::compress(sample, LEVEL)?.len() as u64))
+}
+
+/// Whether `packed` bytes are at least 10% smaller than `raw`.
+pub fn saves_enough(raw: u64, packed: u64) -> bool {
+    packed < raw - raw / 10
+}
+
+/// Writes the tagged, compressed form of `logical_size` bytes read from `source`.
+pub fn encode(logical_size: u64, source: &mut impl Read, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(&header(logical_size))?;
+    let mut encoder = zstd::stream::Encoder::new(out, LEVEL)?;
+    io::copy(source, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Streaming zstd decompression over an [`AsyncRead`].
+///
+/// Corrupt or truncated input surfaces as [`io::ErrorKind::InvalidData`].
+pub struct ZstdDecoder<R> {
+    inner: R,
+    decoder: Decoder<'static>,
+    input: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    /// The last decoder step ended a frame, so input may legitimately stop here.
+    frame_done: bool,
+}
+
+impl<R> ZstdDecoder<R> {
+    pub fn new(inner: R) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            decoder: Decoder::new()?,
+            input: vec![0u8; DECODE_BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+            frame_done: true,
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ZstdDecoder<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // Run even with no pending input: the decoder may still hold output
+            let status = this
+                .decoder
+                .run_on_buffers(&this.input[this.pos..this.len], buf.initialize_unfilled())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            this.pos += status.bytes_read;
+            if status.bytes_read > 0 || status.bytes_written > 0 {
+                this.frame_done = status.remaining == 0;
+            }
+            if status.bytes_written > 0 {
+                buf.advance(status.bytes_written);
+                return Poll::Ready(Ok(()));
+            }
+            if this.pos < this.len {
+                continue;
+            }
+
+            if this.eof {
+                if !this.frame_done {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated zstd frame",
+                    )));
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut read = ReadBuf::new(&mut this.input);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            this.len = read.filled().len();
+            this.pos = 0;
+            this.eof = this.len == 0;
+        }
+    }
+}
@@ -0,0 +1,446 @@
+// Authenticated encryption for stored files.
+//
+// An encrypted file is a fixed header followed by sealed segments of at most
+// 64 KiB of plaintext, each sealed with XChaCha20-Poly1305 under a random
+// nonce. The plaintext is divided into frames (a transfer's chunks) and every
+// frame into segments, so each chunk can be written at its own offset, in any
+// order, and rewritten without reusing a nonce.
+//
+// A segment's associated data names the file, the segment's position and
+// whether it is the last one, so segments cannot be reordered, moved between
+// files or cut off the end without the read failing.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use anyhow::{bail, Context as _, Result};
+
+const MAGIC: &[u8; 8] = b"NLENC01\0";
+/// Magic, file id, plaintext size and frame size.
+pub const HEADER_LEN: usize = MAGIC.len() + 16 + 8 + 8;
+pub const SEGMENT_SIZE: u64 = 64 * 1024;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Bytes each sealed segment adds to its plaintext.
+const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+const KEY_LEN: usize = 32;
+
+/// Key for encrypting stored files.
+#[derive(Clone)]
+pub struct StorageKey(XChaCha20Poly1305);
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+impl StorageKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != KEY_LEN {
+            bail!("encryption key must be {} bytes, got {}", KEY_LEN, bytes.len());
+        }
+        Ok(Self(XChaCha20Poly1305::new_from_slice(bytes)?))
+    }
+
+    /// Parses a key written as 64 hex characters.
+    pub fn from_hex(text: &str) -> Result<Self> {
+        let bytes = hex::decode(text.trim()).context("encryption key is not valid hex")?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Reads a key file holding either 64 hex characters or 32 raw bytes.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        if content.len() == KEY_LEN {
+            return Self::from_bytes(&content);
+        }
+        let text = std::str::from_utf8(&content).context("key file is neither hex nor 32 raw bytes")?;
+        Self::from_hex(text)
+    }
+
+    fn seal(&self, header: &Header, index: u64, last: bool, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = header.aad(index, last);
+        let sealed = self
+            .0
+            .encrypt(&nonce, Payload { msg: plain, aad: &aad })
+            .map_err(|_| io::Error::other("failed to encrypt segment"))?;
+        let mut record = Vec::with_capacity(NONCE_LEN + sealed.len());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+
+    fn open(&self, header: &Header, index: u64, record: &[u8]) -> io::Result<Vec<u8>> {
+        let last = index + 1 == header.segment_count();
+        let aad = header.aad(index, last);
+        let (nonce, sealed) = record.split_at(NONCE_LEN);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad: &aad })
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("encrypted segment {} failed authentication", index),
+                )
+            })
+    }
+}
+
+/// Describes an encrypted file and where each of its segments lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    file_id: [u8; 16],
+    pub plain_size: u64,
+    pub frame_size: u64,
+}
+
+impl Header {
+    fn new(plain_size: u64, frame_size: u64) -> Self {
+        let mut file_id = [0u8; 16];
+        OsRng.fill_bytes(&mut file_id);
+        Self { file_id, plain_size, frame_size: frame_size.max(1) }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let field = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let header = Self {
+            file_id: bytes[8..24].try_into().ok()?,
+            plain_size: field(24),
+            frame_size: field(32),
+        };
+        (header.frame_size > 0).then_some(header)
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..8].copy_from_slice(MAGIC);
+        bytes[8..24].copy_from_slice(&self.file_id);
+        bytes[24..32].copy_from_slice(&self.plain_size.to_le_bytes());
+        bytes[32..].copy_from_slice(&self.frame_size.to_le_bytes());
+        bytes
+    }
+
+    fn aad(&self, index: u64, last: bool) -> [u8; 25] {
+        let mut aad = [0u8; 25];
+        aad[..16].copy_from_slice(&self.file_id);
+        aad[16..24].copy_from_slice(&index.to_le_bytes());
+        aad[24] = last as u8;
+        aad
+    }
+
+    fn segments_per_frame(&self) -> u64 {
+        self.frame_size.div_ceil(SEGMENT_SIZE)
+    }
+
+    /// Number of segments; an empty file still has one, so it cannot be truncated unnoticed.
+    pub fn segment_count(&self) -> u64 {
+        let full_frames = self.plain_size / self.frame_size;
+        let tail = (self.plain_size % self.frame_size).div_ceil(SEGMENT_SIZE);
+        (full_frames * self.segments_per_frame() + tail).max(1)
+    }
+
+    fn segment_len(&self, index: u64) -> u64 {
+        let per_frame = self.segments_per_frame();
+        let frame_start = index / per_frame * self.frame_size;
+        let frame_len = self.frame_size.min(self.plain_size.saturating_sub(frame_start));
+        SEGMENT_SIZE.min(frame_len.saturating_sub(index % per_frame * SEGMENT_SIZE))
+    }
+
+    /// Where segment `index` starts in the file.
+    pub fn segment_offset(&self, index: u64) -> u64 {
+        let per_frame = self.segments_per_frame();
+        let frame_len = self.frame_size + per_frame * OVERHEAD;
+        HEADER_LEN as u64 + index / per_frame * frame_len + index % per_frame * (SEGMENT_SIZE + OVERHEAD)
+    }
+
+    /// The segment holding plaintext byte `offset`, and the plaintext offset
+    /// that segment starts at.
+    pub fn segment_at(&self, offset: u64) -> (u64, u64) {
+        let frame = offset / self.frame_size;
+        let within = offset % self.frame_size / SEGMENT_SIZE;
+        (frame * self.segments_per_frame() + within, frame * self.frame_size + within * SEGMENT_SIZE)
+    }
+
+    pub fn physical_size(&self) -> u64 {
+        // Saturating, since the sizes may come from a damaged header
+        (HEADER_LEN as u64)
+            .saturating_add(self.plain_size)
+            .saturating_add(self.segment_count().saturating_mul(OVERHEAD))
+    }
+}
+
+/// Size on disk of an encrypted file holding `plain_size` bytes in frames of `frame_size`.
+pub fn physical_size(plain_size: u64, frame_size: u64) -> u64 {
+    Header { file_id: [0; 16], plain_size, frame_size: frame_size.max(1) }.physical_size()
+}
+
+/// Creates an encrypted file of `plain_size` bytes whose frames are filled in
+/// later with [`FrameWriter`]. Unwritten segments fail authentication on read.
+pub async fn create(path: &Path, key: &StorageKey, plain_size: u64, frame_size: u64) -> io::Result<()> {
+    let header = Header::new(plain_size, frame_size);
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(&header.to_bytes()).await?;
+    if plain_size == 0 {
+        file.write_all(&key.seal(&header, 0, true, &[])?).await?;
+    }
+    file.set_len(header.physical_size()).await?;
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes one frame of an encrypted file created by [`create`].
+pub struct FrameWriter {
+    file: tokio::fs::File,
+    key: StorageKey,
+    header: Header,
+    next: u64,
+    end: u64,
+    pending: Vec<u8>,
+}
+
+impl FrameWriter {
+    pub async fn open(path: &Path, key: &StorageKey, frame: u64) -> io::Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new().read(true).write(true).open(path).await?;
+        let mut bytes = [0u8; HEADER_LEN];
+        file.read_exact(&mut bytes).await?;
+        let header = Header::parse(&bytes).ok_or_else(|| invalid("data file is not encrypted"))?;
+
+        let first = frame * header.segments_per_frame();
+        let end = (first + header.segments_per_frame()).min(header.segment_count());
+        if first >= end {
+            return Err(invalid("frame is past the end of the file"));
+        }
+        file.seek(SeekFrom::Start(header.segment_offset(first))).await?;
+        Ok(Self { file, key: key.clone(), header, next: first, end, pending: Vec::new() })
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.next >= self.end {
+                return Err(invalid("more data than the frame holds"));
+            }
+            let segment_len = self.header.segment_len(self.next) as usize;
+            let take = (segment_len - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == segment_len {
+                let last = self.next + 1 == self.header.segment_count();
+                let record = self.key.seal(&self.header, self.next, last, &self.pending)?;
+                self.file.write_all(&record).await?;
+                self.pending.clear();
+                self.next += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(self) -> io::Result<()> {
+        if self.next != self.end {
+            return Err(invalid("frame is incomplete"));
+        }
+        self.file.sync_data().await
+    }
+}
+
+/// Encrypts a stream of unknown length into a new file.
+pub struct Encryptor {
+    file: std::fs::File,
+    key: StorageKey,
+    header: Header,
+    next: u64,
+    sealed: u64,
+    pending: Vec<u8>,
+}
+
+impl Encryptor {
+    pub fn create(path: &Path, key: &StorageKey, frame_size: u64) -> io::Result<Self> {
+        let header = Header::new(0, frame_size);
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&header.to_bytes())?;
+        Ok(Self { file, key: key.clone(), header, next: 0, sealed: 0, pending: Vec::new() })
+    }
+
+    /// Plaintext bytes the pending segment holds when full.
+    fn capacity(&self) -> usize {
+        let in_frame = self.sealed % self.header.frame_size;
+        SEGMENT_SIZE.min(self.header.frame_size - in_frame) as usize
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let record = self.key.seal(&self.header, self.next, last, &self.pending)?;
+        self.file.write_all(&record)?;
+        self.sealed += self.pending.len() as u64;
+        self.next += 1;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Seals the final segment, records the size and returns the file's size on disk.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.header.plain_size = self.sealed + self.pending.len() as u64;
+        self.seal(true)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.header.to_bytes())?;
+        self.file.sync_all()?;
+        Ok(self.header.physical_size())
+    }
+}
+
+impl Write for Encryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // A full segment is only sealed once more data proves it is not the last
+        if self.pending.len() == self.capacity() {
+            self.seal(false)?;
+        }
+        let take = (self.capacity() - self.pending.len()).min(buf.len());
+        self.pending.extend_from_slice(&buf[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tracks the next segment while decrypting a file front to back.
+struct Segments {
+    key: StorageKey,
+    header: Header,
+    next: u64,
+}
+
+impl Segments {
+    /// Size of the next sealed record, or `None` after the last one.
+    fn next_record_len(&self) -> Option<usize> {
+        (self.next < self.header.segment_count())
+            .then(|| (self.header.segment_len(self.next) + OVERHEAD) as usize)
+    }
+
+    fn open(&mut self, record: &[u8]) -> io::Result<Vec<u8>> {
+        let plain = self.key.open(&self.header, self.next, record)?;
+        self.next += 1;
+        Ok(plain)
+    }
+}
+
+/// Decrypts a file read from just past its header.
+pub struct Decryptor<R> {
+    inner: R,
+    segments: Segments,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> Decryptor<R> {
+    pub fn new(inner: R, key: &StorageKey, header: Header) -> Self {
+        Self {
+            inner,
+            segments: Segments { key: key.clone(), header, next: 0 },
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.plain.len() {
+                let n = buf.len().min(self.plain.len() - self.pos);
+                buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            let Some(len) = self.segments.next_record_len() else {
+                return Ok(0);
+            };
+            let mut record = vec![0u8; len];
+            self.inner.read_exact(&mut record).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid("encrypted file is truncated"),
+                _ => e,
+            })?;
+            self.plain = self.segments.open(&record)?;
+            self.pos = 0;
+        }
+    }
+}
+
+/// Async counterpart of [`Decryptor`].
+pub struct AsyncDecryptor<R> {
+    inner: R,
+    segments: Segments,
+    record: Vec<u8>,
+    filled: usize,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> AsyncDecryptor<R> {
+    pub fn new(inner: R, key: &StorageKey, header: Header) -> Self {
+        Self::from_segment(inner, key, header, 0)
+    }
+
+    /// Decrypts from segment `index` on, read from where that segment starts.
+    pub fn from_segment(inner: R, key: &StorageKey, header: Header, index: u64) -> Self {
+        Self {
+            inner,
+            segments: Segments { key: key.clone(), header, next: index },
+            record: Vec::new(),
+            filled: 0,
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncDecryptor<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if this.pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.pos);
+                buf.put_slice(&this.plain[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let Some(len) = this.segments.next_record_len() else {
+                return Poll::Ready(Ok(()));
+            };
+            if this.record.len() != len {
+                this.record.resize(len, 0);
+                this.filled = 0;
+            }
+            while this.filled < len {
+                let mut read = ReadBuf::new(&mut this.record[this.filled..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                if read.filled().is_empty() {
+                    return Poll::Ready(Err(invalid("encrypted file is truncated")));
+                }
+                this.filled += read.filled().len();
+            }
+            this.plain = this.segments.open(&this.record)?;
+            this.pos = 0;
+            this.filled = 0;
+        }
+    }
+}
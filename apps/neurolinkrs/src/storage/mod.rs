@@ -0,0 +1,363 @@
+// Stored-file encodings.
+//
+// A stored file may be compressed (see `compression`) and, around that,
+// encrypted (see `crypto`), so compression always sees plaintext. Every
+// reader goes through `open`/`inspect`, which peel whatever layers a file
+// has; the daemon's current settings only decide how new files are written.
+// Layers are recognised by their headers, so content that itself starts with
+// one is always stored compressed, behind a genuine compression header.
+
+pub mod compression;
+pub mod crypto;
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use anyhow::Result;
+
+pub use compression::ZstdDecoder;
+pub use crypto::StorageKey;
+
+/// What a stored file holds, as opposed to what it takes on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredInfo {
+    /// Size of the content clients see.
+    pub logical_size: u64,
+    /// Bytes the file takes on disk.
+    pub physical_size: u64,
+    pub compressed: bool,
+    pub encrypted: bool,
+}
+
+pub type StoredReader = Box<dyn AsyncRead + Send + Unpin>;
+
+fn require_key(key: Option<&StorageKey>) -> io::Result<&StorageKey> {
+    key.ok_or_else(|| io::Error::other("file is encrypted but no encryption key is configured"))
+}
+
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+async fn read_up_to_async(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Opens `path` for reading with any encryption removed, returning the
+/// encryption header if there was one.
+fn open_plain(path: &Path, key: Option<&StorageKey>) -> io::Result<(Box<dyn Read + Send>, Option<crypto::Header>)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut head = [0u8; crypto::HEADER_LEN];
+    let n = read_up_to(&mut file, &mut head)?;
+    match crypto::Header::parse(&head[..n]) {
+        Some(header) => Ok((Box::new(crypto::Decryptor::new(file, require_key(key)?, header)), Some(header))),
+        None => {
+            file.seek(SeekFrom::Start(0))?;
+            Ok((Box::new(file), None))
+        }
+    }
+}
+
+/// Reads just enough of `path` to tell how it is stored.
+pub fn inspect(path: &Path, key: Option<&StorageKey>) -> io::Result<StoredInfo> {
+    let physical_size = std::fs::metadata(path)?.len();
+    let (mut plain, header) = open_plain(path, key)?;
+    let mut prefix = [0u8; compression::HEADER_LEN];
+    let n = read_up_to(&mut plain, &mut prefix)?;
+    let plain_size = header.map_or(physical_size, |h| h.plain_size);
+    let logical_size = compression::parse_header(&prefix[..n]);
+
+    Ok(StoredInfo {
+        logical_size: logical_size.unwrap_or(plain_size),
+        physical_size,
+        compressed: logical_size.is_some(),
+        encrypted: header.is_some(),
+    })
+}
+
+pub async fn inspect_async(path: &Path, key: Option<&StorageKey>) -> io::Result<StoredInfo> {
+    let path = path.to_path_buf();
+    let key = key.cloned();
+    tokio::task::spawn_blocking(move || inspect(&path, key.as_ref())).await?
+}
+
+/// Opens a stored file for reading its content.
+pub async fn open(path: &Path, key: Option<&StorageKey>) -> io::Result<(StoredReader, StoredInfo)> {
+    open_layers(path, key, true).await
+}
+
+/// Opens a stored file decrypted but, if compressed at rest, still compressed.
+pub async fn open_encoded(path: &Path, key: Option<&StorageKey>) -> io::Result<(StoredReader, StoredInfo)> {
+    open_layers(path, key, false).await
+}
+
+/// Opens a stored file for reading its content from byte `start` on.
+///
+/// An encrypted file is decrypted from the segment holding `start`; a
+/// compressed one has to be decoded up to it.
+pub async fn open_from(path: &Path, key: Option<&StorageKey>, start: u64) -> io::Result<(StoredReader, StoredInfo)> {
+    let (mut reader, info) = open(path, key).await?;
+    let mut skip = start;
+    if info.encrypted && !info.compressed && start >= crypto::SEGMENT_SIZE {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut head = [0u8; crypto::HEADER_LEN];
+        let n = read_up_to_async(&mut file, &mut head).await?;
+        let header = crypto::Header::parse(&head[..n])
+            .ok_or_else(|| io::Error::other("encrypted file was replaced while opening it"))?;
+        let (index, offset) = header.segment_at(start);
+        file.seek(SeekFrom::Start(header.segment_offset(index))).await?;
+        reader = Box::new(crypto::AsyncDecryptor::from_segment(file, require_key(key)?, header, index));
+        skip = start - offset;
+    }
+    let skipped = tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+    if skipped < skip {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ends before the requested start"));
+    }
+    Ok((reader, info))
+}
+
+async fn open_layers(path: &Path, key: Option<&StorageKey>, decompress: bool) -> io::Result<(StoredReader, StoredInfo)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let physical_size = file.metadata().await?.len();
+    let mut head = [0u8; crypto::HEADER_LEN];
+    let n = read_up_to_async(&mut file, &mut head).await?;
+
+    let header = crypto::Header::parse(&head[..n]);
+    let mut plain: StoredReader = match header {
+        Some(header) => Box::new(crypto::AsyncDecryptor::new(file, require_key(key)?, header)),
+        None => {
+            file.seek(SeekFrom::Start(0)).await?;
+            Box::new(file)
+        }
+    };
+
+    // Peek for the compression tag, then hand the peeked bytes back
+    let mut prefix = [0u8; compression::HEADER_LEN];
+    let n = read_up_to_async(&mut plain, &mut prefix).await?;
+    let logical_size = compression::parse_header(&prefix[..n]);
+    let reader: StoredReader = Box::new(AsyncReadExt::chain(Cursor::new(prefix[..n].to_vec()), plain));
+
+    let info = StoredInfo {
+        logical_size: logical_size.unwrap_or(header.map_or(physical_size, |h| h.plain_size)),
+        physical_size,
+        compressed: logical_size.is_some(),
+        encrypted: header.is_some(),
+    };
+    let reader: StoredReader = if decompress && info.compressed {
+        Box::new(ZstdDecoder::new(reader)?)
+    } else {
+        reader
+    };
+    Ok((reader, info))
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Turns a finished data file made by [`create_data_file`] into its stored form.
+///
+/// Content that starts like a compressed or encrypted file is always
+/// compressed, so readers find a real header in front of it; other content
+/// only if `compress` is set and zstd saves at least 10%. The result is
+/// encrypted when `key` is set. Returns whether the file was compressed.
+pub async fn finish_data_file(path: &Path, key: Option<&StorageKey>, compress: bool) -> Result<bool> {
+    let path = path.to_path_buf();
+    let key = key.cloned();
+    tokio::task::spawn_blocking(move || -> Result<bool> {
+        let physical_size = std::fs::metadata(&path)?.len();
+        let (mut source, logical_size) = open_data_plain(&path, key.as_ref())?;
+        let mut head = [0u8; crypto::HEADER_LEN];
+        let n = read_up_to(&mut source, &mut head)?;
+        let ambiguous = compression::parse_header(&head[..n]).is_some() || crypto::Header::parse(&head[..n]).is_some();
+        if !ambiguous && (!compress || logical_size == 0) {
+            return Ok(false);
+        }
+        let source = Read::chain(&head[..n], source);
+        replace_compressed(&path, key.as_ref(), source, logical_size, physical_size, ambiguous)
+    })
+    .await?
+}
+
+/// Replaces `path` with the compressed form of the `logical_size` bytes read
+/// from `source`, encrypted when `key` is set.
+///
+/// Unless `force` is set, a sample is tried first so incompressible media
+/// costs one small compression rather than a pass over the whole file, and
+/// the file is kept as it is when the result does not shrink enough.
+fn replace_compressed(
+    path: &Path,
+    key: Option<&StorageKey>,
+    mut source: impl Read,
+    logical_size: u64,
+    physical_size: u64,
+    force: bool,
+) -> Result<bool> {
+    let mut sample = Vec::with_capacity(compression::SAMPLE_SIZE);
+    (&mut source).take(compression::SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    if !force && !compression::worth_compressing(&sample)? {
+        return Ok(false);
+    }
+
+    let tmp = sibling(path, "zst-tmp");
+    let written = (|| -> Result<u64> {
+        let mut source = Read::chain(sample.as_slice(), source);
+        match key {
+            Some(key) => {
+                let mut out = crypto::Encryptor::create(&tmp, key, crypto::SEGMENT_SIZE)?;
+                compression::encode(logical_size, &mut source, &mut out)?;
+                Ok(out.finish()?)
+            }
+            None => {
+                let mut out = std::fs::File::create(&tmp)?;
+                compression::encode(logical_size, &mut source, &mut out)?;
+                out.sync_all()?;
+                Ok(out.metadata()?.len())
+            }
+        }
+    })();
+
+    match written {
+        Ok(size) if force || compression::saves_enough(physical_size, size) => {
+            std::fs::rename(&tmp, path)?;
+            Ok(true)
+        }
+        result => {
+            let _ = std::fs::remove_file(&tmp);
+            result.map(|_| false)
+        }
+    }
+}
+
+/// Replaces a plaintext file with an encrypted copy in frames of `frame_size`,
+/// keeping its modification time. Returns `false` if it was already encrypted
+/// under `key`.
+pub async fn encrypt_in_place(path: &Path, key: &StorageKey, frame_size: u64) -> Result<bool> {
+    let path = path.to_path_buf();
+    let key = key.clone();
+    tokio::task::spawn_blocking(move || -> Result<bool> {
+        let mut source = std::fs::File::open(&path)?;
+        let mut head = [0u8; crypto::HEADER_LEN];
+        let n = read_up_to(&mut source, &mut head)?;
+        // Plaintext may merely start like an encrypted file; a segment that opens under the key settles it
+        if let Some(header) = crypto::Header::parse(&head[..n]) {
+            if crypto::Decryptor::new(&mut source, &key, header).read(&mut [0u8; 1]).is_ok() {
+                return Ok(false);
+            }
+        }
+        source.seek(SeekFrom::Start(0))?;
+        let modified = source.metadata()?.modified()?;
+
+        let tmp = sibling(&path, "enc-tmp");
+        let written = (|| -> Result<()> {
+            let mut out = crypto::Encryptor::create(&tmp, &key, frame_size)?;
+            io::copy(&mut source, &mut out)?;
+            out.finish()?;
+            std::fs::File::options().write(true).open(&tmp)?.set_modified(modified)?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        std::fs::rename(&tmp, &path)?;
+        Ok(true)
+    })
+    .await?
+}
+
+/// Creates the data file a transfer's chunks are written into.
+pub async fn create_data_file(path: &Path, key: Option<&StorageKey>, size: u64, chunk_size: u64) -> io::Result<()> {
+    match key {
+        Some(key) => crypto::create(path, key, size, chunk_size).await,
+        None => tokio::fs::File::create(path).await?.set_len(size).await,
+    }
+}
+
+/// Size on disk of a data file created by [`create_data_file`].
+pub fn data_file_len(key: Option<&StorageKey>, size: u64, chunk_size: u64) -> u64 {
+    match key {
+        Some(_) => crypto::physical_size(size, chunk_size),
+        None => size,
+    }
+}
+
+/// Opens a data file made by [`create_data_file`] for reading the bytes
+/// written into it. Whether it is encrypted follows from `key`, never from
+/// what the bytes look like.
+pub async fn open_data_file(path: &Path, key: Option<&StorageKey>) -> io::Result<StoredReader> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let Some(key) = key else {
+        return Ok(Box::new(file));
+    };
+    let mut head = [0u8; crypto::HEADER_LEN];
+    let n = read_up_to_async(&mut file, &mut head).await?;
+    let header = crypto::Header::parse(&head[..n])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "data file is not encrypted"))?;
+    Ok(Box::new(crypto::AsyncDecryptor::new(file, key, header)))
+}
+
+/// Blocking [`open_data_file`], also returning the size of the content.
+fn open_data_plain(path: &Path, key: Option<&StorageKey>) -> io::Result<(Box<dyn Read + Send>, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let Some(key) = key else {
+        let len = file.metadata()?.len();
+        return Ok((Box::new(file), len));
+    };
+    let mut head = [0u8; crypto::HEADER_LEN];
+    let n = read_up_to(&mut file, &mut head)?;
+    let header = crypto::Header::parse(&head[..n])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "data file is not encrypted"))?;
+    Ok((Box::new(crypto::Decryptor::new(file, key, header)), header.plain_size))
+}
+
+/// Writes one chunk into a transfer's data file.
+pub enum ChunkWriter {
+    Plain(tokio::fs::File),
+    Sealed(crypto::FrameWriter),
+}
+
+impl ChunkWriter {
+    pub async fn open(path: &Path, key: Option<&StorageKey>, index: u64, chunk_size: u64) -> io::Result<Self> {
+        match key {
+            Some(key) => Ok(Self::Sealed(crypto::FrameWriter::open(path, key, index).await?)),
+            None => {
+                let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+                file.seek(SeekFrom::Start(index * chunk_size)).await?;
+                Ok(Self::Plain(file))
+            }
+        }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => tokio::io::AsyncWriteExt::write_all(file, data).await,
+            Self::Sealed(writer) => writer.write(data).await,
+        }
+    }
+
+    pub async fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.sync_data().await,
+            Self::Sealed(writer) => writer.finish().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,242 @@
+use super::*;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+fn text(len: usize) -> Vec<u8> {
+    b"2026-10-17 INFO request served in 12ms\n".iter().copied().cycle().take(len).collect()
+}
+
+/// Bytes zstd cannot shrink: a chain of SHA-256 digests.
+fn noise(len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + 32);
+    let mut block = Sha256::digest(b"seed");
+    while out.len() < len {
+        out.extend_from_slice(&block);
+        block = Sha256::digest(block);
+    }
+    out.truncate(len);
+    out
+}
+
+fn key() -> StorageKey {
+    StorageKey::from_bytes(&[7u8; 32]).unwrap()
+}
+
+async fn try_read_all(path: &Path, key: Option<&StorageKey>) -> io::Result<Vec<u8>> {
+    let (mut reader, _) = open(path, key).await?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+async fn read_all(path: &Path) -> Vec<u8> {
+    try_read_all(path, None).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_compressible_file_round_trips() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("app.log");
+    let data = text(1_000_000);
+    std::fs::write(&path, &data).unwrap();
+
+    assert!(finish_data_file(&path, None, true).await.unwrap());
+    let info = inspect(&path, None).unwrap();
+    assert!(info.compressed);
+    assert_eq!(info.logical_size, data.len() as u64);
+    assert!(info.physical_size < info.logical_size / 10);
+    assert_eq!(read_all(&path).await, data);
+
+    // Any zstd decoder can read it; the tag frame is skipped
+    assert_eq!(zstd::stream::decode_all(std::fs::File::open(&path).unwrap()).unwrap(), data);
+
+    let entries: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_incompressible_and_empty_files_stay_plain() {
+    let dir = TempDir::new().unwrap();
+    let noisy = dir.path().join("photo.jpg");
+    let empty = dir.path().join("empty");
+    std::fs::write(&noisy, noise(300_000)).unwrap();
+    std::fs::write(&empty, b"").unwrap();
+
+    assert!(!finish_data_file(&noisy, None, true).await.unwrap());
+    assert!(!finish_data_file(&empty, None, true).await.unwrap());
+    assert_eq!(std::fs::read(&noisy).unwrap(), noise(300_000));
+    assert_eq!(inspect(&empty, None).unwrap(), StoredInfo {
+        logical_size: 0,
+        physical_size: 0,
+        compressed: false,
+        encrypted: false,
+    });
+}
+
+#[tokio::test]
+async fn test_uploaded_zstd_archive_is_not_mistaken_for_compressed_at_rest() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("backup.tar.zst");
+    let archive = zstd::bulk::compress(&text(10_000), 3).unwrap();
+    std::fs::write(&path, &archive).unwrap();
+
+    assert!(!inspect(&path, None).unwrap().compressed);
+    assert_eq!(read_all(&path).await, archive);
+}
+
+#[tokio::test]
+async fn test_content_starting_with_a_header_is_stored_behind_a_real_one() {
+    let mut tagged = 0x184D_2A5Eu32.to_le_bytes().to_vec();
+    tagged.extend_from_slice(&12u32.to_le_bytes());
+    tagged.extend_from_slice(b"NLZ1");
+    tagged.extend_from_slice(&3u64.to_le_bytes());
+    let mut sealed = b"NLENC01\0".to_vec();
+    sealed.extend_from_slice(&[0u8; 16]);
+    sealed.extend_from_slice(&3u64.to_le_bytes());
+    sealed.extend_from_slice(&1u64.to_le_bytes());
+
+    let dir = TempDir::new().unwrap();
+    for (i, mut data) in [tagged, sealed].into_iter().enumerate() {
+        data.extend_from_slice(&noise(1000));
+        let path = dir.path().join(format!("plain-{}", i));
+        std::fs::write(&path, &data).unwrap();
+        assert!(finish_data_file(&path, None, false).await.unwrap());
+        assert_eq!(read_all(&path).await, data);
+        assert_eq!(inspect(&path, None).unwrap().logical_size, data.len() as u64);
+
+        let key = key();
+        let path = dir.path().join(format!("sealed-{}", i));
+        std::fs::write(&path, &data).unwrap();
+        encrypt_in_place(&path, &key, crypto::SEGMENT_SIZE).await.unwrap();
+        finish_data_file(&path, Some(&key), false).await.unwrap();
+        assert_eq!(try_read_all(&path, Some(&key)).await.unwrap(), data);
+    }
+
+    // Ordinary content is left as it is unless compression was asked for
+    let path = dir.path().join("plain");
+    std::fs::write(&path, text(10_000)).unwrap();
+    assert!(!finish_data_file(&path, None, false).await.unwrap());
+    assert_eq!(std::fs::read(&path).unwrap(), text(10_000));
+}
+
+#[tokio::test]
+async fn test_chunk_frames_written_out_of_order_decrypt_in_order() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("data.part");
+    let key = key();
+    let data = text(250_000);
+    let frame = 100_000;
+
+    create_data_file(&path, Some(&key), data.len() as u64, frame).await.unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), data_file_len(Some(&key), data.len() as u64, frame));
+
+    // The last frame is short, and the first one is written twice as a retried chunk would be
+    for index in [2u64, 0, 1, 0] {
+        let start = (index * frame) as usize;
+        let end = (start + frame as usize).min(data.len());
+        let mut writer = ChunkWriter::open(&path, Some(&key), index, frame).await.unwrap();
+        for piece in data[start..end].chunks(7_000) {
+            writer.write(piece).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+    }
+
+    let info = inspect(&path, Some(&key)).unwrap();
+    assert!(info.encrypted && !info.compressed);
+    assert_eq!(info.logical_size, data.len() as u64);
+    assert_eq!(try_read_all(&path, Some(&key)).await.unwrap(), data);
+
+    let raw = std::fs::read(&path).unwrap();
+    assert!(!raw.windows(20).any(|w| w == &data[..20]));
+}
+
+#[tokio::test]
+async fn test_tampered_truncated_or_unkeyed_encrypted_file_fails_to_read() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("notes.txt");
+    let key = key();
+    let data = text(200_000);
+    std::fs::write(&path, &data).unwrap();
+    assert!(encrypt_in_place(&path, &key, crypto::SEGMENT_SIZE).await.unwrap());
+    let sealed = std::fs::read(&path).unwrap();
+
+    assert!(inspect(&path, None).is_err());
+    assert!(try_read_all(&path, Some(&StorageKey::from_bytes(&[8u8; 32]).unwrap())).await.is_err());
+
+    let mut flipped = sealed.clone();
+    flipped[sealed.len() / 2] ^= 1;
+    std::fs::write(&path, &flipped).unwrap();
+    assert!(try_read_all(&path, Some(&key)).await.is_err());
+
+    // Dropping the last segment must not pass for a shorter file
+    let last_segment = (data.len() as u64 % crypto::SEGMENT_SIZE + 40) as usize;
+    std::fs::write(&path, &sealed[..sealed.len() - last_segment]).unwrap();
+    assert!(try_read_all(&path, Some(&key)).await.is_err());
+
+    std::fs::write(&path, &sealed).unwrap();
+    assert_eq!(try_read_all(&path, Some(&key)).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_encrypt_in_place_keeps_mtime_and_skips_encrypted_files() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("empty");
+    let key = key();
+    std::fs::write(&path, b"").unwrap();
+    let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+    assert!(encrypt_in_place(&path, &key, crypto::SEGMENT_SIZE).await.unwrap());
+    assert!(!encrypt_in_place(&path, &key, crypto::SEGMENT_SIZE).await.unwrap());
+    assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    assert_eq!(inspect(&path, Some(&key)).unwrap().logical_size, 0);
+    assert_eq!(try_read_all(&path, Some(&key)).await.unwrap(), b"");
+}
+
+#[tokio::test]
+async fn test_compressed_file_is_encrypted_around_the_zstd_stream() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("app.log");
+    let key = key();
+    let data = text(1_000_000);
+    std::fs::write(&path, &data).unwrap();
+
+    encrypt_in_place(&path, &key, crypto::SEGMENT_SIZE).await.unwrap();
+    assert!(finish_data_file(&path, Some(&key), true).await.unwrap());
+    let info = inspect(&path, Some(&key)).unwrap();
+    assert!(info.compressed && info.encrypted);
+    assert_eq!(info.logical_size, data.len() as u64);
+    assert_eq!(try_read_all(&path, Some(&key)).await.unwrap(), data);
+
+    let (mut reader, _) = open_encoded(&path, Some(&key)).await.unwrap();
+    let mut packed = Vec::new();
+    reader.read_to_end(&mut packed).await.unwrap();
+    assert_eq!(zstd::stream::decode_all(packed.as_slice()).unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_reading_from_an_offset_matches_the_tail_of_the_content() {
+    let dir = TempDir::new().unwrap();
+    let key = key();
+    let data = noise(300_000);
+    let encrypted = dir.path().join("clip.bin");
+    std::fs::write(&encrypted, &data).unwrap();
+    // Frames that are not a whole number of segments
+    assert!(encrypt_in_place(&encrypted, &key, 100_000).await.unwrap());
+    let compressed = dir.path().join("app.log");
+    let log = text(300_000);
+    std::fs::write(&compressed, &log).unwrap();
+    encrypt_in_place(&compressed, &key, crypto::SEGMENT_SIZE).await.unwrap();
+    assert!(finish_data_file(&compressed, Some(&key), true).await.unwrap());
+
+    for (path, content) in [(&encrypted, &data), (&compressed, &log)] {
+        for start in [0u64, 1, 65_535, 65_536, 99_999, 100_000, 165_536, 299_999, 300_000] {
+            let (mut reader, _) = open_from(path, Some(&key), start).await.unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).await.unwrap();
+            assert_eq!(tail, content[start as usize..], "{} from {}", path.display(), start);
+        }
+        assert!(open_from(path, Some(&key), 300_001).await.is_err());
+    }
+}
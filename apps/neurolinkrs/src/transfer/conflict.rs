@@ -0,0 +1,126 @@
+// What happens when a completed upload's destination already exists.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use tokio::fs;
+
+use super::TransferError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Store the upload beside it as `name (1).ext`, `name (2).ext`, ...
+    Rename,
+    /// Refuse the upload.
+    Reject,
+    /// Keep the existing file as `name.~1~` and store the upload under the original name.
+    Version,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(Self::Overwrite),
+            "rename" => Ok(Self::Rename),
+            "reject" => Ok(Self::Reject),
+            "version" => Ok(Self::Version),
+            _ => Err(format!(
+                "unknown conflict policy '{}' (expected overwrite, rename, reject or version)",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Overwrite => "overwrite",
+            Self::Rename => "rename",
+            Self::Reject => "reject",
+            Self::Version => "version",
+        })
+    }
+}
+
+/// Where a completed upload is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    pub path: PathBuf,
+    /// Free name to keep the file being replaced under, for [`ConflictPolicy::Version`].
+    pub backup: Option<PathBuf>,
+}
+
+/// Picks the path an upload bound for `target` is stored at under `policy`.
+///
+/// Nothing is moved: for [`ConflictPolicy::Version`] the caller keeps the
+/// existing file with [`keep_backup`] just before replacing it. Callers
+/// serialize this with the final rename so two uploads cannot claim the same
+/// free name.
+pub async fn prepare_destination(target: &Path, policy: ConflictPolicy) -> Result<Destination, TransferError> {
+    let at = |path: PathBuf| Destination { path, backup: None };
+    if !fs::try_exists(target).await? {
+        return Ok(at(target.to_path_buf()));
+    }
+
+    match policy {
+        ConflictPolicy::Overwrite => Ok(at(target.to_path_buf())),
+        ConflictPolicy::Reject => Err(TransferError::FileExists(display_name(target))),
+        ConflictPolicy::Rename => {
+            for n in 1.. {
+                let candidate = numbered(target, n);
+                if !fs::try_exists(&candidate).await? {
+                    return Ok(at(candidate));
+                }
+            }
+            unreachable!("ran out of numbered names")
+        }
+        ConflictPolicy::Version => {
+            for n in 1.. {
+                let backup = versioned(target, n);
+                if !fs::try_exists(&backup).await? {
+                    return Ok(Destination { path: target.to_path_buf(), backup: Some(backup) });
+                }
+            }
+            unreachable!("ran out of version numbers")
+        }
+    }
+}
+
+/// Keeps the file at `target` under `backup` as well, by hard link where the
+/// filesystem allows and by copy otherwise, so `target` stays in place until
+/// the upload replaces it.
+pub async fn keep_backup(target: &Path, backup: &Path) -> std::io::Result<()> {
+    if fs::hard_link(target, backup).await.is_err() {
+        fs::copy(target, backup).await?;
+    }
+    Ok(())
+}
+
+/// `photo.jpg` becomes `photo (n).jpg`; dotfiles and extensionless names get the suffix at the end.
+fn numbered(target: &Path, n: usize) -> PathBuf {
+    let name = display_name(target);
+    let renamed = match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], n, &name[dot..]),
+        _ => format!("{} ({})", name, n),
+    };
+    target.with_file_name(renamed)
+}
+
+/// `photo.jpg` becomes `photo.jpg.~n~`, the numbered backup convention of `cp --backup`.
+fn versioned(target: &Path, n: usize) -> PathBuf {
+    target.with_file_name(format!("{}.~{}~", display_name(target), n))
+}
+
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
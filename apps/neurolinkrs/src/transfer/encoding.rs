@@ -0,0 +1,43 @@
+// Transport encodings for chunk bodies.
+//
+// A chunk may arrive compressed; it is decoded while streaming, so the size
+// check and SHA-256 in `write_chunk` always apply to the original bytes.
+
+use std::io;
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+use tokio_util::either::Either;
+
+use crate::storage::ZstdDecoder;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkEncoding {
+    #[default]
+    Identity,
+    Zstd,
+}
+
+impl FromStr for ChunkEncoding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(Self::Identity),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!("unsupported chunk encoding '{}'", other)),
+        }
+    }
+}
+
+impl ChunkEncoding {
+    /// Wraps `reader` so it yields the decoded chunk bytes.
+    pub fn decode<R>(self, reader: R) -> io::Result<Either<R, ZstdDecoder<R>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Ok(match self {
+            Self::Identity => Either::Left(reader),
+            Self::Zstd => Either::Right(ZstdDecoder::new(reader)?),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+// Durable record of completed uploads.
+//
+// Every completed upload appends one line to `<storage>/.neurolink/uploads.jsonl`
+// and is linked into `.neurolink/batches/<batch_id>/`, so batch listings and
+// archives survive restarts and later uploads of the same name.
+
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use anyhow::Result;
+
+use super::CompletedUpload;
+
+pub const HISTORY_FILE: &str = "uploads.jsonl";
+pub const BATCHES_DIR: &str = "batches";
+
+pub async fn append(state_dir: &Path, upload: &CompletedUpload) -> Result<()> {
+    fs::create_dir_all(state_dir).await?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir.join(HISTORY_FILE))
+        .await?;
+    let mut line = serde_json::to_vec(upload)?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/// Replaces the whole history with `uploads`, for when records are dropped.
+pub async fn rewrite(state_dir: &Path, uploads: &[CompletedUpload]) -> Result<()> {
+    let mut content = Vec::new();
+    for upload in uploads {
+        content.extend(serde_json::to_vec(upload)?);
+        content.push(b'\n');
+    }
+    let tmp = state_dir.join(format!("{}.tmp", HISTORY_FILE));
+    fs::write(&tmp, content).await?;
+    fs::rename(&tmp, state_dir.join(HISTORY_FILE)).await?;
+    Ok(())
+}
+
+/// Loads the upload history, skipping unreadable lines.
+pub async fn load(state_dir: &Path) -> Result<Vec<CompletedUpload>> {
+    let path = state_dir.join(HISTORY_FILE);
+    let content = match fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut uploads = Vec::new();
+    for (line_no, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(upload) => uploads.push(upload),
+            Err(e) => warn!("Skipping unreadable upload record {}:{}: {}",
+                            path.display(), line_no + 1, e),
+        }
+    }
+    Ok(uploads)
+}
@@ -0,0 +1,122 @@
+// Write-ahead journal for in-flight transfers.
+//
+// Each transfer owns a directory under `<storage>/.neurolink/transfers/<id>/`
+// holding its preallocated data file and a `journal.jsonl` file. The first
+// record is the transfer metadata; every accepted chunk appends one record
+// after its bytes have been synced, so replay never references missing data.
+// Completion is journaled before the data file is renamed into place, so a
+// crash part-way through publishing is finished on recovery, not resumed.
+
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use serde::{Serialize, Deserialize};
+use tracing::warn;
+use anyhow::Result;
+
+use super::{ChunkInfo, CompletedUpload, TransferMetadata};
+
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalRecord {
+    Init { metadata: TransferMetadata },
+    Chunk { chunk: ChunkInfo },
+    /// A previously accepted chunk is being overwritten and no longer counts.
+    Discard { index: usize },
+    Failed { reason: String },
+    /// The data file is about to become `upload`; nothing is left to resume.
+    Completed { upload: CompletedUpload },
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    file: fs::File,
+}
+
+/// State rebuilt from a journal on startup.
+#[derive(Debug)]
+pub struct ReplayedTransfer {
+    pub metadata: TransferMetadata,
+    pub chunks: Vec<ChunkInfo>,
+    pub failed: Option<String>,
+    /// Set when publishing began and did not fail.
+    pub completed: Option<CompletedUpload>,
+}
+
+impl Journal {
+    /// Creates a fresh journal in `dir` and durably records the transfer metadata.
+    pub async fn create(dir: &Path, metadata: &TransferMetadata) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let file = fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let mut journal = Self { file };
+        journal
+            .append(&JournalRecord::Init { metadata: metadata.clone() })
+            .await?;
+        Ok(journal)
+    }
+
+    /// Reopens an existing journal for appending.
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let file = fs::OpenOptions::new().append(true).open(&path).await?;
+        Ok(Self { file })
+    }
+
+    pub async fn append(&mut self, record: &JournalRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Replays the journal in `dir`. Returns `None` when no init record survived.
+    ///
+    /// A torn trailing line (crash mid-append) is ignored; later chunk records
+    /// for the same index replace earlier ones.
+    pub async fn replay(dir: &Path) -> Result<Option<ReplayedTransfer>> {
+        let path = dir.join(JOURNAL_FILE);
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut metadata = None;
+        let mut chunks: Vec<ChunkInfo> = Vec::new();
+        let mut failed = None;
+        let mut completed = None;
+
+        for (line_no, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JournalRecord>(line) {
+                Ok(JournalRecord::Init { metadata: m }) => metadata = Some(m),
+                Ok(JournalRecord::Chunk { chunk }) => {
+                    chunks.retain(|c| c.index != chunk.index);
+                    chunks.push(chunk);
+                }
+                Ok(JournalRecord::Discard { index }) => chunks.retain(|c| c.index != index),
+                // A failure after completion means the rename itself failed
+                Ok(JournalRecord::Failed { reason }) => {
+                    failed = Some(reason);
+                    completed = None;
+                }
+                Ok(JournalRecord::Completed { upload }) => completed = Some(upload),
+                Err(e) => {
+                    warn!("Skipping unreadable journal record {}:{}: {}",
+                          path.display(), line_no + 1, e);
+                }
+            }
+        }
+
+        Ok(metadata.map(|metadata| ReplayedTransfer { metadata, chunks, failed, completed }))
+    }
+}
@@ -0,0 +1,106 @@
+// Size limits, storage quota and free-space checks applied at transfer init.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Daemon-wide caps on what a transfer may occupy. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct TransferLimits {
+    /// Largest single file accepted.
+    pub max_file_size: Option<u64>,
+    /// Largest total size of all files sharing one `batch_id`.
+    pub max_batch_size: Option<u64>,
+    /// Total bytes the storage root may hold, counting daemon state and the
+    /// bytes in-flight transfers will write.
+    pub storage_quota: Option<u64>,
+}
+
+/// Bytes of the files under `root`, blobs and batch copies included, with
+/// hard-linked files counted once. `in_flight` is left out: the bytes of open
+/// transfers are reserved separately.
+///
+/// Walks the whole tree, so the manager calls it once and keeps a running count.
+pub fn storage_usage(root: &Path, in_flight: &Path) -> u64 {
+    let files = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|e| e.path() != in_flight)
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok());
+    let mut seen = HashSet::new();
+    files
+        .filter(|meta| identity(meta).is_none_or(|id| seen.insert(id)))
+        .map(|meta| meta.len())
+        .sum()
+}
+
+/// Bytes that the files at `paths` hold on disk and no other name keeps: a
+/// file is counted once however many of `paths` name it, and not at all while
+/// a name outside `paths` shares it. The difference between a count taken
+/// before and after changing those names is what the change took or freed.
+pub fn footprint(paths: &[&Path]) -> u64 {
+    // Length, number of names and how many of them are in `paths`, per file
+    let mut files: HashMap<(u64, u64), (u64, u64, u64)> = HashMap::new();
+    let mut unlinked = 0;
+    for path in paths {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            continue;
+        };
+        if !meta.is_file() {
+            continue;
+        }
+        match identity(&meta) {
+            Some(id) => files.entry(id).or_insert((meta.len(), links(&meta), 0)).2 += 1,
+            None => unlinked += meta.len(),
+        }
+    }
+    unlinked + files.values().filter(|(_, links, named)| named >= links).map(|(len, ..)| len).sum::<u64>()
+}
+
+/// Device and inode of a file, where the platform reports them.
+#[cfg(unix)]
+fn identity(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn links(meta: &std::fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(meta)
+}
+
+#[cfg(not(unix))]
+fn links(_meta: &std::fs::Metadata) -> u64 {
+    1
+}
+
+/// Bytes available to unprivileged writers on the volume holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid out-pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free-space probing is only implemented for unix targets.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
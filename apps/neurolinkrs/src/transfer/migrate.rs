@@ -0,0 +1,152 @@
+// Migration of a plaintext storage directory to encryption at rest.
+//
+// Shared files, batch copies and blobs may be hard links to one another, so
+// each linked group is encrypted once. The other names in the daemon's state
+// are linked to the result again; shared files get their own copy of it.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use anyhow::Result;
+use walkdir::WalkDir;
+
+use super::history::BATCHES_DIR;
+use super::journal::Journal;
+use super::{DATA_FILE, STATE_DIR};
+use crate::hashing::{self, BLOBS_DIR};
+use crate::storage::{self, crypto, StorageKey};
+
+/// A file to encrypt, with the frame size its writers and readers use.
+struct Target {
+    path: PathBuf,
+    frame_size: u64,
+}
+
+/// Encrypts every stored file under `storage_path` that is still plaintext.
+/// Returns how many files were encrypted.
+///
+/// Must run before the daemon recovers transfers or serves requests.
+pub async fn encrypt_existing(storage_path: &Path, key: &StorageKey) -> Result<usize> {
+    let root = storage_path.to_path_buf();
+    let (mut targets, transfer_dirs) = tokio::task::spawn_blocking(move || collect(&root)).await??;
+    targets.extend(partial_data_files(transfer_dirs).await?);
+
+    // Identities are taken up front: once the rest of a group is copied,
+    // its last name no longer looks linked
+    let identities = targets
+        .iter()
+        .map(|target| linked_identity(&target.path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let state_dir = storage_path.join(STATE_DIR);
+    let mut done: HashMap<(u64, u64), (PathBuf, bool)> = HashMap::new();
+    let mut encrypted = 0;
+    for (target, identity) in targets.into_iter().zip(identities) {
+        if let Some((first, changed)) = identity.and_then(|id| done.get(&id)) {
+            if first.starts_with(&state_dir) && target.path.starts_with(&state_dir) {
+                hashing::link_or_clone(first, &target.path).await?;
+            } else {
+                hashing::clone_or_copy(first, &target.path).await?;
+            }
+            encrypted += usize::from(*changed);
+            continue;
+        }
+
+        let changed = storage::encrypt_in_place(&target.path, key, target.frame_size).await?;
+        encrypted += usize::from(changed);
+        if let Some(id) = identity {
+            done.insert(id, (target.path, changed));
+        }
+    }
+    Ok(encrypted)
+}
+
+/// Walks the storage directory for shared files, batch copies and blobs, and
+/// returns them with the transfer directories whose data files need a journal
+/// to be read.
+fn collect(root: &Path) -> Result<(Vec<Target>, Vec<PathBuf>)> {
+    let mut targets = Vec::new();
+    let mut transfer_dirs = Vec::new();
+
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry?;
+        let Ok(relative) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        let parts: Vec<&str> = relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+
+        if entry.file_type().is_dir() {
+            if let [STATE_DIR, "transfers", _] = parts.as_slice() {
+                transfer_dirs.push(entry.path().to_path_buf());
+            }
+            continue;
+        }
+        if !entry.file_type().is_file() || is_scratch(&parts) {
+            continue;
+        }
+
+        let stored = match parts.as_slice() {
+            [STATE_DIR, BLOBS_DIR, .., name] => *name != "index.json",
+            [STATE_DIR, BATCHES_DIR, ..] => true,
+            [STATE_DIR, ..] => false,
+            _ => true,
+        };
+        if stored {
+            targets.push(Target {
+                path: entry.path().to_path_buf(),
+                frame_size: crypto::SEGMENT_SIZE,
+            });
+        }
+    }
+    Ok((targets, transfer_dirs))
+}
+
+/// Temporary files left beside stored files by an interrupted write.
+fn is_scratch(parts: &[&str]) -> bool {
+    parts
+        .last()
+        .is_some_and(|name| name.starts_with('.') && name.ends_with("-tmp"))
+}
+
+/// Data files of in-flight transfers, framed by their chunk size so chunks can
+/// keep arriving after the migration.
+///
+/// Data files that recovery would discard anyway are left alone.
+async fn partial_data_files(dirs: Vec<PathBuf>) -> Result<Vec<Target>> {
+    let mut targets = Vec::new();
+    for dir in dirs {
+        let Some(replayed) = Journal::replay(&dir).await? else {
+            continue;
+        };
+        let path = dir.join(DATA_FILE);
+        let intact = tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|meta| meta.len() == replayed.metadata.total_size);
+        if replayed.failed.is_none() && intact && replayed.metadata.chunk_size > 0 {
+            targets.push(Target {
+                path,
+                frame_size: replayed.metadata.chunk_size as u64,
+            });
+        }
+    }
+    Ok(targets)
+}
+
+/// Identifies a file that has other hard links to it.
+#[cfg(unix)]
+fn linked_identity(path: &Path) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = std::fs::metadata(path)?;
+    Ok((meta.nlink() > 1).then(|| (meta.dev(), meta.ino())))
+}
+
+#[cfg(not(unix))]
+fn linked_identity(_path: &Path) -> Result<Option<(u64, u64)>> {
+    Ok(None)
+}
@@ -1,17 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use tokio::fs::ReadDir;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{Mutex, MutexGuard, OnceCell, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use sha2::{Sha256, Digest};
-use tracing::{info, debug};
+use tracing::{info, debug, warn};
 use chrono::Utc;
 use anyhow::Result;
-use tempfile::TempDir;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
+use walkdir::WalkDir;
+
+mod conflict;
+mod encoding;
+mod history;
+mod journal;
+mod limits;
+mod migrate;
+
+use journal::{Journal, JournalRecord};
+use crate::hashing::{self, DedupStats, DedupStore};
+use crate::sanitize::{self, FilenameError};
+use crate::share::{Shares, ShareTarget};
+use crate::storage::{self, StorageKey};
+pub use conflict::ConflictPolicy;
+pub use encoding::ChunkEncoding;
+pub use limits::TransferLimits;
+
+/// Hidden directory under the storage root that holds daemon state.
+pub const STATE_DIR: &str = ".neurolink";
+
+/// Preallocated destination file inside each transfer directory.
+const DATA_FILE: &str = "data.part";
+
+/// Buffer used when streaming chunk bodies to disk.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keeps transfer ids unique when several inits land in the same millisecond.
+static TRANSFER_SEQ: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Error, Debug)]
 pub enum TransferError {
@@ -19,14 +50,63 @@ pub enum TransferError {
     TransferNotFound(String),
     #[error("Chunk out of order: expected {expected}, got {got}")]
     ChunkOutOfOrder { expected: usize, got: usize },
-    #[error("Invalid chunk hash")]
-    InvalidChunkHash,
-    #[error("File too large")]
-    FileTooLarge,
+    #[error("Invalid chunk hash for chunk {index}: expected {expected}, got {actual}")]
+    InvalidChunkHash { index: usize, expected: String, actual: String },
+    #[error("Chunk {index} has wrong size: expected {expected} bytes, got {actual}")]
+    ChunkSizeMismatch { index: usize, expected: u64, actual: u64 },
+    #[error("Chunk {index} could not be decoded: {reason}")]
+    ChunkDecode { index: usize, reason: String },
+    #[error("Transfer busy: {0}")]
+    Busy(String),
+    #[error("File hash mismatch: expected {expected}, got {actual}")]
+    FileHashMismatch { expected: String, actual: String },
+    #[error("Transfer failed: {0}")]
+    TransferFailed(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("File already exists: {0}")]
+    FileExists(String),
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    #[error("Invalid filename: {0}")]
+    InvalidFilename(#[from] FilenameError),
+    #[error("File too large: {size} bytes exceeds the {limit} byte limit")]
+    FileTooLarge { size: u64, limit: u64 },
+    #[error("Batch {batch_id} too large: {size} bytes exceeds the {limit} byte limit")]
+    BatchTooLarge { batch_id: String, size: u64, limit: u64 },
+    #[error("Storage quota exceeded: {used} bytes used, {requested} requested, quota is {quota}")]
+    QuotaExceeded { used: u64, requested: u64, quota: u64 },
+    #[error("Insufficient disk space: {required} bytes required, {available} available")]
+    InsufficientStorage { required: u64, available: u64 },
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+impl TransferError {
+    /// Stable machine-readable code reported to clients alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferError::TransferNotFound(_) => "transfer_not_found",
+            TransferError::ChunkOutOfOrder { .. } => "chunk_out_of_order",
+            TransferError::InvalidChunkHash { .. } => "chunk_hash_mismatch",
+            TransferError::ChunkSizeMismatch { .. } => "chunk_size_mismatch",
+            TransferError::ChunkDecode { .. } => "chunk_decode_failed",
+            TransferError::Busy(_) => "transfer_busy",
+            TransferError::FileHashMismatch { .. } => "file_hash_mismatch",
+            TransferError::TransferFailed(_) => "transfer_failed",
+            TransferError::InvalidRequest(_) => "invalid_request",
+            TransferError::InvalidFilename(_) => "invalid_filename",
+            TransferError::FileExists(_) => "file_exists",
+            TransferError::FileNotFound(_) => "file_not_found",
+            TransferError::FileTooLarge { .. } => "file_too_large",
+            TransferError::BatchTooLarge { .. } => "batch_too_large",
+            TransferError::QuotaExceeded { .. } => "quota_exceeded",
+            TransferError::InsufficientStorage { .. } => "insufficient_storage",
+            TransferError::Io(_) => "io_error",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferMetadata {
     pub id: String,
@@ -35,10 +115,65 @@ pub struct TransferMetadata {
     pub chunk_size: usize,
     pub total_chunks: usize,
     pub batch_id: Option<String>,
+    /// SHA-256 the client declared for the whole file, checked at completion.
+    #[serde(default)]
+    pub expected_hash: Option<String>,
+    /// Overrides the daemon's conflict policy for this transfer.
+    #[serde(default)]
+    pub conflict_policy: Option<ConflictPolicy>,
+    /// Path below the storage root the file ended up at, once completed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_name: Option<String>,
     pub created_at: String,
     pub status: TransferStatus,
 }
 
+/// Daemon-level settings for a [`TransferManager`].
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    pub limits: TransferLimits,
+    /// What to do when a completed upload's destination already exists.
+    pub conflict_policy: ConflictPolicy,
+    /// How long a transfer may go without a chunk before it expires.
+    pub idle_timeout: Duration,
+    /// How long a failed transfer stays visible in status before it is forgotten.
+    pub failed_retention: Duration,
+    /// Store new content zstd-compressed when it shrinks enough to be worth it.
+    pub compress_at_rest: bool,
+    /// Encrypt new blobs and chunk data with this key; `None` stores plaintext.
+    pub encryption_key: Option<StorageKey>,
+    /// Serve stored files and batches only through signed share links.
+    pub require_share_links: bool,
+    /// Lets callers on other machines create share links; without it only
+    /// this machine can.
+    pub admin_token: Option<String>,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            limits: TransferLimits::default(),
+            conflict_policy: ConflictPolicy::default(),
+            idle_timeout: Duration::from_secs(60 * 60),
+            failed_retention: Duration::from_secs(15 * 60),
+            compress_at_rest: false,
+            encryption_key: None,
+            require_share_links: false,
+            admin_token: None,
+        }
+    }
+}
+
+/// Optional per-transfer settings supplied at init.
+#[derive(Debug, Clone, Default)]
+pub struct TransferOptions {
+    pub batch_id: Option<String>,
+    /// Stores the file at this path below the storage root instead of at `filename`.
+    pub relative_path: Option<String>,
+    pub conflict_policy: Option<ConflictPolicy>,
+    pub expected_hash: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransferStatus {
     Pending,
@@ -50,36 +185,71 @@ pub enum TransferStatus {
 #[derive(Debug)]
 pub struct Transfer {
     pub metadata: TransferMetadata,
-    pub temp_dir: TempDir,
+    pub dir: PathBuf,
+    pub journal: Journal,
     pub received_chunks: HashMap<usize, ChunkInfo>,
+    /// Chunk indices whose bodies are currently being written.
+    pub writing: HashSet<usize>,
+    /// Set while completion verifies the data file; new chunks are refused.
+    pub completing: bool,
+    /// Last time a client touched the transfer, or when it failed.
+    pub last_activity: Instant,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkInfo {
     pub index: usize,
     pub hash: String,
     pub size: usize,
 }
 
+/// Shared handle to one transfer's state.
+///
+/// The map lock is only held to look up, insert or remove entries; everything
+/// else, including journal appends, runs under the transfer's own lock, and
+/// chunk bodies are written with no lock held at all.
+type TransferHandle = Arc<Mutex<Transfer>>;
+
 #[derive(Debug, Clone)]
 pub struct TransferManager {
-    transfers: Arc<Mutex<HashMap<String, Transfer>>>,
+    transfers: Arc<RwLock<HashMap<String, TransferHandle>>>,
     completed_uploads: Arc<Mutex<Vec<CompletedUpload>>>,
     storage_path: PathBuf,
+    config: TransferConfig,
+    /// Serializes limit checks with registration so concurrent inits cannot overbook.
+    admission: Arc<Mutex<()>>,
+    /// Serializes picking a destination with the rename that claims it.
+    publish: Arc<Mutex<()>>,
+    dedup: Arc<DedupStore>,
+    shares: Arc<OnceCell<Shares>>,
+    /// Bytes of stored files under the root, counted once and then kept
+    /// current as uploads are published and shares deleted.
+    usage: Arc<OnceCell<AtomicU64>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SharedFile {
     pub name: String,
+    /// Size of the content, whether or not it is compressed at rest.
     pub size: u64,
+    /// Bytes the file takes on disk.
+    pub physical_size: u64,
     pub modified_at: String,
+    /// SHA-256 of the content, when it is still what its last upload wrote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_hash: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadedFile {
     pub name: String,
+    /// Where the file was stored, which differs from `name` after a conflict rename.
+    pub stored_name: String,
     pub size: u64,
     pub uploaded_at: String,
+    /// SHA-256 of the content as uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_hash: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,42 +259,169 @@ pub struct UploadBatch {
     pub files: Vec<UploadedFile>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompletedUpload {
     pub batch_id: String,
     pub name: String,
+    pub stored_name: String,
     pub size: u64,
     pub uploaded_at: String,
+    /// Absent from history recorded before hashes were kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_hash: Option<String>,
+    /// Length on disk and modification time of the file as published, which
+    /// tell whether the stored file still holds this upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_len: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_modified: Option<String>,
+}
+
+/// Which chunks of a transfer the server already holds, for resuming clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub transfer_id: String,
+    pub total_chunks: usize,
+    pub chunk_size: usize,
+    pub received: Vec<ChunkInfo>,
+    pub missing: Vec<ChunkRange>,
+}
+
+/// Inclusive range of chunk indices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRange {
+    pub start: usize,
+    pub end: usize,
 }
 
 impl TransferManager {
     pub fn new(storage_path: impl AsRef<Path>) -> Self {
         Self {
-            transfers: Arc::new(Mutex::new(HashMap::new())),
+            transfers: Arc::new(RwLock::new(HashMap::new())),
             completed_uploads: Arc::new(Mutex::new(Vec::new())),
             storage_path: storage_path.as_ref().to_path_buf(),
+            config: TransferConfig::default(),
+            admission: Arc::new(Mutex::new(())),
+            publish: Arc::new(Mutex::new(())),
+            dedup: Arc::new(DedupStore::new(
+                storage_path.as_ref().join(STATE_DIR).join(hashing::BLOBS_DIR),
+            )),
+            shares: Arc::new(OnceCell::new()),
+            usage: Arc::new(OnceCell::new()),
         }
     }
 
+    pub fn with_config(mut self, config: TransferConfig) -> Self {
+        self.dedup = Arc::new(
+            DedupStore::new(self.storage_path.join(STATE_DIR).join(hashing::BLOBS_DIR))
+                .with_key(config.encryption_key.clone()),
+        );
+        self.config = config;
+        self
+    }
+
+    /// Key for files encrypted at rest, if encryption is configured.
+    pub fn storage_key(&self) -> Option<&StorageKey> {
+        self.config.encryption_key.as_ref()
+    }
+
+    /// Whether downloads must come through a signed share link.
+    pub fn requires_share_links(&self) -> bool {
+        self.config.require_share_links
+    }
+
+    /// Token that lets callers on other machines manage shares, if one is set.
+    pub fn admin_token(&self) -> Option<&str> {
+        self.config.admin_token.as_deref()
+    }
+
+    /// Share link signing and records, loaded (or created) on first use.
+    pub async fn shares(&self) -> std::io::Result<&Shares> {
+        let state_dir = self.storage_path.join(STATE_DIR);
+        let now = Utc::now().timestamp().max(0) as u64;
+        self.shares
+            .get_or_try_init(|| async move { Shares::load(&state_dir, now).await })
+            .await
+    }
+
+    /// Registers a new transfer and returns its metadata.
+    ///
+    /// When the declared SHA-256 matches content the server already stores,
+    /// the file is linked from that blob and the returned transfer is already
+    /// `Completed`; no chunks need to be sent.
     pub async fn init_transfer(
         &self,
         filename: String,
         total_size: u64,
         chunk_size: usize,
-        batch_id: Option<String>,
-    ) -> Result<String> {
+        options: TransferOptions,
+    ) -> Result<TransferMetadata> {
         // Validate chunk_size to prevent division by zero
         if chunk_size == 0 {
             return Err(anyhow::anyhow!("chunk_size must be greater than 0"));
         }
 
-        let transfer_id = format!("trans_{}", Utc::now().timestamp_millis());
-        let total_chunks = ((total_size + chunk_size as u64 - 1) / chunk_size as u64) as usize;
+        let filename = match options.relative_path.as_deref() {
+            Some(path) => sanitize::sanitize_relative_path(path),
+            None => sanitize::sanitize_filename(&filename),
+        }
+        .map_err(TransferError::from)?;
+
+        if let Some(batch_id) = options.batch_id.as_deref() {
+            if !is_plain_name(batch_id) {
+                return Err(TransferError::InvalidRequest(format!("invalid batch id '{}'", batch_id)).into());
+            }
+        }
+
+        let expected_hash = match options.expected_hash {
+            Some(hash) if is_sha256_hex(&hash) => Some(hash.to_ascii_lowercase()),
+            Some(_) => {
+                return Err(TransferError::InvalidRequest(
+                    "expected SHA-256 must be 64 hex characters".to_string(),
+                ).into());
+            }
+            None => None,
+        };
+
+        // A reject policy is checked again at completion; this just saves a doomed upload
+        let policy = options.conflict_policy.unwrap_or(self.config.conflict_policy);
+        if policy == ConflictPolicy::Reject && fs::try_exists(self.storage_path.join(&filename)).await? {
+            return Err(TransferError::FileExists(filename).into());
+        }
+
+        let transfer_id = format!(
+            "trans_{}_{}",
+            Utc::now().timestamp_millis(),
+            TRANSFER_SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+        let total_chunks = total_size.div_ceil(chunk_size as u64) as usize;
+
+        let stored_blob = match expected_hash.as_deref() {
+            Some(hash) if self.dedup.verify(hash).await == Some(total_size) => Some(self.dedup.blob_path(hash)),
+            _ => None,
+        };
+
+        // Fail up front rather than halfway through a large copy
+        let _admission = self.admission.lock().await;
+        let planned = match &stored_blob {
+            // At worst a full copy of the blob
+            Some(blob) => fs::metadata(blob).await.map_or(total_size, |meta| meta.len()),
+            None => self.planned_bytes(total_size, chunk_size),
+        };
+        self.check_limits(total_size, planned, options.batch_id.as_deref()).await?;
         
         info!("Initializing transfer: {} for file: {} ({} chunks)", 
               transfer_id, filename, total_chunks);
 
-        let temp_dir = TempDir::new()?;
+        let dir = self.transfers_dir().join(&transfer_id);
+        fs::create_dir_all(&dir).await?;
+
+        if let Some(blob) = &stored_blob {
+            hashing::clone_or_copy(blob, &dir.join(DATA_FILE)).await?;
+        } else {
+            // Chunks are written in place at their offsets, so reserve the full size up front
+            storage::create_data_file(&dir.join(DATA_FILE), self.storage_key(), total_size, chunk_size as u64).await?;
+        }
         
         let metadata = TransferMetadata {
             id: transfer_id.clone(),
@@ -132,52 +429,143 @@ impl TransferManager {
             total_size,
             chunk_size,
             total_chunks,
-            batch_id,
+            batch_id: options.batch_id,
+            expected_hash,
+            conflict_policy: options.conflict_policy,
+            stored_name: None,
             created_at: Utc::now().to_rfc3339(),
             status: TransferStatus::Pending,
         };
 
+        let journal = Journal::create(&dir, &metadata).await?;
+
         let transfer = Transfer {
             metadata,
-            temp_dir,
+            dir,
+            journal,
             received_chunks: HashMap::new(),
+            writing: HashSet::new(),
+            completing: false,
+            last_activity: Instant::now(),
         };
 
-        let mut transfers = self.transfers.lock().await;
-        transfers.insert(transfer_id.clone(), transfer);
+        if let (Some(hash), Some(_)) = (transfer.metadata.expected_hash.clone(), &stored_blob) {
+            // Never registered, so no chunk can reach a data file that is the blob itself
+            info!("Transfer {} matches stored content; completing without chunks", transfer_id);
+            let handle = Mutex::new(transfer);
+            return self.publish(handle.lock().await, hash).await;
+        }
 
-        Ok(transfer_id)
+        let metadata = transfer.metadata.clone();
+        let mut transfers = self.transfers.write().await;
+        transfers.insert(transfer_id, Arc::new(Mutex::new(transfer)));
+
+        Ok(metadata)
     }
 
-    pub async fn receive_chunk(
+    /// Streams one chunk from `reader` into the transfer, then into its offset
+    /// in the data file.
+    ///
+    /// The body is copied through a fixed-size buffer and hashed as it arrives,
+    /// so memory use does not depend on the chunk size. It lands in a staging
+    /// file first: a chunk whose size or hash is wrong never reaches the data
+    /// file, and an accepted chunk is only replaced by a resend that checks out.
+    pub async fn receive_chunk<R>(
         &self,
         transfer_id: &str,
         chunk_index: usize,
-        chunk_data: Vec<u8>,
-    ) -> Result<String> {
-        let mut transfers = self.transfers.lock().await;
-        
-        let transfer = transfers
-            .get_mut(transfer_id)
-            .ok_or_else(|| TransferError::TransferNotFound(transfer_id.to_string()))?;
+        mut reader: R,
+        expected_hash: Option<&str>,
+    ) -> Result<String>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let handle = self.transfer(transfer_id).await?;
+
+        let (dir, chunk_size, chunk_len) = {
+            let mut transfer = handle.lock().await;
+            ensure_open(&transfer)?;
+
+            if chunk_index >= transfer.metadata.total_chunks {
+                return Err(TransferError::ChunkOutOfOrder {
+                    expected: transfer.metadata.total_chunks,
+                    got: chunk_index,
+                }.into());
+            }
+
+            if transfer.completing {
+                return Err(TransferError::Busy("transfer is completing".to_string()).into());
+            }
+            if transfer.writing.contains(&chunk_index) {
+                return Err(TransferError::Busy(format!("chunk {} is already being uploaded", chunk_index)).into());
+            }
+
+            // Re-sending identical bytes is a no-op
+            if let Some(existing) = transfer.received_chunks.get(&chunk_index) {
+                if expected_hash.is_some_and(|h| h.eq_ignore_ascii_case(&existing.hash)) {
+                    return Ok(existing.hash.clone());
+                }
+            }
 
-        if chunk_index >= transfer.metadata.total_chunks {
-            return Err(TransferError::ChunkOutOfOrder {
-                expected: transfer.metadata.total_chunks,
-                got: chunk_index,
-            }.into());
+            transfer.writing.insert(chunk_index);
+            transfer.last_activity = Instant::now();
+            let chunk_len = chunk_len(&transfer.metadata, chunk_index);
+            (transfer.dir.clone(), transfer.metadata.chunk_size as u64, chunk_len)
+        };
+
+        // Staged and checked in full before any of it reaches the data file
+        let staged = dir.join(format!("chunk-{}.part", chunk_index));
+        let key = self.storage_key();
+        let stored = async {
+            storage::create_data_file(&staged, key, chunk_len, chunk_len).await?;
+            let writer = storage::ChunkWriter::open(&staged, key, 0, chunk_len).await?;
+            let hash = write_chunk(&mut reader, writer, chunk_index, chunk_len).await?;
+
+            // Reject a chunk that does not match the client's declared hash
+            if let Some(expected) = expected_hash {
+                if !expected.eq_ignore_ascii_case(&hash) {
+                    return Err(TransferError::InvalidChunkHash {
+                        index: chunk_index,
+                        expected: expected.to_ascii_lowercase(),
+                        actual: hash,
+                    }.into());
+                }
+            }
+
+            {
+                let mut transfer = handle.lock().await;
+                ensure_open(&transfer)?;
+                if let Some(existing) = transfer.received_chunks.get(&chunk_index) {
+                    if existing.hash == hash {
+                        return Ok((hash, false));
+                    }
+                    // Checked bytes are about to replace it, so it no longer counts
+                    transfer.received_chunks.remove(&chunk_index);
+                    transfer
+                        .journal
+                        .append(&JournalRecord::Discard { index: chunk_index })
+                        .await?;
+                }
+            }
+
+            copy_staged_chunk(&staged, &dir.join(DATA_FILE), key, chunk_index, chunk_size).await?;
+            anyhow::Ok((hash, true))
         }
+        .await;
+        let _ = fs::remove_file(&staged).await;
+
+        let mut transfer = handle.lock().await;
+        transfer.writing.remove(&chunk_index);
+        transfer.last_activity = Instant::now();
 
-        // Compute hash
-        let mut hasher = Sha256::new();
-        hasher.update(&chunk_data);
-        let hash = hex::encode(hasher.finalize());
+        let (hash, written) = stored?;
+        if !written {
+            // Resent with the bytes it already had
+            return Ok(hash);
+        }
 
-        // Write chunk to temp file
-        let chunk_path = transfer.temp_dir.path().join(format!("chunk_{}.tmp", chunk_index));
-        let mut file = fs::File::create(&chunk_path).await?;
-        file.write_all(&chunk_data).await?;
-        file.sync_all().await?;
+        // Cancelled or failed while the body was streaming
+        ensure_open(&transfer)?;
 
         debug!("Received chunk {} for transfer {} (hash: {})", 
                chunk_index, transfer_id, &hash[..16]);
@@ -185,9 +573,14 @@ impl TransferManager {
         let chunk_info = ChunkInfo {
             index: chunk_index,
             hash: hash.clone(),
-            size: chunk_data.len(),
+            size: chunk_len as usize,
         };
 
+        // The bytes are already synced, so journaling now keeps replay honest
+        transfer
+            .journal
+            .append(&JournalRecord::Chunk { chunk: chunk_info.clone() })
+            .await?;
         transfer.received_chunks.insert(chunk_index, chunk_info);
         transfer.metadata.status = TransferStatus::InProgress {
             received_chunks: transfer.received_chunks.len(),
@@ -196,43 +589,152 @@ impl TransferManager {
         Ok(hash)
     }
 
+    /// Verifies the data file and renames it into the storage directory.
+    ///
+    /// No lock is held while the file is hashed, so status queries and other
+    /// transfers keep moving during completion of a large file.
     pub async fn complete_transfer(&self, transfer_id: &str) -> Result<TransferMetadata> {
-        let mut transfers = self.transfers.lock().await;
-        
-        let transfer = transfers
-            .get_mut(transfer_id)
-            .ok_or_else(|| TransferError::TransferNotFound(transfer_id.to_string()))?;
+        let handle = self.transfer(transfer_id).await?;
 
-        // Verify all chunks received
-        if transfer.received_chunks.len() != transfer.metadata.total_chunks {
-            return Err(TransferError::ChunkOutOfOrder {
-                expected: transfer.metadata.total_chunks,
-                got: transfer.received_chunks.len(),
-            }.into());
-        }
+        let data_path = {
+            let mut transfer = handle.lock().await;
+            ensure_open(&transfer)?;
+
+            if transfer.completing || !transfer.writing.is_empty() {
+                return Err(TransferError::Busy("chunks are still being written".to_string()).into());
+            }
+
+            // Verify all chunks received
+            if transfer.received_chunks.len() != transfer.metadata.total_chunks {
+                return Err(TransferError::ChunkOutOfOrder {
+                    expected: transfer.metadata.total_chunks,
+                    got: transfer.received_chunks.len(),
+                }.into());
+            }
+
+            transfer.completing = true;
+            transfer.last_activity = Instant::now();
+            transfer.dir.join(DATA_FILE)
+        };
 
         info!("Completing transfer: {}", transfer_id);
 
-        // Reassemble file
-        let final_path = self.storage_path.join(&transfer.metadata.filename);
-        let mut final_file = fs::File::create(&final_path).await?;
+        // Hashes the bytes as uploaded, whatever this server's encryption does to them
+        let hashed = async {
+            let reader = storage::open_data_file(&data_path, self.storage_key()).await?;
+            hashing::compute_reader_hash(reader).await
+        }
+        .await;
+
+        let mut transfer = handle.lock().await;
+        transfer.completing = false;
+        ensure_open(&transfer)?;
+        let final_hash = hashed?;
+
+        if let Some(expected) = transfer.metadata.expected_hash.clone() {
+            if expected != final_hash {
+                let err = TransferError::FileHashMismatch { expected, actual: final_hash };
+                warn!("Transfer {} failed verification: {}", transfer_id, err);
+                fail_transfer(&mut transfer, err.to_string()).await;
+                return Err(err.into());
+            }
+        }
+
+        self.publish(transfer, final_hash).await
+    }
 
-        let mut final_hasher = Sha256::new();
+    /// Moves a verified data file to its final name under the conflict policy,
+    /// copies it into the dedup store and its batch, and retires the transfer.
+    async fn publish(&self, mut transfer: MutexGuard<'_, Transfer>, final_hash: String) -> Result<TransferMetadata> {
+        let transfer_id = transfer.metadata.id.clone();
+        let data_path = transfer.dir.join(DATA_FILE);
 
-        for i in 0..transfer.metadata.total_chunks {
-            let chunk_path = transfer.temp_dir.path().join(format!("chunk_{}.tmp", i));
-            let mut chunk_file = fs::File::open(&chunk_path).await?;
-            let mut chunk_data = Vec::new();
-            chunk_file.read_to_end(&mut chunk_data).await?;
-            
-            final_file.write_all(&chunk_data).await?;
-            final_hasher.update(&chunk_data);
+        let target = sanitize::resolve(&self.storage_path, &transfer.metadata.filename)
+            .map_err(TransferError::from)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
         }
 
-        final_file.sync_all().await?;
-        drop(final_file);
+        let policy = transfer.metadata.conflict_policy.unwrap_or(self.config.conflict_policy);
+        let publish = self.publish.lock().await;
+        let destination = match conflict::prepare_destination(&target, policy).await {
+            Ok(destination) => destination,
+            Err(err @ TransferError::FileExists(_)) => {
+                drop(publish);
+                warn!("Transfer {} rejected: {}", transfer_id, err);
+                fail_transfer(&mut transfer, err.to_string()).await;
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let final_path = destination.path;
+        let stored_name = relative_name(&self.storage_path, &final_path)
+            .unwrap_or_else(|| transfer.metadata.filename.clone());
 
-        let final_hash = hex::encode(final_hasher.finalize());
+        let batch_id = batch_key(&transfer.metadata);
+        let batch_path = self.batch_dir(&batch_id)?.join(&stored_name);
+        let blob_path = self.dedup.blob_path(&final_hash);
+        let mut touched = vec![final_path.as_path(), batch_path.as_path(), blob_path.as_path()];
+        touched.extend(destination.backup.as_deref());
+        let before = limits::footprint(&touched);
+        let mut upload = CompletedUpload {
+            batch_id,
+            name: transfer.metadata.filename.clone(),
+            stored_name: stored_name.clone(),
+            size: transfer.metadata.total_size,
+            uploaded_at: Utc::now().to_rfc3339(),
+            final_hash: Some(final_hash.clone()),
+            stored_len: None,
+            stored_modified: None,
+        };
+
+        // Once ingested, the data file may have been replaced by a clone of the
+        // blob, so any failure past this point fails the transfer
+        let published = async {
+            // Only new content is stored from the data file; known content reuses its blob as stored
+            if self.dedup.verify(&final_hash).await.is_none()
+                && storage::finish_data_file(&data_path, self.storage_key(), self.config.compress_at_rest).await?
+            {
+                debug!("Compressed {} at rest", stored_name);
+            }
+            self.dedup.ingest(&data_path, &final_hash).await?;
+            // The batch keeps its own copy, whatever later lands at the shared name
+            self.dedup.link(&final_hash, &batch_path).await?;
+            // The data file is final now, and the rename below keeps its length and time
+            let meta = fs::metadata(&data_path).await?;
+            upload.stored_len = Some(meta.len());
+            upload.stored_modified = meta.modified().ok().map(timestamp);
+            // Kept before the completion is journaled, which lets recovery replace the file
+            if let Some(backup) = &destination.backup {
+                conflict::keep_backup(&final_path, backup).await?;
+            }
+            transfer
+                .journal
+                .append(&JournalRecord::Completed { upload: upload.clone() })
+                .await?;
+            fs::rename(&data_path, &final_path).await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let (Err(_), Some(backup)) = (&published, &destination.backup) {
+            // The file it would have kept is still at its name
+            if let Err(e) = fs::remove_file(backup).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to remove backup {}: {}", backup.display(), e);
+                }
+            }
+        }
+        // Whatever was stored before a failure still takes its space
+        self.adjust_usage(limits::footprint(&touched), before);
+        drop(publish);
+
+        if let Err(e) = published {
+            warn!("Failed to store completed transfer {}: {}", transfer_id, e);
+            fail_transfer(&mut transfer, format!("failed to store file: {}", e)).await;
+            return Err(e);
+        }
+
+        transfer.metadata.stored_name = Some(stored_name.clone());
         
         transfer.metadata.status = TransferStatus::Completed {
             final_hash: final_hash.clone(),
@@ -241,68 +743,362 @@ impl TransferManager {
         info!("Transfer {} completed. File: {} (hash: {})", 
               transfer_id, transfer.metadata.filename, &final_hash[..16]);
 
-        let mut completed_uploads = self.completed_uploads.lock().await;
-        completed_uploads.push(CompletedUpload {
-            batch_id: transfer
-                .metadata
-                .batch_id
-                .clone()
-                .unwrap_or_else(|| format!("single_{}", transfer.metadata.id)),
-            name: transfer.metadata.filename.clone(),
-            size: transfer.metadata.total_size,
-            uploaded_at: Utc::now().to_rfc3339(),
-        });
-
         let metadata = transfer.metadata.clone();
-        
-        // Remove from active transfers
-        transfers.remove(transfer_id);
+        let dir = transfer.dir.clone();
+        drop(transfer);
+
+        self.record_upload(upload).await;
+
+        // Remove from active transfers along with its journal
+        self.transfers.write().await.remove(&transfer_id);
+        remove_transfer_dir(&dir).await;
 
         Ok(metadata)
     }
 
+    /// Adds a published file to the upload history and counts it against its
+    /// blob. The history goes first: [`recover`](Self::recover) recounts blobs
+    /// from it, so a crash in between cannot count an upload twice.
+    async fn record_upload(&self, upload: CompletedUpload) {
+        let mut completed_uploads = self.completed_uploads.lock().await;
+        if let Err(e) = history::append(&self.storage_path.join(STATE_DIR), &upload).await {
+            warn!("Failed to record upload of {} in history: {}", upload.stored_name, e);
+        }
+        if let Some(hash) = &upload.final_hash {
+            self.dedup.record(hash, upload.size).await;
+        }
+        completed_uploads.push(upload);
+    }
+
+    /// Finishes publishing a transfer whose completion was journaled before a
+    /// crash: the data file is moved into place if it is still there, and the
+    /// upload is recorded unless the history already has it.
+    async fn finish_publish(&self, dir: &Path, upload: CompletedUpload) -> Result<()> {
+        let data_path = dir.join(DATA_FILE);
+        if fs::try_exists(&data_path).await? {
+            let final_path = sanitize::resolve(&self.storage_path, &upload.stored_name).map_err(TransferError::from)?;
+            if let Some(parent) = final_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let before = limits::footprint(&[&final_path]);
+            fs::rename(&data_path, &final_path).await?;
+            self.adjust_usage(limits::footprint(&[&final_path]), before);
+        }
+        let recorded = self.completed_uploads.lock().await.iter().any(|item| {
+            item.batch_id == upload.batch_id
+                && item.stored_name == upload.stored_name
+                && item.uploaded_at == upload.uploaded_at
+        });
+        if !recorded {
+            self.record_upload(upload).await;
+        }
+        remove_transfer_dir(dir).await;
+        Ok(())
+    }
+
     pub async fn get_transfer_status(&self, transfer_id: &str) -> Option<TransferMetadata> {
-        let transfers = self.transfers.lock().await;
-        transfers.get(transfer_id).map(|t| t.metadata.clone())
+        let handle = self.transfer(transfer_id).await.ok()?;
+        let transfer = handle.lock().await;
+        Some(transfer.metadata.clone())
+    }
+
+    /// Fails for transfers that can no longer take chunks, so clients start over instead of resuming.
+    pub async fn chunk_manifest(&self, transfer_id: &str) -> Result<ChunkManifest> {
+        let handle = self.transfer(transfer_id).await?;
+        let transfer = handle.lock().await;
+        ensure_open(&transfer)?;
+
+        let mut received: Vec<ChunkInfo> = transfer.received_chunks.values().cloned().collect();
+        received.sort_by_key(|c| c.index);
+        let missing = missing_ranges(
+            transfer.metadata.total_chunks,
+            received.iter().map(|c| c.index),
+        );
+
+        Ok(ChunkManifest {
+            transfer_id: transfer.metadata.id.clone(),
+            total_chunks: transfer.metadata.total_chunks,
+            chunk_size: transfer.metadata.chunk_size,
+            received,
+            missing,
+        })
     }
 
     pub async fn cancel_transfer(&self, transfer_id: &str) -> Result<()> {
-        let mut transfers = self.transfers.lock().await;
-        transfers
+        let handle = self
+            .transfers
+            .write()
+            .await
             .remove(transfer_id)
             .ok_or_else(|| TransferError::TransferNotFound(transfer_id.to_string()))?;
+
+        // In-flight chunk writers hold the handle; the status tells them to stop
+        let mut transfer = handle.lock().await;
+        transfer.metadata.status = TransferStatus::Failed { reason: "cancelled".to_string() };
+        remove_transfer_dir(&transfer.dir).await;
         info!("Cancelled transfer: {}", transfer_id);
         Ok(())
     }
 
-    pub async fn list_files(&self) -> Result<Vec<SharedFile>> {
-        let mut out = Vec::new();
-        let mut entries: ReadDir = fs::read_dir(&self.storage_path).await?;
+    /// Resolves a selection of stored files, or one directory, to
+    /// `(archive name, path)` pairs, below batch `batch_id` if given and the
+    /// storage root otherwise.
+    ///
+    /// Files keep the path they were named by; a directory's files are named
+    /// from the directory itself down, so it unpacks as one folder.
+    pub async fn select_files(
+        &self,
+        batch_id: Option<&str>,
+        files: &[String],
+        directory: Option<&str>,
+    ) -> Result<Vec<(String, PathBuf)>, TransferError> {
+        let root = match batch_id {
+            Some(batch_id) => self.batch_dir(batch_id)?,
+            None => self.storage_path.clone(),
+        };
 
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if path.is_file() {
-                let meta = entry.metadata().await?;
-                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                    let modified_at = meta
-                        .modified()
-                        .ok()
-                        .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339())
-                        .unwrap_or_else(|| "unknown".to_string());
-
-                    out.push(SharedFile {
-                        name: name.to_string(),
-                        size: meta.len(),
-                        modified_at,
-                    });
+        match (files.is_empty(), directory) {
+            (false, None) => {
+                let mut selected: Vec<(String, PathBuf)> = Vec::with_capacity(files.len());
+                for file in files {
+                    let name = sanitize::check_relative_path(file)?;
+                    let path = root.join(&name);
+                    if !fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+                        return Err(TransferError::FileNotFound(name));
+                    }
+                    if !selected.iter().any(|(n, _)| *n == name) {
+                        selected.push((name, path));
+                    }
                 }
+                Ok(selected)
             }
+            (true, Some(directory)) => {
+                let name = sanitize::check_relative_path(directory)?;
+                let dir = root.join(&name);
+                if !fs::metadata(&dir).await.is_ok_and(|m| m.is_dir()) {
+                    return Err(TransferError::FileNotFound(name));
+                }
+                let base = dir.parent().map(Path::to_path_buf).unwrap_or_else(|| root.clone());
+                tokio::task::spawn_blocking(move || {
+                    let mut selected = Vec::new();
+                    let entries = WalkDir::new(&dir)
+                        .sort_by_file_name()
+                        .into_iter()
+                        .filter_entry(|e| e.file_name() != STATE_DIR);
+                    for entry in entries {
+                        let entry = entry.map_err(std::io::Error::from)?;
+                        if !entry.file_type().is_file() {
+                            continue;
+                        }
+                        if let Some(name) = relative_name(&base, entry.path()) {
+                            selected.push((name, entry.into_path()));
+                        }
+                    }
+                    Ok(selected)
+                })
+                .await
+                .map_err(std::io::Error::other)?
+            }
+            _ => Err(TransferError::InvalidRequest(
+                "select either a list of files or one directory".to_string(),
+            )),
         }
+    }
+
+    /// What a share link for one stored file, or for one batch, grants;
+    /// fails unless exactly one of them is given and it exists.
+    pub async fn share_target(&self, file: Option<&str>, batch_id: Option<&str>) -> Result<ShareTarget, TransferError> {
+        match (file, batch_id) {
+            (Some(file), None) => {
+                let name = sanitize::check_relative_path(file)?;
+                if !fs::metadata(self.storage_path.join(&name)).await.is_ok_and(|m| m.is_file()) {
+                    return Err(TransferError::FileNotFound(name));
+                }
+                Ok(ShareTarget::File(name))
+            }
+            (None, Some(batch_id)) => {
+                self.batch_dir(batch_id)?;
+                if self.files_for_batch(batch_id).await.is_empty() {
+                    return Err(TransferError::FileNotFound(format!("batch {}", batch_id)));
+                }
+                Ok(ShareTarget::Batch(batch_id.to_string()))
+            }
+            _ => Err(TransferError::InvalidRequest(
+                "share either one file or one batch".to_string(),
+            )),
+        }
+    }
+
+    /// Deletes what a share served: one stored file with the uploads of its
+    /// content under that name, or a batch with its history and the shared
+    /// copies that still hold its content. Either way the batch copies, the
+    /// history rows and the dedup references of those uploads go too.
+    pub async fn delete_shared(&self, target: &ShareTarget) -> Result<()> {
+        let _publish = self.publish.lock().await;
+        match target {
+            ShareTarget::File(name) => {
+                let path = sanitize::locate(&self.storage_path, name).map_err(TransferError::from)?;
+                let hash = self.content_hash(&path).await;
+                let removed: Vec<CompletedUpload> = {
+                    let mut completed_uploads = self.completed_uploads.lock().await;
+                    let (removed, kept): (Vec<CompletedUpload>, Vec<CompletedUpload>) = completed_uploads
+                        .iter()
+                        .cloned()
+                        .partition(|item| item.stored_name == *name && hash.is_some() && item.final_hash == hash);
+                    if !removed.is_empty() {
+                        history::rewrite(&self.storage_path.join(STATE_DIR), &kept).await?;
+                        *completed_uploads = kept;
+                    }
+                    removed
+                };
+
+                let batches_root = self.storage_path.join(STATE_DIR).join(history::BATCHES_DIR);
+                let copies = removed
+                    .iter()
+                    .map(|upload| self.batch_file(&upload.batch_id, &upload.stored_name))
+                    .collect::<Result<Vec<PathBuf>, TransferError>>()?;
+                let blobs: Vec<PathBuf> = hash.iter().map(|hash| self.dedup.blob_path(hash)).collect();
+                let touched: Vec<&Path> = [&path].into_iter().chain(&copies).chain(&blobs).map(PathBuf::as_path).collect();
+                let before = limits::footprint(&touched);
+
+                for copy in &copies {
+                    remove_stored(&batches_root, copy).await?;
+                }
+                remove_stored(&self.storage_path, &path).await?;
+                for hash in removed.iter().filter_map(|item| item.final_hash.as_deref()) {
+                    self.dedup.release(hash).await;
+                }
+                self.adjust_usage(limits::footprint(&touched), before);
+            }
+            ShareTarget::Batch(batch_id) => {
+                let batch_dir = self.batch_dir(batch_id)?;
+                let (removed, superseded): (Vec<CompletedUpload>, Vec<bool>) = {
+                    let mut completed_uploads = self.completed_uploads.lock().await;
+                    let kept: Vec<CompletedUpload> = completed_uploads
+                        .iter()
+                        .filter(|item| item.batch_id != *batch_id)
+                        .cloned()
+                        .collect();
+                    history::rewrite(&self.storage_path.join(STATE_DIR), &kept).await?;
+                    let removed: Vec<CompletedUpload> =
+                        completed_uploads.drain(..).filter(|item| item.batch_id == *batch_id).collect();
+                    let superseded = removed
+                        .iter()
+                        .map(|upload| {
+                            kept.iter()
+                                .any(|item| item.stored_name == upload.stored_name && item.uploaded_at > upload.uploaded_at)
+                        })
+                        .collect();
+                    *completed_uploads = kept;
+                    (removed, superseded)
+                };
+
+                let paths: Vec<PathBuf> = removed
+                    .iter()
+                    .flat_map(|upload| {
+                        let blob = upload.final_hash.as_deref().map(|hash| self.dedup.blob_path(hash));
+                        [self.storage_path.join(&upload.stored_name), batch_dir.join(&upload.stored_name)]
+                            .into_iter()
+                            .chain(blob)
+                    })
+                    .collect::<HashSet<PathBuf>>()
+                    .into_iter()
+                    .collect();
+                let touched: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+                let before = limits::footprint(&touched);
+
+                // The shared copy goes too, unless a later upload has taken its name
+                for (upload, superseded) in removed.iter().zip(superseded) {
+                    let shared = self.storage_path.join(&upload.stored_name);
+                    if !superseded && self.same_content(&batch_dir.join(&upload.stored_name), &shared).await {
+                        remove_stored(&self.storage_path, &shared).await?;
+                    }
+                }
+                match fs::remove_dir_all(&batch_dir).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                for hash in removed.iter().filter_map(|item| item.final_hash.as_deref()) {
+                    self.dedup.release(hash).await;
+                }
+                self.adjust_usage(limits::footprint(&touched), before);
+            }
+        }
+        info!("Deleted the files of share {:?}", target);
+        Ok(())
+    }
+
+    /// Lists stored files recursively; nested files are named by their
+    /// `/`-separated path below the storage root.
+    pub async fn list_files(&self) -> Result<Vec<SharedFile>> {
+        let root = self.storage_path.clone();
+        let key = self.config.encryption_key.clone();
+        let uploads = self.latest_uploads().await;
+        let mut out = tokio::task::spawn_blocking(move || -> Result<Vec<SharedFile>> {
+            let mut out = Vec::new();
+            let entries = WalkDir::new(&root)
+                .min_depth(1)
+                .into_iter()
+                .filter_entry(|e| e.depth() != 1 || e.file_name() != STATE_DIR);
+
+            for entry in entries {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let Some(name) = relative_name(&root, entry.path()) else {
+                    continue;
+                };
+                let meta = entry.metadata()?;
+                let info = match storage::inspect(entry.path(), key.as_ref()) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        warn!("Listing {} by its size on disk: {}", name, e);
+                        storage::StoredInfo {
+                            logical_size: meta.len(),
+                            physical_size: meta.len(),
+                            compressed: false,
+                            encrypted: false,
+                        }
+                    }
+                };
+                let modified_at = meta
+                    .modified()
+                    .ok()
+                    .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339())
+                    .unwrap_or_else(|| "unknown".to_string());
+
+                let final_hash = uploads
+                    .get(&name)
+                    .filter(|upload| matches_upload(&meta, info.logical_size, upload))
+                    .and_then(|upload| upload.final_hash.clone());
+
+                out.push(SharedFile {
+                    name,
+                    size: info.logical_size,
+                    physical_size: info.physical_size,
+                    modified_at,
+                    final_hash,
+                });
+            }
+            Ok(out)
+        })
+        .await??;
 
         out.sort_by(|a, b| b.modified_at.cmp(&a.modified_at));
         Ok(out)
     }
 
+    /// The latest upload to each stored name.
+    async fn latest_uploads(&self) -> HashMap<String, CompletedUpload> {
+        let completed_uploads = self.completed_uploads.lock().await;
+        let mut latest = HashMap::new();
+        for item in completed_uploads.iter() {
+            latest.insert(item.stored_name.clone(), item.clone());
+        }
+        latest
+    }
+
     pub async fn list_upload_batches(&self) -> Vec<UploadBatch> {
         let completed_uploads = self.completed_uploads.lock().await;
         let mut grouped: HashMap<String, Vec<CompletedUpload>> = HashMap::new();
@@ -326,8 +1122,10 @@ impl TransferManager {
                     .into_iter()
                     .map(|f| UploadedFile {
                         name: f.name,
+                        stored_name: f.stored_name,
                         size: f.size,
                         uploaded_at: f.uploaded_at,
+                        final_hash: f.final_hash,
                     })
                     .collect();
 
@@ -350,23 +1148,597 @@ impl TransferManager {
             .filter(|item| item.batch_id == batch_id)
             .map(|item| UploadedFile {
                 name: item.name.clone(),
+                stored_name: item.stored_name.clone(),
                 size: item.size,
                 uploaded_at: item.uploaded_at.clone(),
+                final_hash: item.final_hash.clone(),
             })
             .collect();
         files.sort_by(|a, b| a.uploaded_at.cmp(&b.uploaded_at));
         files
     }
 
+    /// The recorded SHA-256 of a shared file, if it still holds what its last
+    /// upload wrote.
+    pub async fn shared_file_hash(&self, filename: &str) -> Option<String> {
+        let path = sanitize::locate(&self.storage_path, filename).ok()?;
+        let name = relative_name(&self.storage_path, &path)?;
+        let upload = {
+            let completed_uploads = self.completed_uploads.lock().await;
+            completed_uploads.iter().rev().find(|item| item.stored_name == name)?.clone()
+        };
+        let meta = fs::metadata(&path).await.ok()?;
+        let info = storage::inspect_async(&path, self.storage_key()).await.ok()?;
+        matches_upload(&meta, info.logical_size, &upload).then_some(upload.final_hash?)
+    }
+
+    /// The SHA-256 a batch recorded for one of its files.
+    pub async fn batch_file_hash(&self, batch_id: &str, stored_name: &str) -> Option<String> {
+        let completed_uploads = self.completed_uploads.lock().await;
+        completed_uploads
+            .iter()
+            .rev()
+            .find(|item| item.batch_id == batch_id && item.stored_name == stored_name)
+            .and_then(|item| item.final_hash.clone())
+    }
+
+    /// Reads a stored file's content, undoing any compression or encryption at rest.
     pub async fn read_file(&self, filename: &str) -> Result<Vec<u8>> {
-        let path = self.storage_path.join(filename);
-        let data = fs::read(path).await?;
+        let path = sanitize::locate(&self.storage_path, filename).map_err(TransferError::from)?;
+        let (mut reader, info) = storage::open(&path, self.storage_key()).await?;
+        let mut data = Vec::with_capacity(info.logical_size as usize);
+        reader.read_to_end(&mut data).await?;
         Ok(data)
     }
 
+    pub async fn dedup_stats(&self) -> DedupStats {
+        self.dedup.stats().await
+    }
+
+    /// Clones the content of files already in the storage root into the dedup
+    /// store, so later uploads of it complete without sending any chunks. The
+    /// files themselves are left as they are. Returns how many blobs were added.
+    ///
+    /// Without reflinks the store would hold a second full copy of every
+    /// file, so nothing is done on filesystems that cannot clone.
+    /// Files are hashed without locks; one that changes meanwhile is skipped.
+    pub async fn dedup_existing(&self) -> Result<usize> {
+        let blobs = self.storage_path.join(STATE_DIR).join(hashing::BLOBS_DIR);
+        fs::create_dir_all(&blobs).await?;
+        if !hashing::reflinks_supported(&blobs).await {
+            warn!("The storage filesystem cannot clone files; existing files are not added to the dedup store");
+            return Ok(0);
+        }
+        let mut added = 0;
+        for file in self.list_files().await? {
+            let path = self.storage_path.join(&file.name);
+            let before = fs::metadata(&path).await?;
+            let hashed = match storage::open(&path, self.storage_key()).await {
+                Ok((reader, _)) => hashing::compute_reader_hash(reader).await,
+                Err(e) => Err(e.into()),
+            };
+            let hash = match hashed {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn!("Skipping {} during dedup: {}", file.name, e);
+                    continue;
+                }
+            };
+
+            let _publish = self.publish.lock().await;
+            let after = fs::metadata(&path).await?;
+            if after.len() != before.len() || after.modified().ok() != before.modified().ok() {
+                continue;
+            }
+            let blob = self.dedup.blob_path(&hash);
+            let before = limits::footprint(&[&blob]);
+            added += usize::from(self.dedup.adopt(&path, &hash).await?);
+            self.adjust_usage(limits::footprint(&[&blob]), before);
+        }
+        Ok(added)
+    }
+
+    /// SHA-256 of a stored file's content, however it is encoded.
+    async fn content_hash(&self, path: &Path) -> Option<String> {
+        let (reader, _) = storage::open(path, self.storage_key()).await.ok()?;
+        hashing::compute_reader_hash(reader).await.ok()
+    }
+
+    /// Whether two stored files hold the same content.
+    async fn same_content(&self, a: &Path, b: &Path) -> bool {
+        match (self.content_hash(a).await, self.content_hash(b).await) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
     pub fn storage_path(&self) -> PathBuf {
         self.storage_path.clone()
     }
+
+    /// Encrypts the plaintext files of a storage directory written before
+    /// encryption was enabled. Returns how many files were encrypted.
+    ///
+    /// Must run before [`recover`](Self::recover).
+    pub async fn encrypt_existing(&self) -> Result<usize> {
+        let key = self
+            .storage_key()
+            .ok_or_else(|| anyhow::anyhow!("no encryption key is configured"))?;
+        migrate::encrypt_existing(&self.storage_path, key).await
+    }
+
+    /// Rebuilds the upload history, the blob counts it implies, and in-flight
+    /// transfers after a restart.
+    ///
+    /// Chunk records whose file is missing or has the wrong size are dropped so
+    /// the client resends them. Returns the number of recovered transfers.
+    pub async fn recover(&self) -> Result<usize> {
+        let uploads = history::load(&self.storage_path.join(STATE_DIR)).await?;
+        self.dedup.load().await?;
+        self.dedup
+            .recount(uploads.iter().filter_map(|upload| Some((upload.final_hash.as_deref()?, upload.size))))
+            .await;
+        *self.completed_uploads.lock().await = uploads;
+
+        let transfers_dir = self.transfers_dir();
+        let mut entries = match fs::read_dir(&transfers_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut transfers = self.transfers.write().await;
+        let mut recovered = 0;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let dir = entry.path();
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let replayed = match Journal::replay(&dir).await {
+                Ok(Some(replayed)) => replayed,
+                Ok(None) => {
+                    warn!("Discarding transfer directory without journal: {}", dir.display());
+                    remove_transfer_dir(&dir).await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to replay journal in {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+
+            if let Some(upload) = replayed.completed {
+                info!("Finishing transfer {} that completed before the restart", replayed.metadata.id);
+                if let Err(e) = self.finish_publish(&dir, upload).await {
+                    warn!("Failed to finish completed transfer {}: {}", replayed.metadata.id, e);
+                }
+                continue;
+            }
+
+            let mut metadata = replayed.metadata;
+            let mut received_chunks = HashMap::new();
+
+            // Failed transfers already released their data; otherwise the
+            // journaled chunks are only trusted if the data file is intact
+            if replayed.failed.is_none() {
+                let data_path = dir.join(DATA_FILE);
+                let chunk_size = metadata.chunk_size as u64;
+                let expected_len = storage::data_file_len(self.storage_key(), metadata.total_size, chunk_size);
+                // A length mismatch also catches data files left by a run with encryption toggled
+                match fs::metadata(&data_path).await {
+                    Ok(meta) if meta.len() == expected_len => {
+                        received_chunks.extend(replayed.chunks.into_iter().map(|c| (c.index, c)));
+                    }
+                    _ => {
+                        warn!("Data file of transfer {} is missing or truncated; chunks must be resent",
+                              metadata.id);
+                        storage::create_data_file(&data_path, self.storage_key(), metadata.total_size, chunk_size).await?;
+                    }
+                }
+            }
+
+            metadata.status = if let Some(reason) = replayed.failed {
+                TransferStatus::Failed { reason }
+            } else if received_chunks.is_empty() {
+                TransferStatus::Pending
+            } else {
+                TransferStatus::InProgress { received_chunks: received_chunks.len() }
+            };
+
+            info!("Recovered transfer {} for file: {} ({}/{} chunks)",
+                  metadata.id, metadata.filename, received_chunks.len(), metadata.total_chunks);
+
+            let journal = Journal::open(&dir).await?;
+            transfers.insert(metadata.id.clone(), Arc::new(Mutex::new(Transfer {
+                metadata,
+                dir,
+                journal,
+                received_chunks,
+                writing: HashSet::new(),
+                completing: false,
+                last_activity: Instant::now(),
+            })));
+            recovered += 1;
+        }
+        drop(transfers);
+
+        if self.config.limits.storage_quota.is_some() {
+            self.stored_bytes().await;
+        }
+        Ok(recovered)
+    }
+
+    /// Expires open transfers idle for longer than the configured timeout and
+    /// forgets failed ones whose retention has passed. Returns how many
+    /// transfers were expired.
+    ///
+    /// Transfers with a chunk body or completion in flight are never idle.
+    pub async fn reap_expired(&self) -> usize {
+        let handles: Vec<(String, TransferHandle)> = self
+            .transfers
+            .read()
+            .await
+            .iter()
+            .map(|(id, handle)| (id.clone(), handle.clone()))
+            .collect();
+
+        let mut expired = 0;
+        let mut forgotten = Vec::new();
+        for (transfer_id, handle) in handles {
+            let mut transfer = handle.lock().await;
+            let idle = transfer.last_activity.elapsed();
+
+            if matches!(transfer.metadata.status, TransferStatus::Failed { .. }) {
+                if idle >= self.config.failed_retention {
+                    forgotten.push((transfer_id, transfer.dir.clone()));
+                }
+            } else if !transfer.completing
+                && transfer.writing.is_empty()
+                && idle >= self.config.idle_timeout
+            {
+                info!("Transfer {} expired after {}s without activity", transfer_id, idle.as_secs());
+                fail_transfer(&mut transfer, "expired".to_string()).await;
+                expired += 1;
+            }
+        }
+
+        if !forgotten.is_empty() {
+            let mut transfers = self.transfers.write().await;
+            for (transfer_id, _) in &forgotten {
+                transfers.remove(transfer_id);
+            }
+        }
+        for (transfer_id, dir) in forgotten {
+            debug!("Forgetting failed transfer {}", transfer_id);
+            remove_transfer_dir(&dir).await;
+        }
+
+        expired
+    }
+
+    /// Runs [`reap_expired`](Self::reap_expired) periodically in the background.
+    pub fn spawn_reaper(&self) -> JoinHandle<()> {
+        let manager = self.clone();
+        let period = self
+            .config
+            .idle_timeout
+            .min(self.config.failed_retention)
+            .div_f32(4.0)
+            .clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let expired = manager.reap_expired().await;
+                if expired > 0 {
+                    info!("Expired {} idle transfer(s)", expired);
+                }
+            }
+        })
+    }
+
+    /// Checks a new transfer of `total_size` bytes against the configured
+    /// limits, and the `planned` bytes it will write against the quota and the
+    /// free space left on the storage volume.
+    async fn check_limits(&self, total_size: u64, planned: u64, batch_id: Option<&str>) -> Result<(), TransferError> {
+        let limits = &self.config.limits;
+
+        if let Some(limit) = limits.max_file_size {
+            if total_size > limit {
+                return Err(TransferError::FileTooLarge { size: total_size, limit });
+            }
+        }
+
+        // In-flight transfers: bytes they will write, those not written yet, and this batch's share
+        let handles: Vec<TransferHandle> = self.transfers.read().await.values().cloned().collect();
+        let (mut reserved, mut outstanding, mut batch_in_flight) = (0u64, 0u64, 0u64);
+        for handle in handles {
+            let transfer = handle.lock().await;
+            if ensure_open(&transfer).is_err() {
+                continue;
+            }
+            let received: u64 = transfer.received_chunks.values().map(|c| c.size as u64).sum();
+            let planned = self.planned_bytes(transfer.metadata.total_size, transfer.metadata.chunk_size);
+            reserved += planned;
+            outstanding += planned.saturating_sub(received);
+            if batch_id.is_some() && transfer.metadata.batch_id.as_deref() == batch_id {
+                batch_in_flight += transfer.metadata.total_size;
+            }
+        }
+
+        if let (Some(limit), Some(batch_id)) = (limits.max_batch_size, batch_id) {
+            let completed: u64 = self
+                .completed_uploads
+                .lock()
+                .await
+                .iter()
+                .filter(|upload| upload.batch_id == batch_id)
+                .map(|upload| upload.size)
+                .sum();
+            let size = completed + batch_in_flight + total_size;
+            if size > limit {
+                return Err(TransferError::BatchTooLarge { batch_id: batch_id.to_string(), size, limit });
+            }
+        }
+
+        if let Some(quota) = limits.storage_quota {
+            let used = self.stored_bytes().await + reserved;
+            if used + planned > quota {
+                return Err(TransferError::QuotaExceeded { used, requested: planned, quota });
+            }
+        }
+
+        // Data files are sparse, so space for bytes not yet written is not held by the OS
+        let root = self.storage_path.clone();
+        let available = tokio::task::spawn_blocking(move || limits::available_space(&root))
+            .await
+            .map_err(std::io::Error::other)??;
+        let available = available.saturating_sub(outstanding);
+        if planned > available {
+            return Err(TransferError::InsufficientStorage { required: planned, available });
+        }
+
+        Ok(())
+    }
+
+    /// Bytes an upload of `total_size` can take on disk by the time it is
+    /// published: the data file that becomes the stored file, a blob of the
+    /// same size for new content, and one staged chunk.
+    fn planned_bytes(&self, total_size: u64, chunk_size: usize) -> u64 {
+        let chunk_size = chunk_size as u64;
+        let data = storage::data_file_len(self.storage_key(), total_size, chunk_size);
+        let staged = storage::data_file_len(self.storage_key(), total_size.min(chunk_size), chunk_size);
+        data.saturating_mul(2).saturating_add(staged)
+    }
+
+    /// Bytes stored under the root, daemon state included but in-flight
+    /// transfers left to their reservations. The tree is walked once; after
+    /// that the count follows what this daemon publishes and deletes.
+    async fn stored_bytes(&self) -> u64 {
+        let usage = self
+            .usage
+            .get_or_init(|| async {
+                let (root, in_flight) = (self.storage_path.clone(), self.transfers_dir());
+                let stored = tokio::task::spawn_blocking(move || limits::storage_usage(&root, &in_flight))
+                    .await
+                    .unwrap_or(0);
+                AtomicU64::new(stored)
+            })
+            .await;
+        usage.load(Ordering::Relaxed)
+    }
+
+    /// Applies a change in stored bytes to the usage count, once it is counted.
+    fn adjust_usage(&self, added: u64, removed: u64) {
+        if let Some(usage) = self.usage.get() {
+            let _ = usage.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_add(added).saturating_sub(removed))
+            });
+        }
+    }
+
+    async fn transfer(&self, transfer_id: &str) -> Result<TransferHandle, TransferError> {
+        self.transfers
+            .read()
+            .await
+            .get(transfer_id)
+            .cloned()
+            .ok_or_else(|| TransferError::TransferNotFound(transfer_id.to_string()))
+    }
+
+    fn transfers_dir(&self) -> PathBuf {
+        self.storage_path.join(STATE_DIR).join("transfers")
+    }
+
+    /// Directory holding the files exactly as batch `batch_id` received them.
+    pub fn batch_dir(&self, batch_id: &str) -> Result<PathBuf, TransferError> {
+        if !is_plain_name(batch_id) {
+            return Err(TransferError::InvalidRequest(format!("invalid batch id '{}'", batch_id)));
+        }
+        Ok(self.storage_path.join(STATE_DIR).join(history::BATCHES_DIR).join(batch_id))
+    }
+
+    /// Location of `stored_name` as batch `batch_id` received it.
+    pub fn batch_file(&self, batch_id: &str, stored_name: &str) -> Result<PathBuf, TransferError> {
+        Ok(sanitize::locate(&self.batch_dir(batch_id)?, stored_name)?)
+    }
+}
+
+/// Refuses further work on transfers that failed, were cancelled or already completed.
+fn ensure_open(transfer: &Transfer) -> Result<(), TransferError> {
+    match &transfer.metadata.status {
+        TransferStatus::Failed { reason } => Err(TransferError::TransferFailed(reason.clone())),
+        TransferStatus::Completed { .. } => Err(TransferError::TransferNotFound(transfer.metadata.id.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Length of chunk `index` within the file.
+fn chunk_len(metadata: &TransferMetadata, index: usize) -> u64 {
+    let offset = index as u64 * metadata.chunk_size as u64;
+    (metadata.total_size - offset).min(metadata.chunk_size as u64)
+}
+
+/// Copies exactly `len` bytes from `reader` into `writer`, returning their SHA-256.
+///
+/// `reader` yields decoded bytes, so a compressed chunk is checked against its original size.
+async fn write_chunk<R>(reader: &mut R, mut writer: storage::ChunkWriter, index: usize, len: u64) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    let mut size = 0u64;
+
+    loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(n) => n,
+            // Raised by a transport decoder on corrupt or truncated input
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                return Err(TransferError::ChunkDecode { index, reason: e.to_string() }.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if bytes_read == 0 {
+            break;
+        }
+        size += bytes_read as u64;
+        if size > len {
+            // Stop before spilling into the next chunk's range
+            return Err(TransferError::ChunkSizeMismatch { index, expected: len, actual: size }.into());
+        }
+        hasher.update(&buffer[..bytes_read]);
+        writer.write(&buffer[..bytes_read]).await?;
+    }
+
+    if size != len {
+        return Err(TransferError::ChunkSizeMismatch { index, expected: len, actual: size }.into());
+    }
+
+    writer.finish().await?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Copies a checked chunk from its staging file to its offset in the data file.
+async fn copy_staged_chunk(
+    staged: &Path,
+    data_path: &Path,
+    key: Option<&StorageKey>,
+    index: usize,
+    chunk_size: u64,
+) -> Result<()> {
+    let mut source = storage::open_data_file(staged, key).await?;
+    let mut writer = storage::ChunkWriter::open(data_path, key, index as u64, chunk_size).await?;
+    let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+    loop {
+        let n = source.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write(&buffer[..n]).await?;
+    }
+    writer.finish().await?;
+    Ok(())
+}
+
+/// Marks a transfer failed, durably records why, and frees its chunk data.
+///
+/// The transfer stays in the map so clients can read the reason from its status.
+async fn fail_transfer(transfer: &mut Transfer, reason: String) {
+    transfer.metadata.status = TransferStatus::Failed { reason: reason.clone() };
+    transfer.last_activity = Instant::now();
+    if let Err(e) = transfer.journal.append(&JournalRecord::Failed { reason }).await {
+        warn!("Failed to journal failure of transfer {}: {}", transfer.metadata.id, e);
+    }
+    transfer.received_chunks.clear();
+
+    let mut entries = match fs::read_dir(&transfer.dir).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name() != journal::JOURNAL_FILE {
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+/// `path` below `root` joined with `/`, or `None` if it is not valid UTF-8.
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|c| c.as_os_str().to_str()).collect();
+    Some(parts?.join("/"))
+}
+
+/// Whether a stored file still holds what `upload` published, judged by its
+/// size and by the length and modification time it was published with.
+fn matches_upload(meta: &std::fs::Metadata, logical_size: u64, upload: &CompletedUpload) -> bool {
+    let untouched = upload.stored_len == Some(meta.len())
+        && upload.stored_modified.is_some()
+        && upload.stored_modified == meta.modified().ok().map(timestamp);
+    logical_size == upload.size && untouched
+}
+
+/// A file time at full precision, as kept in the upload history.
+fn timestamp(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+}
+
+/// Removes a stored file, then any folders below `root` it leaves empty.
+async fn remove_stored(root: &Path, path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for dir in path.ancestors().skip(1).take_while(|dir| *dir != root && dir.starts_with(root)) {
+        if fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Batch a transfer's file is recorded under; standalone uploads get a batch of their own.
+fn batch_key(metadata: &TransferMetadata) -> String {
+    metadata
+        .batch_id
+        .clone()
+        .unwrap_or_else(|| format!("single_{}", metadata.id))
+}
+
+/// Whether `name` is usable verbatim as a single path component.
+fn is_plain_name(name: &str) -> bool {
+    sanitize::sanitize_filename(name).is_ok_and(|clean| clean == name)
+}
+
+fn is_sha256_hex(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Collapses the indices absent from `received` (sorted ascending) into ranges.
+fn missing_ranges(total_chunks: usize, received: impl Iterator<Item = usize>) -> Vec<ChunkRange> {
+    let mut ranges = Vec::new();
+    let mut next = 0;
+    for index in received {
+        if index > next {
+            ranges.push(ChunkRange { start: next, end: index - 1 });
+        }
+        next = index + 1;
+    }
+    if next < total_chunks {
+        ranges.push(ChunkRange { start: next, end: total_chunks - 1 });
+    }
+    ranges
+}
+
+async fn remove_transfer_dir(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir).await {
+        warn!("Failed to remove transfer directory {}: {}", dir.display(), e);
+    }
 }
 
 #[cfg(test)]
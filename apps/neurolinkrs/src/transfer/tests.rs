@@ -1,85 +1,1318 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio_test;
-
-    #[tokio::test]
-    async fn test_init_transfer_success() {
-        let manager = TransferManager::new("./test_shared");
-        let result = manager.init_transfer("test.txt".to_string(), 1024, 512, None).await;
-        assert!(result.is_ok());
-        let transfer_id = result.unwrap();
-        assert!(transfer_id.starts_with("trans_"));
-    }
-
-    #[tokio::test]
-    async fn test_init_transfer_zero_chunk_size_fails() {
-        let manager = TransferManager::new("./test_shared");
-        let result = manager.init_transfer("test.txt".to_string(), 1024, 0, None).await;
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("chunk_size must be greater than 0"));
-    }
-
-    #[tokio::test]
-    async fn test_receive_chunk_success() {
-        let manager = TransferManager::new("./test_shared");
-        let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, None).await.unwrap();
-        
-        let chunk_data = vec![0u8; 512];
-        let result = manager.receive_chunk(&transfer_id, 0, chunk_data).await;
-        assert!(result.is_ok());
-    }
-
-    #[tokio::test]
-    async fn test_receive_out_of_range_chunk_fails() {
-        let manager = TransferManager::new("./test_shared");
-        let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, None).await.unwrap();
-        // File is 1024 bytes with 512 byte chunks = 2 chunks (indices 0 and 1)
-        // Index 5 is out of range
-        let chunk_data = vec![0u8; 512];
-        let result = manager.receive_chunk(&transfer_id, 5, chunk_data).await;
-        assert!(result.is_err());
-    }
-
-    #[tokio::test]
-    async fn test_complete_transfer_with_missing_chunks_fails() {
-        let manager = TransferManager::new("./test_shared");
-        let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, None).await.unwrap();
-        // Only send 1 of 2 chunks
-        let chunk_data = vec![0u8; 512];
-        manager.receive_chunk(&transfer_id, 0, chunk_data).await.unwrap();
-        
-        // Try to complete with missing chunk
-        let result = manager.complete_transfer(&transfer_id).await;
-        assert!(result.is_err());
-    }
-
-    #[tokio::test]
-    async fn test_complete_transfer_success() {
-        let manager = TransferManager::new("./test_shared");
-        let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 1024, None).await.unwrap();
-        // Send the only chunk
-        let chunk_data = vec![0u8; 1024];
-        manager.receive_chunk(&transfer_id, 0, chunk_data).await.unwrap();
-        
-        // Complete should succeed
-        let result = manager.complete_transfer(&transfer_id).await;
-        assert!(result.is_ok());
-    }
-
-    #[tokio::test]
-    async fn test_get_transfer_status() {
-        let manager = TransferManager::new("./test_shared");
-        let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, None).await.unwrap();
-        
-        let status = manager.get_transfer_status(&transfer_id).await;
-        assert!(status.is_some());
-    }
-
-    #[tokio::test]
-    async fn test_get_nonexistent_transfer_status() {
-        let manager = TransferManager::new("./test_shared");
-        let status = manager.get_transfer_status("nonexistent").await;
-        assert!(status.is_none());
+use super::*;
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+
+fn test_manager() -> (TempDir, TransferManager) {
+    let storage = TempDir::new().unwrap();
+    let manager = TransferManager::new(storage.path());
+    (storage, manager)
+}
+
+#[tokio::test]
+async fn test_init_transfer_success() {
+    let (_storage, manager) = test_manager();
+    let result = manager.init_transfer("test.txt".to_string(), 1024, 512, TransferOptions::default()).await;
+    assert!(result.is_ok());
+    let metadata = result.unwrap();
+    assert!(metadata.id.starts_with("trans_"));
+    assert!(matches!(metadata.status, TransferStatus::Pending));
+}
+
+#[tokio::test]
+async fn test_init_transfer_zero_chunk_size_fails() {
+    let (_storage, manager) = test_manager();
+    let result = manager.init_transfer("test.txt".to_string(), 1024, 0, TransferOptions::default()).await;
+    assert!(result.is_err());
+    assert!(result.unwrap_err().to_string().contains("chunk_size must be greater than 0"));
+}
+
+#[tokio::test]
+async fn test_receive_chunk_success() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    
+    let chunk_data = vec![0u8; 512];
+    let result = manager.receive_chunk(&transfer_id, 0, &chunk_data[..], None).await;
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_receive_out_of_range_chunk_fails() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    // File is 1024 bytes with 512 byte chunks = 2 chunks (indices 0 and 1)
+    // Index 5 is out of range
+    let chunk_data = vec![0u8; 512];
+    let result = manager.receive_chunk(&transfer_id, 5, &chunk_data[..], None).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_complete_transfer_with_missing_chunks_fails() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    // Only send 1 of 2 chunks
+    let chunk_data = vec![0u8; 512];
+    manager.receive_chunk(&transfer_id, 0, &chunk_data[..], None).await.unwrap();
+    
+    // Try to complete with missing chunk
+    let result = manager.complete_transfer(&transfer_id).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_complete_transfer_success() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 1024, TransferOptions::default()).await.unwrap().id;
+    // Send the only chunk
+    let chunk_data = vec![0u8; 1024];
+    manager.receive_chunk(&transfer_id, 0, &chunk_data[..], None).await.unwrap();
+    
+    // Complete should succeed
+    let result = manager.complete_transfer(&transfer_id).await;
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_get_transfer_status() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("test.txt".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    
+    let status = manager.get_transfer_status(&transfer_id).await;
+    assert!(status.is_some());
+}
+
+#[tokio::test]
+async fn test_get_nonexistent_transfer_status() {
+    let (_storage, manager) = test_manager();
+    let status = manager.get_transfer_status("nonexistent").await;
+    assert!(status.is_none());
+}
+
+#[tokio::test]
+async fn test_recover_resumes_transfer_after_restart() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("resume.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+    drop(manager);
+
+    // A fresh manager over the same storage root stands in for a restarted daemon
+    let manager = TransferManager::new(storage.path());
+    assert_eq!(manager.recover().await.unwrap(), 1);
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::InProgress { received_chunks: 1 }));
+
+    manager.receive_chunk(&transfer_id, 1, &[2u8; 512][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap();
+
+    let data = fs::read(storage.path().join("resume.bin")).await.unwrap();
+    assert_eq!(&data[..512], &[1u8; 512][..]);
+    assert_eq!(&data[512..], &[2u8; 512][..]);
+    assert!(!storage.path().join(STATE_DIR).join("transfers").join(&transfer_id).exists());
+}
+
+#[tokio::test]
+async fn test_recover_drops_chunks_with_missing_data() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("lost.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 1, &[2u8; 512][..], None).await.unwrap();
+    drop(manager);
+
+    let transfer_dir = storage.path().join(STATE_DIR).join("transfers").join(&transfer_id);
+    fs::remove_file(transfer_dir.join(DATA_FILE)).await.unwrap();
+
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::Pending));
+
+    // The data file is recreated so the resent chunks have somewhere to go
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 1, &[2u8; 512][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_recover_finishes_transfer_that_completed_before_a_crash() {
+    for renamed in [false, true] {
+        let (storage, manager) = test_manager();
+        let transfer_id = manager.init_transfer("done.bin".to_string(), 512, 512, TransferOptions::default()).await.unwrap().id;
+        manager.receive_chunk(&transfer_id, 0, &[7u8; 512][..], None).await.unwrap();
+        let metadata = manager.get_transfer_status(&transfer_id).await.unwrap();
+        drop(manager);
+
+        // Journaled as completed, then the daemon died before or just after the rename
+        let dir = storage.path().join(STATE_DIR).join("transfers").join(&transfer_id);
+        let upload = CompletedUpload {
+            batch_id: batch_key(&metadata),
+            name: "done.bin".to_string(),
+            stored_name: "done.bin".to_string(),
+            size: 512,
+            uploaded_at: Utc::now().to_rfc3339(),
+            final_hash: None,
+            stored_len: None,
+            stored_modified: None,
+        };
+        let mut journal = Journal::open(&dir).await.unwrap();
+        journal.append(&JournalRecord::Completed { upload }).await.unwrap();
+        if renamed {
+            fs::rename(dir.join(DATA_FILE), storage.path().join("done.bin")).await.unwrap();
+        }
+
+        let manager = TransferManager::new(storage.path());
+        assert_eq!(manager.recover().await.unwrap(), 0);
+        assert!(!dir.exists());
+        assert_eq!(fs::read(storage.path().join("done.bin")).await.unwrap(), vec![7u8; 512]);
+        assert!(!storage.path().join("done (1).bin").exists());
+        assert_eq!(manager.list_upload_batches().await.len(), 1);
+
+        // A client resending the last chunk finds nothing to publish again
+        let err = manager.receive_chunk(&transfer_id, 0, &[7u8; 512][..], None).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "transfer_not_found");
+
+        let manager = TransferManager::new(storage.path());
+        manager.recover().await.unwrap();
+        assert_eq!(manager.list_upload_batches().await.len(), 1);
     }
 }
+
+#[tokio::test]
+async fn test_cancel_transfer_removes_journal() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("gone.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    manager.cancel_transfer(&transfer_id).await.unwrap();
+
+    let manager = TransferManager::new(storage.path());
+    assert_eq!(manager.recover().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_chunk_manifest_reports_missing_ranges() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("gaps.bin".to_string(), 6 * 512, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 1, &[0u8; 512][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 2, &[0u8; 512][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 4, &[0u8; 512][..], None).await.unwrap();
+
+    let manifest = manager.chunk_manifest(&transfer_id).await.unwrap();
+    let indices: Vec<usize> = manifest.received.iter().map(|c| c.index).collect();
+    assert_eq!(indices, vec![1, 2, 4]);
+    assert_eq!(manifest.missing, vec![
+        ChunkRange { start: 0, end: 0 },
+        ChunkRange { start: 3, end: 3 },
+        ChunkRange { start: 5, end: 5 },
+    ]);
+}
+
+#[test]
+fn test_missing_ranges_edges() {
+    assert_eq!(missing_ranges(3, [0, 1, 2].into_iter()), vec![]);
+    assert_eq!(missing_ranges(4, std::iter::empty()), vec![ChunkRange { start: 0, end: 3 }]);
+    assert_eq!(missing_ranges(0, std::iter::empty()), vec![]);
+}
+
+#[tokio::test]
+async fn test_receive_chunk_with_matching_hash() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("hashed.bin".to_string(), 512, 512, TransferOptions::default()).await.unwrap().id;
+    let chunk_data = vec![7u8; 512];
+    let expected = hex::encode(Sha256::digest(&chunk_data)).to_uppercase();
+
+    let hash = manager.receive_chunk(&transfer_id, 0, &chunk_data[..], Some(&expected)).await.unwrap();
+    assert_eq!(hash, expected.to_lowercase());
+}
+
+#[tokio::test]
+async fn test_receive_chunk_with_mismatched_hash_is_rejected_unwritten() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("hashed.bin".to_string(), 512, 512, TransferOptions::default()).await.unwrap().id;
+    let wrong = hex::encode(Sha256::digest(b"something else"));
+    let data_path = storage.path().join(STATE_DIR).join("transfers").join(&transfer_id).join(DATA_FILE);
+
+    let err = manager.receive_chunk(&transfer_id, 0, &[7u8; 512][..], Some(&wrong)).await.unwrap_err();
+    let err = err.downcast_ref::<TransferError>().unwrap();
+    assert_eq!(err.code(), "chunk_hash_mismatch");
+
+    let manifest = manager.chunk_manifest(&transfer_id).await.unwrap();
+    assert!(manifest.received.is_empty());
+    assert_eq!(fs::read(&data_path).await.unwrap(), vec![0u8; 512]);
+
+    // Nothing about the rejected chunk reached the journal either
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let manifest = manager.chunk_manifest(&transfer_id).await.unwrap();
+    assert!(manifest.received.is_empty());
+}
+
+#[tokio::test]
+async fn test_bad_resend_keeps_the_accepted_chunk() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("kept.bin".to_string(), 512, 512, TransferOptions::default()).await.unwrap().id;
+    let data_path = storage.path().join(STATE_DIR).join("transfers").join(&transfer_id).join(DATA_FILE);
+    let hash = manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+
+    // A resend that fails its hash, or is cut short, leaves the chunk as accepted
+    let wrong = hex::encode(Sha256::digest([2u8; 512]));
+    let err = manager.receive_chunk(&transfer_id, 0, &[3u8; 512][..], Some(&wrong)).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "chunk_hash_mismatch");
+    let err = manager.receive_chunk(&transfer_id, 0, &[3u8; 100][..], None).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "chunk_size_mismatch");
+
+    assert_eq!(fs::read(&data_path).await.unwrap(), vec![1u8; 512]);
+    let manifest = manager.chunk_manifest(&transfer_id).await.unwrap();
+    assert_eq!(manifest.received.len(), 1);
+    assert_eq!(manifest.received[0].hash, hash);
+
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    assert_eq!(manager.chunk_manifest(&transfer_id).await.unwrap().received.len(), 1);
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    assert_eq!(fs::read(storage.path().join("kept.bin")).await.unwrap(), vec![1u8; 512]);
+}
+
+#[tokio::test]
+async fn test_complete_transfer_with_expected_hash() {
+    let (storage, manager) = test_manager();
+    let data = vec![9u8; 1024];
+    let options = TransferOptions {
+        expected_hash: Some(hex::encode(Sha256::digest(&data))),
+        ..Default::default()
+    };
+    let transfer_id = manager.init_transfer("good.bin".to_string(), 1024, 1024, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &data[..], None).await.unwrap();
+
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    assert!(storage.path().join("good.bin").exists());
+}
+
+#[tokio::test]
+async fn test_failed_transfer_cannot_be_resumed() {
+    let (_storage, manager) = test_manager();
+    let options = TransferOptions {
+        expected_hash: Some(hex::encode(Sha256::digest(b"other content"))),
+        ..Default::default()
+    };
+    let transfer_id = manager.init_transfer("retry.bin".to_string(), 1024, 1024, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[9u8; 1024][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap_err();
+
+    let err = manager.chunk_manifest(&transfer_id).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "transfer_failed");
+
+    // Starting over succeeds and the new transfer is resumable
+    let retry_id = manager.init_transfer("retry.bin".to_string(), 1024, 1024, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&retry_id, 0, &[9u8; 1024][..], None).await.unwrap();
+    assert_eq!(manager.chunk_manifest(&retry_id).await.unwrap().received.len(), 1);
+    manager.complete_transfer(&retry_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_complete_transfer_with_wrong_expected_hash_fails() {
+    let (storage, manager) = test_manager();
+    let options = TransferOptions {
+        expected_hash: Some(hex::encode(Sha256::digest(b"other content"))),
+        ..Default::default()
+    };
+    let transfer_id = manager.init_transfer("bad.bin".to_string(), 1024, 1024, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[9u8; 1024][..], None).await.unwrap();
+
+    let err = manager.complete_transfer(&transfer_id).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "file_hash_mismatch");
+    assert!(!storage.path().join("bad.bin").exists());
+
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::Failed { .. }));
+
+    // The failure survives a restart
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::Failed { .. }));
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_malformed_expected_hash() {
+    let (_storage, manager) = test_manager();
+    let options = TransferOptions {
+        expected_hash: Some("not-a-hash".to_string()),
+        ..Default::default()
+    };
+    let err = manager.init_transfer("x.bin".to_string(), 1024, 1024, options).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "invalid_request");
+}
+
+#[tokio::test]
+async fn test_receive_chunk_streams_body_larger_than_buffer() {
+    use tokio::io::AsyncReadExt as _;
+
+    let (storage, manager) = test_manager();
+    let chunk_size = 3 * 1024 * 1024 + 17;
+    let transfer_id = manager.init_transfer("big.bin".to_string(), chunk_size as u64, chunk_size, TransferOptions::default()).await.unwrap().id;
+
+    let reader = tokio::io::repeat(0xAB).take(chunk_size as u64);
+    let hash = manager.receive_chunk(&transfer_id, 0, reader, None).await.unwrap();
+    assert_eq!(hash, hex::encode(Sha256::digest(vec![0xABu8; chunk_size])));
+
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    let meta = fs::metadata(storage.path().join("big.bin")).await.unwrap();
+    assert_eq!(meta.len(), chunk_size as u64);
+}
+
+#[tokio::test]
+async fn test_chunks_written_out_of_order_land_at_their_offsets() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("order.bin".to_string(), 1000, 400, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 2, &[3u8; 200][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 400][..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 1, &[2u8; 400][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap();
+
+    let data = fs::read(storage.path().join("order.bin")).await.unwrap();
+    assert_eq!(data.len(), 1000);
+    assert!(data[..400].iter().all(|&b| b == 1));
+    assert!(data[400..800].iter().all(|&b| b == 2));
+    assert!(data[800..].iter().all(|&b| b == 3));
+}
+
+#[tokio::test]
+async fn test_receive_chunk_rejects_wrong_size() {
+    let (_storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("sized.bin".to_string(), 1000, 400, TransferOptions::default()).await.unwrap().id;
+
+    let err = manager.receive_chunk(&transfer_id, 0, &[1u8; 401][..], None).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "chunk_size_mismatch");
+    let err = manager.receive_chunk(&transfer_id, 2, &[1u8; 100][..], None).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "chunk_size_mismatch");
+    assert!(manager.chunk_manifest(&transfer_id).await.unwrap().received.is_empty());
+}
+
+#[tokio::test]
+async fn test_resending_a_chunk_replaces_it() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("again.bin".to_string(), 4, 4, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &b"old!"[..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 0, &b"new!"[..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap();
+
+    assert_eq!(fs::read(storage.path().join("again.bin")).await.unwrap(), b"new!");
+}
+
+#[tokio::test]
+async fn test_empty_file_completes_without_chunks() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("empty.txt".to_string(), 0, 512, TransferOptions::default()).await.unwrap().id;
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    assert_eq!(fs::metadata(storage.path().join("empty.txt")).await.unwrap().len(), 0);
+}
+
+/// Reader that yields `data` only after `delay`, standing in for a slow client.
+fn delayed_reader(data: Vec<u8>, delay: std::time::Duration) -> tokio::io::DuplexStream {
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = writer.write_all(&data).await;
+    });
+    reader
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_parallel_chunks_of_one_file() {
+    let (storage, manager) = test_manager();
+    let chunks = 16;
+    let transfer_id = manager.init_transfer("parallel.bin".to_string(), chunks as u64 * 1024, 1024, TransferOptions::default()).await.unwrap().id;
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for index in 0..chunks {
+        let manager = manager.clone();
+        let transfer_id = transfer_id.clone();
+        tasks.spawn(async move {
+            let data = vec![index as u8; 1024];
+            manager.receive_chunk(&transfer_id, index, &data[..], None).await
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        result.unwrap().unwrap();
+    }
+
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    let data = fs::read(storage.path().join("parallel.bin")).await.unwrap();
+    for (index, chunk) in data.chunks(1024).enumerate() {
+        assert!(chunk.iter().all(|&b| b == index as u8));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_stalled_upload_does_not_block_other_transfers() {
+    let (storage, manager) = test_manager();
+    let slow_id = manager.init_transfer("slow.bin".to_string(), 2048, 1024, TransferOptions::default()).await.unwrap().id;
+
+    // Half a chunk arrives, then the client goes quiet with the connection open
+    let (mut slow_writer, slow_reader) = tokio::io::duplex(4096);
+    slow_writer.write_all(&[1u8; 512]).await.unwrap();
+    let slow_upload = {
+        let manager = manager.clone();
+        let slow_id = slow_id.clone();
+        tokio::spawn(async move { manager.receive_chunk(&slow_id, 0, slow_reader, None).await })
+    };
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let other_files = async {
+        let fast_id = manager.init_transfer("fast.bin".to_string(), 1024, 1024, TransferOptions::default()).await.unwrap().id;
+        manager.receive_chunk(&fast_id, 0, &[2u8; 1024][..], None).await.unwrap();
+        manager.complete_transfer(&fast_id).await.unwrap();
+        // Another chunk of the stalled file is not held up either
+        manager.receive_chunk(&slow_id, 1, &[3u8; 1024][..], None).await.unwrap();
+        manager.get_transfer_status(&slow_id).await.unwrap();
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), other_files)
+        .await
+        .expect("a stalled chunk upload blocked unrelated work");
+    assert!(storage.path().join("fast.bin").exists());
+
+    slow_writer.write_all(&[1u8; 512]).await.unwrap();
+    drop(slow_writer);
+    slow_upload.await.unwrap().unwrap();
+    manager.complete_transfer(&slow_id).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_uploads_scale_with_client_count() {
+    let (_storage, manager) = test_manager();
+    let clients = 8;
+    let delay = std::time::Duration::from_millis(200);
+
+    let started = std::time::Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+    for client in 0..clients {
+        let manager = manager.clone();
+        tasks.spawn(async move {
+            let transfer_id = manager
+                .init_transfer(format!("client_{}.bin", client), 4096, 4096, TransferOptions::default())
+                .await?
+                .id;
+            let reader = delayed_reader(vec![client as u8; 4096], delay);
+            manager.receive_chunk(&transfer_id, 0, reader, None).await?;
+            manager.complete_transfer(&transfer_id).await
+        });
+    }
+    let mut ids = HashSet::new();
+    while let Some(result) = tasks.join_next().await {
+        ids.insert(result.unwrap().unwrap().id);
+    }
+    let elapsed = started.elapsed();
+
+    // Serialized uploads would need clients * delay; parallel ones need about one delay
+    assert_eq!(ids.len(), clients);
+    assert!(
+        elapsed < delay * (clients as u32) / 2,
+        "{} uploads took {:?}, expected them to overlap",
+        clients,
+        elapsed
+    );
+}
+
+fn limited_manager(limits: TransferLimits) -> (TempDir, TransferManager) {
+    let storage = TempDir::new().unwrap();
+    let manager = TransferManager::new(storage.path()).with_config(TransferConfig { limits, ..Default::default() });
+    (storage, manager)
+}
+
+fn limit_code(err: anyhow::Error) -> &'static str {
+    err.downcast_ref::<TransferError>().unwrap().code()
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_file_over_size_limit() {
+    let (_storage, manager) = limited_manager(TransferLimits { max_file_size: Some(1024), ..Default::default() });
+    assert!(manager.init_transfer("ok.bin".to_string(), 1024, 512, TransferOptions::default()).await.is_ok());
+
+    let err = manager.init_transfer("big.bin".to_string(), 1025, 512, TransferOptions::default()).await.unwrap_err();
+    assert_eq!(limit_code(err), "file_too_large");
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_batch_over_size_limit() {
+    let (_storage, manager) = limited_manager(TransferLimits { max_batch_size: Some(2048), ..Default::default() });
+    let batch = || TransferOptions { batch_id: Some("batch_1".to_string()), ..Default::default() };
+
+    // One completed and one in-flight file both count towards the batch
+    let done = manager.init_transfer("a.bin".to_string(), 512, 512, batch()).await.unwrap().id;
+    manager.receive_chunk(&done, 0, &[1u8; 512][..], None).await.unwrap();
+    manager.complete_transfer(&done).await.unwrap();
+    manager.init_transfer("b.bin".to_string(), 1024, 512, batch()).await.unwrap();
+
+    let err = manager.init_transfer("c.bin".to_string(), 1024, 512, batch()).await.unwrap_err();
+    assert_eq!(limit_code(err), "batch_too_large");
+
+    // Other batches are unaffected
+    let other = TransferOptions { batch_id: Some("batch_2".to_string()), ..Default::default() };
+    assert!(manager.init_transfer("c.bin".to_string(), 1024, 512, other).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_quota_overrun() {
+    let (storage, manager) = limited_manager(TransferLimits { storage_quota: Some(8192), ..Default::default() });
+    std::fs::write(storage.path().join("existing.bin"), vec![0u8; 2048]).unwrap();
+
+    // Each upload reserves its data file, a blob of new content and a staged chunk
+    manager.init_transfer("a.bin".to_string(), 1024, 1024, TransferOptions::default()).await.unwrap();
+    let err = manager.init_transfer("b.bin".to_string(), 1025, 1025, TransferOptions::default()).await.unwrap_err();
+    assert_eq!(limit_code(err), "quota_exceeded");
+
+    assert!(manager.init_transfer("b.bin".to_string(), 1024, 1024, TransferOptions::default()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_quota_follows_published_and_deleted_files() {
+    let limits = TransferLimits { storage_quota: Some(12288), ..Default::default() };
+    let (storage, manager) = limited_manager(limits.clone());
+    let id = manager.init_transfer("a.bin".to_string(), 2048, 2048, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&id, 0, &[1u8; 2048][..], None).await.unwrap();
+    manager.complete_transfer(&id).await.unwrap();
+
+    // The stored file and its blob count, the batch copy linked to the blob does not,
+    // and an upload reserves three times its size
+    let err = manager.init_transfer("b.bin".to_string(), 2731, 2731, TransferOptions::default()).await.unwrap_err();
+    assert_eq!(limit_code(err), "quota_exceeded");
+    let id = manager.init_transfer("b.bin".to_string(), 2730, 2730, TransferOptions::default()).await.unwrap().id;
+    manager.cancel_transfer(&id).await.unwrap();
+
+    // A restart counts the state directory the same way
+    let reloaded = TransferManager::new(storage.path()).with_config(TransferConfig { limits, ..Default::default() });
+    let err = reloaded.init_transfer("b.bin".to_string(), 2731, 2731, TransferOptions::default()).await.unwrap_err();
+    assert_eq!(limit_code(err), "quota_exceeded");
+
+    // Counted once at the first check; later files are tracked without walking the tree again
+    std::fs::write(storage.path().join("outside.bin"), vec![0u8; 2048]).unwrap();
+    manager.delete_shared(&ShareTarget::File("a.bin".to_string())).await.unwrap();
+    assert!(manager.init_transfer("b.bin".to_string(), 4096, 4096, TransferOptions::default()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_more_than_free_space() {
+    let (_storage, manager) = test_manager();
+    let err = manager.init_transfer("huge.bin".to_string(), u64::MAX / 2, 1 << 20, TransferOptions::default()).await.unwrap_err();
+    assert_eq!(limit_code(err), "insufficient_storage");
+}
+
+fn manager_with_timeouts(idle_timeout: Duration, failed_retention: Duration) -> (TempDir, TransferManager) {
+    let storage = TempDir::new().unwrap();
+    let config = TransferConfig { idle_timeout, failed_retention, ..Default::default() };
+    let manager = TransferManager::new(storage.path()).with_config(config);
+    (storage, manager)
+}
+
+#[tokio::test]
+async fn test_reaper_expires_idle_transfer_and_keeps_status() {
+    let (storage, manager) = manager_with_timeouts(Duration::ZERO, Duration::from_secs(3600));
+    let transfer_id = manager.init_transfer("idle.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+
+    assert_eq!(manager.reap_expired().await, 1);
+
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::Failed { ref reason } if reason == "expired"));
+    let transfer_dir = storage.path().join(STATE_DIR).join("transfers").join(&transfer_id);
+    assert!(!transfer_dir.join(DATA_FILE).exists());
+
+    let err = manager.receive_chunk(&transfer_id, 1, &[1u8; 512][..], None).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "transfer_failed");
+
+    // Already failed transfers are not expired twice
+    assert_eq!(manager.reap_expired().await, 0);
+}
+
+#[tokio::test]
+async fn test_reaper_forgets_failed_transfer_after_retention() {
+    let (storage, manager) = manager_with_timeouts(Duration::ZERO, Duration::ZERO);
+    let transfer_id = manager.init_transfer("idle.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+
+    assert_eq!(manager.reap_expired().await, 1);
+    assert!(manager.get_transfer_status(&transfer_id).await.is_some());
+
+    manager.reap_expired().await;
+    assert!(manager.get_transfer_status(&transfer_id).await.is_none());
+    assert!(!storage.path().join(STATE_DIR).join("transfers").join(&transfer_id).exists());
+}
+
+#[tokio::test]
+async fn test_reaper_leaves_active_transfers_alone() {
+    let (_storage, manager) = manager_with_timeouts(Duration::from_secs(3600), Duration::ZERO);
+    let transfer_id = manager.init_transfer("busy.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+
+    assert_eq!(manager.reap_expired().await, 0);
+    assert!(matches!(
+        manager.get_transfer_status(&transfer_id).await.unwrap().status,
+        TransferStatus::Pending
+    ));
+}
+
+#[tokio::test]
+async fn test_reaper_skips_transfer_with_chunk_in_flight() {
+    let (_storage, manager) = manager_with_timeouts(Duration::ZERO, Duration::from_secs(3600));
+    let transfer_id = manager.init_transfer("slow.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+
+    let (mut client, server) = tokio::io::duplex(64);
+    let upload = {
+        let manager = manager.clone();
+        let transfer_id = transfer_id.clone();
+        tokio::spawn(async move { manager.receive_chunk(&transfer_id, 0, server, None).await })
+    };
+    client.write_all(&[1u8; 16]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(manager.reap_expired().await, 0);
+
+    client.write_all(&[1u8; 496]).await.unwrap();
+    drop(client);
+    upload.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_traversal_filename() {
+    let (_storage, manager) = test_manager();
+    for name in ["../../.bashrc", "/etc/passwd", ".neurolink"] {
+        let err = manager.init_transfer(name.to_string(), 512, 512, TransferOptions::default()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "invalid_filename", "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn test_completed_file_uses_sanitized_name() {
+    let (storage, manager) = test_manager();
+    let transfer_id = manager.init_transfer("CON.txt. ".to_string(), 512, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+
+    let metadata = manager.complete_transfer(&transfer_id).await.unwrap();
+    assert_eq!(metadata.filename, "_CON.txt");
+    assert!(storage.path().join("_CON.txt").exists());
+    // Reads use the stored name as it is
+    assert_eq!(manager.read_file("_CON.txt").await.unwrap(), vec![1u8; 512]);
+    assert!(manager.read_file("CON.txt").await.is_err());
+    assert!(manager.read_file("../_CON.txt").await.is_err());
+}
+
+#[tokio::test]
+async fn test_folder_upload_preserves_tree() {
+    let (storage, manager) = test_manager();
+    for (path, byte) in [("site/a/readme.md", 1u8), ("site/b/readme.md", 2u8)] {
+        let options = TransferOptions { relative_path: Some(path.to_string()), ..Default::default() };
+        let transfer_id = manager.init_transfer("readme.md".to_string(), 512, 512, options).await.unwrap().id;
+        manager.receive_chunk(&transfer_id, 0, &[byte; 512][..], None).await.unwrap();
+        let metadata = manager.complete_transfer(&transfer_id).await.unwrap();
+        assert_eq!(metadata.filename, path);
+    }
+
+    assert_eq!(std::fs::read(storage.path().join("site/a/readme.md")).unwrap(), vec![1u8; 512]);
+    assert_eq!(std::fs::read(storage.path().join("site/b/readme.md")).unwrap(), vec![2u8; 512]);
+
+    // Daemon state stays out of the listing
+    manager.init_transfer("pending.bin".to_string(), 512, 512, TransferOptions::default()).await.unwrap();
+    let mut names: Vec<String> = manager.list_files().await.unwrap().into_iter().map(|f| f.name).collect();
+    names.sort();
+    assert_eq!(names, vec!["site/a/readme.md", "site/b/readme.md"]);
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_traversal_relative_path() {
+    let (_storage, manager) = test_manager();
+    let options = TransferOptions { relative_path: Some("site/../../escape.txt".to_string()), ..Default::default() };
+    let err = manager.init_transfer("escape.txt".to_string(), 512, 512, options).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "invalid_filename");
+}
+
+async fn upload_with_policy(manager: &TransferManager, name: &str, byte: u8, policy: Option<ConflictPolicy>) -> Result<TransferMetadata> {
+    let options = TransferOptions { conflict_policy: policy, ..Default::default() };
+    let transfer_id = manager.init_transfer(name.to_string(), 512, 512, options).await?.id;
+    manager.receive_chunk(&transfer_id, 0, &[byte; 512][..], None).await?;
+    manager.complete_transfer(&transfer_id).await
+}
+
+#[tokio::test]
+async fn test_conflict_rename_numbers_new_uploads() {
+    let (storage, manager) = test_manager();
+    let manager = manager.with_config(TransferConfig { conflict_policy: ConflictPolicy::Rename, ..Default::default() });
+    for (byte, expected) in [(1u8, "photo.jpg"), (2, "photo (1).jpg"), (3, "photo (2).jpg")] {
+        let metadata = upload_with_policy(&manager, "photo.jpg", byte, None).await.unwrap();
+        assert_eq!(metadata.stored_name.as_deref(), Some(expected));
+        assert_eq!(std::fs::read(storage.path().join(expected)).unwrap(), vec![byte; 512]);
+    }
+
+    let metadata = upload_with_policy(&manager, ".env", 1, None).await.unwrap();
+    assert_eq!(metadata.stored_name.as_deref(), Some(".env"));
+    let metadata = upload_with_policy(&manager, ".env", 2, None).await.unwrap();
+    assert_eq!(metadata.stored_name.as_deref(), Some(".env (1)"));
+
+    let stored: Vec<String> = manager.files_for_batch(&format!("single_{}", metadata.id)).await
+        .into_iter().map(|f| f.stored_name).collect();
+    assert_eq!(stored, vec![".env (1)"]);
+}
+
+#[tokio::test]
+async fn test_conflict_overwrite_is_the_default() {
+    let (storage, manager) = test_manager();
+    upload_with_policy(&manager, "a.bin", 1, None).await.unwrap();
+    let metadata = upload_with_policy(&manager, "a.bin", 2, None).await.unwrap();
+    assert_eq!(metadata.stored_name.as_deref(), Some("a.bin"));
+    assert_eq!(std::fs::read(storage.path().join("a.bin")).unwrap(), vec![2u8; 512]);
+}
+
+#[tokio::test]
+async fn test_conflict_version_keeps_previous_copy() {
+    let (storage, manager) = test_manager();
+    let manager = manager.with_config(TransferConfig { conflict_policy: ConflictPolicy::Version, ..Default::default() });
+    for byte in 1..=3u8 {
+        upload_with_policy(&manager, "notes.txt", byte, None).await.unwrap();
+    }
+    assert_eq!(std::fs::read(storage.path().join("notes.txt")).unwrap(), vec![3u8; 512]);
+    assert_eq!(std::fs::read(storage.path().join("notes.txt.~1~")).unwrap(), vec![1u8; 512]);
+    assert_eq!(std::fs::read(storage.path().join("notes.txt.~2~")).unwrap(), vec![2u8; 512]);
+}
+
+#[tokio::test]
+async fn test_conflict_version_leaves_the_file_in_place_when_publishing_fails() {
+    let (storage, manager) = test_manager();
+    let manager = manager.with_config(TransferConfig { conflict_policy: ConflictPolicy::Version, ..Default::default() });
+    upload_with_policy(&manager, "notes.txt", 1, None).await.unwrap();
+
+    // A directory where the batch copy should go stops the next upload partway
+    let options = TransferOptions { batch_id: Some("batch_b".to_string()), ..Default::default() };
+    let transfer_id = manager.init_transfer("notes.txt".to_string(), 512, 512, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[2u8; 512][..], None).await.unwrap();
+    let blocker = manager.batch_dir("batch_b").unwrap().join("notes.txt");
+    std::fs::create_dir_all(blocker.join("inside")).unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap_err();
+
+    assert_eq!(std::fs::read(storage.path().join("notes.txt")).unwrap(), vec![1u8; 512]);
+    assert!(!storage.path().join("notes.txt.~1~").exists());
+
+    std::fs::remove_dir_all(&blocker).unwrap();
+    upload_with_policy(&manager, "notes.txt", 3, None).await.unwrap();
+    assert_eq!(std::fs::read(storage.path().join("notes.txt")).unwrap(), vec![3u8; 512]);
+    assert_eq!(std::fs::read(storage.path().join("notes.txt.~1~")).unwrap(), vec![1u8; 512]);
+}
+
+#[tokio::test]
+async fn test_conflict_reject_refuses_existing_name() {
+    let (storage, manager) = test_manager();
+    upload_with_policy(&manager, "a.bin", 1, None).await.unwrap();
+
+    let err = upload_with_policy(&manager, "a.bin", 2, Some(ConflictPolicy::Reject)).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "file_exists");
+
+    // A file that appears while the upload is in flight fails it at completion
+    let options = TransferOptions { conflict_policy: Some(ConflictPolicy::Reject), ..Default::default() };
+    let transfer_id = manager.init_transfer("b.bin".to_string(), 512, 512, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[2u8; 512][..], None).await.unwrap();
+    std::fs::write(storage.path().join("b.bin"), b"other").unwrap();
+
+    let err = manager.complete_transfer(&transfer_id).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "file_exists");
+    assert_eq!(std::fs::read(storage.path().join("b.bin")).unwrap(), b"other");
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::Failed { .. }));
+}
+
+async fn upload_to_batch(manager: &TransferManager, batch_id: &str, name: &str, byte: u8) -> TransferMetadata {
+    let options = TransferOptions {
+        batch_id: Some(batch_id.to_string()),
+        conflict_policy: Some(ConflictPolicy::Overwrite),
+        ..Default::default()
+    };
+    let transfer_id = manager.init_transfer(name.to_string(), 512, 512, options).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[byte; 512][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_batches_keep_their_own_bytes_after_overwrite() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_b", "a.txt", 2).await;
+
+    assert_eq!(std::fs::read(storage.path().join("a.txt")).unwrap(), vec![2u8; 512]);
+    assert_eq!(std::fs::read(manager.batch_file("batch_a", "a.txt").unwrap()).unwrap(), vec![1u8; 512]);
+    assert_eq!(std::fs::read(manager.batch_file("batch_b", "a.txt").unwrap()).unwrap(), vec![2u8; 512]);
+}
+
+#[tokio::test]
+async fn test_upload_history_survives_restart() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_a", "b.txt", 2).await;
+    drop(manager);
+
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let files: Vec<String> = manager.files_for_batch("batch_a").await.into_iter().map(|f| f.stored_name).collect();
+    assert_eq!(files, vec!["a.txt", "b.txt"]);
+}
+
+#[tokio::test]
+async fn test_restart_recounts_blobs_from_the_upload_history() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_b", "b.txt", 2).await;
+    // Crashes between the history and the dedup index: a.txt's blob counted
+    // twice, and b.txt's upload gone from the history but still counted
+    let hash_a = hex::encode(Sha256::digest([1u8; 512]));
+    let hash_b = hex::encode(Sha256::digest([2u8; 512]));
+    manager.dedup.record(&hash_a, 512).await;
+    let kept: Vec<CompletedUpload> = manager.completed_uploads.lock().await.iter()
+        .filter(|upload| upload.batch_id == "batch_a").cloned().collect();
+    history::rewrite(&storage.path().join(STATE_DIR), &kept).await.unwrap();
+    assert_eq!(manager.dedup_stats().await.references, 3);
+    drop(manager);
+
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let stats = manager.dedup_stats().await;
+    assert_eq!((stats.blobs, stats.references), (1, 1));
+    assert!(manager.dedup.blob_path(&hash_a).exists());
+    assert!(!manager.dedup.blob_path(&hash_b).exists());
+
+    // Counted once more, and released to nothing, by the next uploads and deletes
+    upload_to_batch(&manager, "batch_c", "c.txt", 1).await;
+    assert_eq!(manager.dedup_stats().await.references, 2);
+    manager.delete_shared(&ShareTarget::Batch("batch_a".to_string())).await.unwrap();
+    manager.delete_shared(&ShareTarget::Batch("batch_c".to_string())).await.unwrap();
+    assert!(!manager.dedup.blob_path(&hash_a).exists());
+}
+
+#[tokio::test]
+async fn test_upload_hashes_are_kept_and_listed() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_a", "b.txt", 2).await;
+    // History written before hashes were kept still loads, without one
+    let legacy = r#"{"batch_id":"batch_old","name":"old.txt","stored_name":"old.txt","size":3,"uploaded_at":"2024-01-01T00:00:00+00:00"}"#;
+    let history = storage.path().join(STATE_DIR).join(history::HISTORY_FILE);
+    let mut lines = std::fs::read_to_string(&history).unwrap();
+    lines.push_str(legacy);
+    lines.push('\n');
+    std::fs::write(&history, lines).unwrap();
+    std::fs::write(storage.path().join("old.txt"), b"old").unwrap();
+    drop(manager);
+
+    let manager = TransferManager::new(storage.path());
+    manager.recover().await.unwrap();
+    let hash_a = hex::encode(Sha256::digest([1u8; 512]));
+    let hashes: Vec<Option<String>> = manager.files_for_batch("batch_a").await.into_iter().map(|f| f.final_hash).collect();
+    assert_eq!(hashes, vec![Some(hash_a.clone()), Some(hex::encode(Sha256::digest([2u8; 512])))]);
+    assert_eq!(manager.files_for_batch("batch_old").await[0].final_hash, None);
+
+    // A shared file edited after its upload no longer claims that upload's hash
+    let b = storage.path().join("b.txt");
+    std::fs::write(&b, [3u8; 512]).unwrap();
+    let later = std::time::SystemTime::now() + Duration::from_secs(60);
+    std::fs::File::options().write(true).open(&b).unwrap().set_modified(later).unwrap();
+
+    let listed: HashMap<String, Option<String>> = manager
+        .list_files()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.name, f.final_hash))
+        .collect();
+    assert_eq!(listed["a.txt"], Some(hash_a));
+    assert_eq!(listed["b.txt"], None);
+    assert_eq!(listed["old.txt"], None);
+}
+
+#[tokio::test]
+async fn test_init_transfer_rejects_unsafe_batch_id() {
+    let (_storage, manager) = test_manager();
+    for batch_id in ["../escape", "a/b", ""] {
+        let options = TransferOptions { batch_id: Some(batch_id.to_string()), ..Default::default() };
+        let err = manager.init_transfer("a.txt".to_string(), 512, 512, options).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "invalid_request", "{}", batch_id);
+    }
+    assert!(manager.batch_file("..", "a.txt").is_err());
+    assert!(manager.batch_file("batch_a", "../../a.txt").is_err());
+}
+
+#[tokio::test]
+async fn test_duplicate_uploads_share_one_blob() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "Shinchan-freinds.PNG", 7).await;
+    upload_to_batch(&manager, "batch_a", "Shinchan-freinds.png", 7).await;
+    upload_to_batch(&manager, "batch_a", "other.png", 8).await;
+
+    let stats = manager.dedup_stats().await;
+    assert_eq!((stats.blobs, stats.references, stats.saved_bytes), (2, 3, 512));
+
+    // Overwriting one name leaves the other copy and the blob intact
+    upload_to_batch(&manager, "batch_b", "Shinchan-freinds.png", 9).await;
+    assert_eq!(std::fs::read(storage.path().join("Shinchan-freinds.PNG")).unwrap(), vec![7u8; 512]);
+
+    // So does editing a stored file in place
+    std::fs::write(storage.path().join("Shinchan-freinds.PNG"), vec![6u8; 512]).unwrap();
+    assert_eq!(std::fs::read(manager.batch_file("batch_a", "Shinchan-freinds.PNG").unwrap()).unwrap(), vec![7u8; 512]);
+    let options = TransferOptions { expected_hash: Some(hex::encode(Sha256::digest([7u8; 512]))), ..Default::default() };
+    let copy = manager.init_transfer("copy.png".to_string(), 512, 512, options).await.unwrap();
+    assert!(matches!(copy.status, TransferStatus::Completed { .. }));
+    assert_eq!(std::fs::read(storage.path().join("copy.png")).unwrap(), vec![7u8; 512]);
+}
+
+#[tokio::test]
+async fn test_dedup_existing_stores_content_and_leaves_files_alone() {
+    let (storage, manager) = test_manager();
+    std::fs::write(storage.path().join("a.png"), vec![3u8; 2048]).unwrap();
+    std::fs::create_dir_all(storage.path().join("copies")).unwrap();
+    std::fs::write(storage.path().join("copies/a.png"), vec![3u8; 2048]).unwrap();
+    std::fs::write(storage.path().join("b.png"), vec![4u8; 2048]).unwrap();
+
+    let blobs = storage.path().join(STATE_DIR).join(hashing::BLOBS_DIR);
+    std::fs::create_dir_all(&blobs).unwrap();
+    if !hashing::reflinks_supported(&blobs).await {
+        // A full copy of every file would only cost space
+        assert_eq!(manager.dedup_existing().await.unwrap(), 0);
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 0);
+        return;
+    }
+
+    assert_eq!(manager.dedup_existing().await.unwrap(), 2);
+    assert!(!same_file::is_same_file(storage.path().join("a.png"), storage.path().join("copies/a.png")).unwrap());
+
+    // A second pass finds nothing new to fold in
+    assert_eq!(manager.dedup_existing().await.unwrap(), 0);
+
+    // Editing a user's file neither reaches the store nor lets it serve the edit
+    std::fs::write(storage.path().join("a.png"), vec![9u8; 2048]).unwrap();
+    assert_eq!(std::fs::read(storage.path().join("copies/a.png")).unwrap(), vec![3u8; 2048]);
+    let options = TransferOptions { expected_hash: Some(hex::encode(Sha256::digest([3u8; 2048]))), ..Default::default() };
+    let copy = manager.init_transfer("c.png".to_string(), 2048, 2048, options).await.unwrap();
+    assert!(matches!(copy.status, TransferStatus::Completed { .. }));
+    assert_eq!(std::fs::read(storage.path().join("c.png")).unwrap(), vec![3u8; 2048]);
+}
+
+#[tokio::test]
+async fn test_init_with_known_hash_completes_without_chunks() {
+    let (storage, manager) = test_manager();
+    let first = upload_to_batch(&manager, "batch_a", "movie.mkv", 5).await;
+    let TransferStatus::Completed { final_hash } = first.status else { panic!("not completed") };
+
+    let options = TransferOptions {
+        batch_id: Some("batch_b".to_string()),
+        expected_hash: Some(final_hash.to_ascii_uppercase()),
+        ..Default::default()
+    };
+    let metadata = manager.init_transfer("copy.mkv".to_string(), 512, 512, options).await.unwrap();
+
+    assert!(matches!(metadata.status, TransferStatus::Completed { final_hash: ref h } if *h == final_hash));
+    assert_eq!(metadata.stored_name.as_deref(), Some("copy.mkv"));
+    assert_eq!(std::fs::read(storage.path().join("copy.mkv")).unwrap(), vec![5u8; 512]);
+    assert_eq!(std::fs::read(manager.batch_file("batch_b", "copy.mkv").unwrap()).unwrap(), vec![5u8; 512]);
+    assert!(manager.get_transfer_status(&metadata.id).await.is_none());
+    assert_eq!(manager.dedup_stats().await.references, 2);
+}
+
+#[tokio::test]
+async fn test_init_with_unknown_or_mismatched_size_hash_uploads_normally() {
+    let (_storage, manager) = test_manager();
+    let first = upload_to_batch(&manager, "batch_a", "movie.mkv", 5).await;
+    let TransferStatus::Completed { final_hash } = first.status else { panic!("not completed") };
+
+    // Same hash but a different declared size is not trusted
+    let options = TransferOptions { expected_hash: Some(final_hash), ..Default::default() };
+    let metadata = manager.init_transfer("copy.mkv".to_string(), 1024, 512, options).await.unwrap();
+    assert!(matches!(metadata.status, TransferStatus::Pending));
+
+    let options = TransferOptions { expected_hash: Some("ab".repeat(32)), ..Default::default() };
+    let metadata = manager.init_transfer("new.mkv".to_string(), 512, 512, options).await.unwrap();
+    assert!(matches!(metadata.status, TransferStatus::Pending));
+}
+
+#[tokio::test]
+async fn test_init_with_known_hash_checks_the_blob_first() {
+    let (storage, manager) = test_manager();
+    let first = upload_to_batch(&manager, "batch_a", "movie.mkv", 5).await;
+    let TransferStatus::Completed { final_hash } = first.status else { panic!("not completed") };
+    let blob = storage.path().join(STATE_DIR).join(hashing::BLOBS_DIR).join(&final_hash[..2]).join(&final_hash);
+    let mut permissions = std::fs::metadata(&blob).unwrap().permissions();
+    hashing::make_writable(&mut permissions);
+    std::fs::set_permissions(&blob, permissions).unwrap();
+    std::fs::write(&blob, vec![6u8; 512]).unwrap();
+
+    let options = TransferOptions { expected_hash: Some(final_hash), ..Default::default() };
+    let metadata = manager.init_transfer("copy.mkv".to_string(), 512, 512, options).await.unwrap();
+    assert!(matches!(metadata.status, TransferStatus::Pending));
+
+    // Uploading the content for real repairs the blob
+    manager.receive_chunk(&metadata.id, 0, &[5u8; 512][..], None).await.unwrap();
+    manager.complete_transfer(&metadata.id).await.unwrap();
+    assert_eq!(std::fs::read(&blob).unwrap(), vec![5u8; 512]);
+    assert_eq!(std::fs::read(storage.path().join("copy.mkv")).unwrap(), vec![5u8; 512]);
+}
+
+#[tokio::test]
+async fn test_zstd_chunk_is_decoded_and_hashed_uncompressed() {
+    let (storage, manager) = test_manager();
+    // Larger than the stream buffer on both sides, so the decoder refills and drains repeatedly
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i / 1000) as u8).collect();
+    let compressed = zstd::bulk::compress(&data, 3).unwrap();
+    assert!(compressed.len() < data.len());
+    let size = data.len() as u64;
+    let transfer_id = manager.init_transfer("packed.bin".to_string(), size, data.len(), TransferOptions::default()).await.unwrap().id;
+
+    let reader = ChunkEncoding::Zstd.decode(&compressed[..]).unwrap();
+    let expected = hex::encode(Sha256::digest(&data));
+    let hash = manager.receive_chunk(&transfer_id, 0, reader, Some(&expected)).await.unwrap();
+    assert_eq!(hash, expected);
+
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    assert_eq!(std::fs::read(storage.path().join("packed.bin")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_corrupt_or_truncated_zstd_chunk_is_rejected() {
+    let (_storage, manager) = test_manager();
+    let data = vec![5u8; 4096];
+    let compressed = zstd::bulk::compress(&data, 3).unwrap();
+    let transfer_id = manager.init_transfer("broken.bin".to_string(), 4096, 4096, TransferOptions::default()).await.unwrap().id;
+
+    let truncated = &compressed[..compressed.len() - 4];
+    let garbage = b"definitely not a zstd frame";
+    for body in [truncated, &garbage[..]] {
+        let reader = ChunkEncoding::Zstd.decode(body).unwrap();
+        let err = manager.receive_chunk(&transfer_id, 0, reader, None).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<TransferError>().unwrap().code(), "chunk_decode_failed");
+    }
+
+    let manifest = manager.chunk_manifest(&transfer_id).await.unwrap();
+    assert!(manifest.received.is_empty());
+}
+
+#[test]
+fn test_chunk_encoding_parses_known_names_only() {
+    assert_eq!("ZSTD".parse::<ChunkEncoding>().unwrap(), ChunkEncoding::Zstd);
+    assert_eq!("identity".parse::<ChunkEncoding>().unwrap(), ChunkEncoding::Identity);
+    assert!("gzip".parse::<ChunkEncoding>().is_err());
+}
+
+#[tokio::test]
+async fn test_compress_at_rest_keeps_logical_view() {
+    let storage = TempDir::new().unwrap();
+    let config = TransferConfig { compress_at_rest: true, ..Default::default() };
+    let manager = TransferManager::new(storage.path()).with_config(config);
+    let data: Vec<u8> = b"GET /index.html 200\n".iter().copied().cycle().take(64 * 1024).collect();
+    let size = data.len() as u64;
+
+    let transfer_id = manager.init_transfer("access.log".to_string(), size, data.len(), TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &data[..], None).await.unwrap();
+    let metadata = manager.complete_transfer(&transfer_id).await.unwrap();
+    let TransferStatus::Completed { final_hash } = metadata.status else { panic!("not completed") };
+
+    let files = manager.list_files().await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].size, size);
+    assert!(files[0].physical_size < size / 10);
+    assert_eq!(manager.read_file("access.log").await.unwrap(), data);
+
+    // The compressed blob still answers to the content's size for instant uploads
+    let options = TransferOptions { expected_hash: Some(final_hash), ..Default::default() };
+    let copy = manager.init_transfer("copy.log".to_string(), size, data.len(), options).await.unwrap();
+    assert!(matches!(copy.status, TransferStatus::Completed { .. }));
+    assert_eq!(manager.read_file("copy.log").await.unwrap(), data);
+    assert_eq!(manager.dedup_existing().await.unwrap(), 0);
+}
+
+fn encrypted_config() -> TransferConfig {
+    TransferConfig {
+        encryption_key: Some(StorageKey::from_bytes(&[5u8; 32]).unwrap()),
+        ..Default::default()
+    }
+}
+
+/// Content that begins exactly like a compressed-at-rest or an encrypted file.
+fn magic_prefixed_contents() -> Vec<Vec<u8>> {
+    let mut compressed = 0x184D_2A5Eu32.to_le_bytes().to_vec();
+    compressed.extend_from_slice(&12u32.to_le_bytes());
+    compressed.extend_from_slice(b"NLZ1");
+    compressed.extend_from_slice(&7u64.to_le_bytes());
+    let mut encrypted = b"NLENC01\0".to_vec();
+    encrypted.extend_from_slice(&[1u8; 16]);
+    encrypted.extend_from_slice(&7u64.to_le_bytes());
+    encrypted.extend_from_slice(&512u64.to_le_bytes());
+    [compressed, encrypted]
+        .into_iter()
+        .map(|mut data| {
+            data.extend(std::iter::repeat_n(b'x', 1000 - data.len()));
+            data
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_upload_starting_with_a_storage_header_round_trips() {
+    for config in [TransferConfig::default(), encrypted_config(), TransferConfig { compress_at_rest: true, ..Default::default() }] {
+        for data in magic_prefixed_contents() {
+            let storage = TempDir::new().unwrap();
+            let manager = TransferManager::new(storage.path()).with_config(config.clone());
+            let transfer_id = manager.init_transfer("odd.bin".to_string(), 1000, 512, TransferOptions::default()).await.unwrap().id;
+            manager.receive_chunk(&transfer_id, 0, &data[..512], None).await.unwrap();
+            manager.receive_chunk(&transfer_id, 1, &data[512..], None).await.unwrap();
+            let metadata = manager.complete_transfer(&transfer_id).await.unwrap();
+
+            let TransferStatus::Completed { final_hash } = metadata.status else { panic!("not completed") };
+            assert_eq!(final_hash, hex::encode(Sha256::digest(&data)));
+            assert_eq!(manager.read_file("odd.bin").await.unwrap(), data);
+            assert_eq!(manager.list_files().await.unwrap()[0].size, 1000);
+
+            // The blob it left behind serves instant uploads of the same bytes
+            let options = TransferOptions { expected_hash: Some(final_hash), ..Default::default() };
+            let copy = manager.init_transfer("copy.bin".to_string(), 1000, 512, options).await.unwrap();
+            assert!(matches!(copy.status, TransferStatus::Completed { .. }));
+            assert_eq!(manager.read_file("copy.bin").await.unwrap(), data);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_upload_stays_sealed_across_restart() {
+    let storage = TempDir::new().unwrap();
+    let manager = TransferManager::new(storage.path()).with_config(encrypted_config());
+    let data: Vec<u8> = (0..1300u32).map(|i| (i % 251) as u8).collect();
+    let transfer_id = manager.init_transfer("secret.bin".to_string(), 1300, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 2, &data[1024..], None).await.unwrap();
+    manager.receive_chunk(&transfer_id, 0, &data[..512], None).await.unwrap();
+    drop(manager);
+
+    let manager = TransferManager::new(storage.path()).with_config(encrypted_config());
+    assert_eq!(manager.recover().await.unwrap(), 1);
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::InProgress { received_chunks: 2 }));
+    manager.receive_chunk(&transfer_id, 1, &data[512..1024], None).await.unwrap();
+    let metadata = manager.complete_transfer(&transfer_id).await.unwrap();
+
+    let TransferStatus::Completed { final_hash } = metadata.status else { panic!("not completed") };
+    assert_eq!(final_hash, hex::encode(Sha256::digest(&data)));
+    let raw = std::fs::read(storage.path().join("secret.bin")).unwrap();
+    assert!(!raw.windows(64).any(|w| w == &data[..64]));
+    assert_eq!(manager.read_file("secret.bin").await.unwrap(), data);
+    assert_eq!(manager.list_files().await.unwrap()[0].size, 1300);
+
+    // Without the key the file cannot be read back
+    assert!(TransferManager::new(storage.path()).read_file("secret.bin").await.is_err());
+}
+
+#[tokio::test]
+async fn test_encrypt_existing_migrates_plaintext_storage() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_a", "b.txt", 1).await;
+    std::fs::write(storage.path().join("preexisting.txt"), b"dropped in by hand").unwrap();
+    let transfer_id = manager.init_transfer("resume.bin".to_string(), 1024, 512, TransferOptions::default()).await.unwrap().id;
+    manager.receive_chunk(&transfer_id, 0, &[1u8; 512][..], None).await.unwrap();
+    drop(manager);
+
+    let manager = TransferManager::new(storage.path()).with_config(encrypted_config());
+    // Two shared names, two batch copies and the blob, the hand-placed file and the partial upload
+    assert_eq!(manager.encrypt_existing().await.unwrap(), 7);
+    assert_eq!(manager.encrypt_existing().await.unwrap(), 0);
+    assert_eq!(manager.recover().await.unwrap(), 1);
+
+    assert_eq!(manager.read_file("b.txt").await.unwrap(), vec![1u8; 512]);
+    assert_ne!(std::fs::read(storage.path().join("a.txt")).unwrap(), vec![1u8; 512]);
+    assert_eq!(manager.read_file("a.txt").await.unwrap(), vec![1u8; 512]);
+    assert_eq!(manager.read_file("preexisting.txt").await.unwrap(), b"dropped in by hand");
+    // The batch copies still share the blob
+    let blob = manager.dedup.blob_path(&hex::encode(Sha256::digest([1u8; 512])));
+    assert!(same_file::is_same_file(manager.batch_file("batch_a", "b.txt").unwrap(), &blob).unwrap());
+
+    // The in-flight transfer keeps its first chunk and accepts the rest
+    let status = manager.get_transfer_status(&transfer_id).await.unwrap();
+    assert!(matches!(status.status, TransferStatus::InProgress { received_chunks: 1 }));
+    manager.receive_chunk(&transfer_id, 1, &[2u8; 512][..], None).await.unwrap();
+    manager.complete_transfer(&transfer_id).await.unwrap();
+    let data = manager.read_file("resume.bin").await.unwrap();
+    assert_eq!(&data[..512], &[1u8; 512][..]);
+    assert_eq!(&data[512..], &[2u8; 512][..]);
+}
+
+#[tokio::test]
+async fn test_select_files_by_name_or_directory() {
+    let (storage, manager) = test_manager();
+    for name in ["photos/2024/a.jpg", "photos/2024/b.jpg", "photos/cover.png", "notes.txt"] {
+        let path = storage.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, name).unwrap();
+    }
+
+    let files = vec!["photos/2024/b.jpg".to_string(), "notes.txt".to_string(), "./photos//2024/b.jpg".to_string()];
+    let selected = manager.select_files(None, &files, None).await.unwrap();
+    let names: Vec<&str> = selected.iter().map(|(n, _)| n.as_str()).collect();
+    assert_eq!(names, ["photos/2024/b.jpg", "notes.txt"]);
+    assert_eq!(selected[1].1, storage.path().join("notes.txt"));
+
+    // A directory unpacks as itself, not as the path above it
+    let selected = manager.select_files(None, &[], Some("photos/2024")).await.unwrap();
+    let names: Vec<&str> = selected.iter().map(|(n, _)| n.as_str()).collect();
+    assert_eq!(names, ["2024/a.jpg", "2024/b.jpg"]);
+
+    let code = |err: TransferError| err.code();
+    assert_eq!(code(manager.select_files(None, &["missing.txt".to_string()], None).await.unwrap_err()), "file_not_found");
+    assert_eq!(code(manager.select_files(None, &["photos".to_string()], None).await.unwrap_err()), "file_not_found");
+    assert_eq!(code(manager.select_files(None, &["../etc/passwd".to_string()], None).await.unwrap_err()), "invalid_filename");
+    assert_eq!(code(manager.select_files(None, &[], Some(STATE_DIR)).await.unwrap_err()), "invalid_filename");
+    assert_eq!(code(manager.select_files(None, &[], None).await.unwrap_err()), "invalid_request");
+    assert_eq!(code(manager.select_files(None, &files, Some("photos")).await.unwrap_err()), "invalid_request");
+}
+
+#[tokio::test]
+async fn test_select_files_within_a_batch_reads_its_own_copies() {
+    let (_storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_b", "a.txt", 2).await;
+
+    let selected = manager.select_files(Some("batch_a"), &["a.txt".to_string()], None).await.unwrap();
+    assert_eq!(std::fs::read(&selected[0].1).unwrap(), vec![1u8; 512]);
+    assert!(manager.select_files(Some("../batch_a"), &["a.txt".to_string()], None).await.is_err());
+}
+
+#[tokio::test]
+async fn test_delete_shared_file_removes_the_uploads_of_its_content() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_a", "b.txt", 3).await;
+    upload_to_batch(&manager, "batch_b", "a.txt", 2).await;
+    let blobs = manager.dedup_stats().await.blobs;
+
+    manager.delete_shared(&ShareTarget::File("a.txt".to_string())).await.unwrap();
+
+    assert!(!storage.path().join("a.txt").exists());
+    assert!(!manager.batch_dir("batch_b").unwrap().exists());
+    assert_eq!(manager.dedup_stats().await.blobs, blobs - 1);
+    // batch_a uploaded other bytes under that name, and keeps them
+    assert_eq!(std::fs::read(manager.batch_file("batch_a", "a.txt").unwrap()).unwrap(), vec![1u8; 512]);
+
+    let reloaded = TransferManager::new(storage.path());
+    reloaded.recover().await.unwrap();
+    let batches = reloaded.list_upload_batches().await;
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].batch_id, "batch_a");
+    assert_eq!(batches[0].files.len(), 2);
+}
+
+#[tokio::test]
+async fn test_delete_shared_batch_keeps_later_uploads() {
+    let (storage, manager) = test_manager();
+    upload_to_batch(&manager, "batch_a", "a.txt", 1).await;
+    upload_to_batch(&manager, "batch_a", "b.txt", 3).await;
+    upload_to_batch(&manager, "batch_b", "a.txt", 2).await;
+    let blobs = manager.dedup_stats().await.blobs;
+
+    manager.delete_shared(&ShareTarget::Batch("batch_a".to_string())).await.unwrap();
+
+    assert!(!manager.batch_dir("batch_a").unwrap().exists());
+    assert!(!storage.path().join("b.txt").exists());
+    // batch_b has since published its own a.txt, which stays
+    assert_eq!(std::fs::read(storage.path().join("a.txt")).unwrap(), vec![2u8; 512]);
+    assert_eq!(manager.dedup_stats().await.blobs, blobs - 2);
+
+    let reloaded = TransferManager::new(storage.path());
+    reloaded.recover().await.unwrap();
+    let batches = reloaded.list_upload_batches().await;
+    assert_eq!(batches.iter().map(|b| b.batch_id.as_str()).collect::<Vec<_>>(), ["batch_b"]);
+}